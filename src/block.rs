@@ -1,8 +1,59 @@
-use crate::{proof_of_work::ProofOfWork, transaction::Transaction, utils};
+use crate::{
+    proof_of_work::ProofOfWork,
+    transaction::{SUBSIDY, Transaction},
+    utils,
+};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use sled::IVec;
 
+/// `pre_block_hash` of the genesis block.
+pub const GENESIS_PRE_BLOCK_HASH: &str = "None";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BlockHeader {
+    timestamp: i64,
+    pre_block_hash: String,
+    hash: String,
+    transactions_hash: Vec<u8>,
+    nonce: i64,
+    height: usize,
+}
+
+impl BlockHeader {
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        Ok(bincode::deserialize::<Self>(bytes)?)
+    }
+
+    pub fn get_timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    pub fn get_pre_block_hash(&self) -> String {
+        self.pre_block_hash.clone()
+    }
+
+    pub fn get_hash(&self) -> &str {
+        self.hash.as_str()
+    }
+
+    pub fn get_transactions_hash(&self) -> &[u8] {
+        self.transactions_hash.as_slice()
+    }
+
+    pub fn get_nonce(&self) -> i64 {
+        self.nonce
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Block {
     timestamp: i64,
@@ -17,7 +68,7 @@ impl Block {
     pub fn generate_genesis_block(transaction: &Transaction) -> Self {
         let transactions = vec![transaction.clone()];
 
-        Self::new_block(String::from("None"), &transactions, 0)
+        Self::new_block(String::from(GENESIS_PRE_BLOCK_HASH), &transactions, 0)
     }
 
     pub fn new_block(pre_block_hash: String, transactions: &[Transaction], height: usize) -> Self {
@@ -46,6 +97,17 @@ impl Block {
         Ok(bincode::deserialize::<Self>(bytes)?)
     }
 
+    pub fn get_header(&self) -> BlockHeader {
+        BlockHeader {
+            timestamp: self.timestamp,
+            pre_block_hash: self.pre_block_hash.clone(),
+            hash: self.hash.clone(),
+            transactions_hash: self.hash_transactions(),
+            nonce: self.nonce,
+            height: self.height,
+        }
+    }
+
     pub fn get_timestamp(&self) -> i64 {
         self.timestamp
     }
@@ -71,10 +133,46 @@ impl Block {
         utils::sha256_digest(data.as_slice())
     }
 
+    /// Checks that the block has exactly one coinbase and that it pays out
+    /// no more than `SUBSIDY`.
+    pub fn check_coinbase(&self) -> Result<()> {
+        let coinbases: Vec<&Transaction> = self
+            .transactions
+            .iter()
+            .filter(|tx| tx.is_coinbase())
+            .collect();
+        if coinbases.len() != 1 {
+            return Err(anyhow::anyhow!(
+                "Block {} has {} coinbase transactions",
+                self.hash,
+                coinbases.len()
+            ));
+        }
+
+        let mut paid: i64 = 0;
+        for out in coinbases[0].get_vout() {
+            paid += out.get_value() as i64;
+        }
+        if paid > SUBSIDY as i64 {
+            return Err(anyhow::anyhow!(
+                "Coinbase of block {} pays {}, more than the subsidy of {}",
+                self.hash,
+                paid,
+                SUBSIDY
+            ));
+        }
+
+        Ok(())
+    }
+
     pub fn get_transactions(&self) -> &[Transaction] {
         self.transactions.as_slice()
     }
 
+    pub fn get_nonce(&self) -> i64 {
+        self.nonce
+    }
+
     pub fn get_height(&self) -> usize {
         self.height
     }
@@ -1,83 +1,126 @@
 use anyhow::Result;
-use data_encoding;
 use std::{
-    collections::HashMap,
-    env,
+    collections::{HashMap, HashSet},
+    path::Path,
     sync::{Arc, RwLock},
 };
 
-use sled::{Db, Tree};
-
 use crate::{
-    block::Block,
+    block::{Block, BlockHeader, GENESIS_PRE_BLOCK_HASH},
+    migration,
+    proof_of_work::ProofOfWork,
+    snapshot,
+    store::{ChainStore, META_INDEX, SledStore, UNDO_INDEX, UTXOs},
     transaction::{TXOutput, Transaction},
+    utxo_set::UTXOSet,
 };
 
-const TIP_BLOCK_HASH_KEY: &str = "tip_block_hash";
-const BLOCKS_TREE: &str = "blocks";
+/// Blocks closer to the tip than this are never pruned.
+pub const MIN_PRUNE_DEPTH: usize = 10;
+
+const PRUNED_KEY: &str = "pruned";
+const PRUNE_HEIGHT_KEY: &str = "prune_height";
 
 #[derive(Clone)]
 pub struct Blockchain {
     tip_hash: Arc<RwLock<String>>,
-    db: Db,
+    store: Arc<dyn ChainStore>,
 }
 
 impl Blockchain {
-    pub fn create_blockchain(genesis_address: &str) -> Result<Self> {
-        let dir = env::current_dir()?;
-        let db = sled::open(dir.join("data"))?;
-        let blocks_tree = db.open_tree(BLOCKS_TREE)?;
-
-        let tip_hash = match blocks_tree.get(TIP_BLOCK_HASH_KEY)? {
-            Some(value) => String::from_utf8(value.to_vec())?,
-            None => {
-                let coinbase_tx = Transaction::new_coinbase_tx(genesis_address)?;
-                let block = Block::generate_genesis_block(&coinbase_tx);
-                Self::update_blocks_tree(&blocks_tree, &block)?;
-
-                String::from(block.get_hash())
-            }
-        };
+    pub fn create_blockchain(data_dir: &Path, genesis_address: &str) -> Result<Self> {
+        let store = SledStore::open(data_dir)?;
+
+        Self::create_with_store(Arc::new(store), genesis_address)
+    }
+
+    pub fn create_with_store(store: Arc<dyn ChainStore>, genesis_address: &str) -> Result<Self> {
+        if store.get_tip_hash()?.is_some() {
+            return Self::open_with_store(store);
+        }
+
+        let coinbase_tx = Transaction::new_coinbase_tx(genesis_address)?;
+        let block = Block::generate_genesis_block(&coinbase_tx);
+        migration::set_schema_version(store.as_ref(), migration::SCHEMA_VERSION)?;
+        store.put_block(&block, true)?;
 
         let blockchain = Self {
-            tip_hash: Arc::new(RwLock::new(tip_hash)),
-            db,
+            tip_hash: Arc::new(RwLock::new(String::from(block.get_hash()))),
+            store,
         };
 
         Ok(blockchain)
     }
 
-    fn update_blocks_tree(blocks_tree: &Tree, block: &Block) -> Result<()> {
-        let block_hash = block.get_hash();
-        let _ = blocks_tree.transaction::<_, (), ()>(|tx| {
-            tx.insert(block_hash, block)?;
-            tx.insert(TIP_BLOCK_HASH_KEY, block_hash)?;
-            Ok(())
-        });
+    pub fn new_blockchain(data_dir: &Path) -> Result<Self> {
+        let store = SledStore::open(data_dir)?;
 
-        Ok(())
+        Self::open_with_store(Arc::new(store))
+    }
+
+    /// Starts a chain from an existing genesis block instead of mining a new one.
+    pub fn create_with_genesis(store: Arc<dyn ChainStore>, genesis: &Block) -> Result<Self> {
+        if store.get_tip_hash()?.is_some() {
+            let blockchain = Self::open_with_store(store)?;
+            if blockchain.get_genesis_hash()? != genesis.get_hash() {
+                return Err(anyhow::anyhow!(
+                    "Genesis block {} does not match the existing chain",
+                    genesis.get_hash()
+                ));
+            }
+
+            return Ok(blockchain);
+        }
+
+        if genesis.get_height() != 0 || !ProofOfWork::new_proof_of_work(genesis.clone()).validate()
+        {
+            return Err(anyhow::anyhow!(
+                "Block {} is not a valid genesis block",
+                genesis.get_hash()
+            ));
+        }
+        migration::set_schema_version(store.as_ref(), migration::SCHEMA_VERSION)?;
+        store.put_block(genesis, true)?;
+
+        Ok(Blockchain {
+            tip_hash: Arc::new(RwLock::new(String::from(genesis.get_hash()))),
+            store,
+        })
     }
 
-    pub fn new_blockchain() -> Result<Self> {
-        let dir = env::current_dir()?;
-        let db = sled::open(dir.join("data"))?;
-        let blocks_tree = db.open_tree(BLOCKS_TREE)?;
+    /// Starts a chain at a snapshot's base block, with no history below it.
+    pub fn create_from_snapshot(store: Arc<dyn ChainStore>, base: &BlockHeader) -> Result<Self> {
+        if store.get_tip_hash()?.is_some() {
+            return Err(anyhow::anyhow!("A blockchain already exists"));
+        }
+
+        migration::set_schema_version(store.as_ref(), migration::SCHEMA_VERSION)?;
+        store.put_header(base)?;
+        store.set_tip_hash(base.get_hash())?;
+
+        Ok(Blockchain {
+            tip_hash: Arc::new(RwLock::new(String::from(base.get_hash()))),
+            store,
+        })
+    }
 
-        let tip_bytes = blocks_tree
-            .get(TIP_BLOCK_HASH_KEY)?
-            .expect("No existing blockchain found. Create one first.");
-        let tip_hash = String::from_utf8(tip_bytes.to_vec())?;
+    pub fn open_with_store(store: Arc<dyn ChainStore>) -> Result<Self> {
+        let tip_hash = store.get_tip_hash()?.ok_or(anyhow::anyhow!(
+            "No existing blockchain found. Create one first."
+        ))?;
 
         let blockchain = Blockchain {
             tip_hash: Arc::new(RwLock::new(tip_hash)),
-            db,
+            store,
         };
+        migration::migrate_chain(&blockchain)?;
+        snapshot::check_chainstate(&blockchain)?;
 
         Ok(blockchain)
     }
 
-    pub fn get_db(&self) -> &Db {
-        &self.db
+    pub fn get_store(&self) -> &dyn ChainStore {
+        self.store.as_ref()
     }
 
     pub fn get_tip_hash(&self) -> String {
@@ -92,31 +135,35 @@ impl Blockchain {
     }
 
     pub fn iterator(&self) -> BlockchainIterator {
-        BlockchainIterator::new(self.get_tip_hash(), self.db.clone())
+        BlockchainIterator::new(self.get_tip_hash(), self.store.clone())
+    }
+
+    pub fn iterator_from(&self, block_hash: &str) -> BlockchainIterator {
+        BlockchainIterator::new(String::from(block_hash), self.store.clone())
     }
 
     pub fn get_best_height(&self) -> Result<usize> {
-        let blocks_tree = self.db.open_tree(BLOCKS_TREE)?;
-        let tip_bytes = blocks_tree
-            .get(self.get_tip_hash())?
-            .expect("The tip hash is invalid.");
-        let block = Block::deserialize(tip_bytes.as_ref())?;
+        let header = self
+            .store
+            .get_header(self.get_tip_hash().as_bytes())?
+            .ok_or(anyhow::anyhow!("The tip hash is invalid."))?;
 
-        Ok(block.get_height())
+        Ok(header.get_height())
     }
 
     pub fn mine_block(&self, transactions: &[Transaction]) -> Result<Block> {
         for transaction in transactions {
             if !transaction.verify(self)? {
-                panic!("error: invalid transaction")
+                return Err(anyhow::anyhow!(
+                    "Invalid transaction {}",
+                    data_encoding::HEXLOWER.encode(transaction.get_id())
+                ));
             }
         }
 
         let best_height = self.get_best_height()?;
         let block = Block::new_block(self.get_tip_hash(), transactions, best_height + 1);
-
-        let blocks_tree = self.db.open_tree(BLOCKS_TREE)?;
-        Self::update_blocks_tree(&blocks_tree, &block)?;
+        self.store.put_block(&block, true)?;
 
         let block_hash = block.get_hash();
         self.set_tip_hash(block_hash);
@@ -125,81 +172,248 @@ impl Blockchain {
     }
 
     pub fn add_block(&self, block: &Block) -> Result<()> {
-        let blocks_tree = self.db.open_tree(BLOCKS_TREE)?;
-
-        // 1. 检查是否已存在
-        if blocks_tree.get(block.get_hash())?.is_some() {
+        // 只有 header 的块（如快照的起点）仍然要补存区块体
+        if self.store.get_block(block.get_hash().as_bytes())?.is_some() {
             return Ok(());
         }
 
-        let block_bytes = block.serialize()?;
-        let block_hash = block.get_hash().to_string();
+        // 只有比当前 tip 更高的块才移动 tip
+        let is_new_tip = block.get_height() > self.get_best_height()?;
+        self.store.put_block(block, is_new_tip)?;
 
-        // 2. 事务操作
-        blocks_tree
-            .transaction::<_, _, anyhow::Error>(|tx| {
-                // 存入块数据
-                tx.insert(block.get_hash(), block_bytes.as_slice())?;
+        if is_new_tip {
+            self.set_tip_hash(block.get_hash());
+        }
 
-                // 获取当前 Tip 进行对比
-                let tip_hash = self.get_tip_hash();
-                if let Some(tip_bytes) = tx.get(&tip_hash)? {
-                    let tip_block = Block::deserialize(tip_bytes.as_ref())
-                        .map_err(sled::transaction::ConflictableTransactionError::Abort)?;
+        Ok(())
+    }
 
-                    if block.get_height() > tip_block.get_height() {
-                        tx.insert(TIP_BLOCK_HASH_KEY, block.get_hash())?;
-                    }
-                }
-                Ok(())
-            })
-            .map_err(|e| anyhow::anyhow!("Transaction error: {}", e))?;
+    pub fn get_block(&self, block_hash: &[u8]) -> Result<Option<Block>> {
+        self.store.get_block(block_hash)
+    }
 
-        // 3. 只有事务成功了，才更新内存
-        // 这里需要根据逻辑判断是否真的需要更新内存中的 tip
-        self.set_tip_hash(&block_hash);
+    pub fn get_header(&self, block_hash: &[u8]) -> Result<Option<BlockHeader>> {
+        self.store.get_header(block_hash)
+    }
 
-        Ok(())
+    pub fn get_genesis_hash(&self) -> Result<String> {
+        let mut current_hash = self.get_tip_hash();
+        while let Some(header) = self.store.get_header(current_hash.as_bytes())? {
+            if header.get_height() == 0 {
+                return Ok(current_hash);
+            }
+            current_hash = header.get_pre_block_hash();
+        }
+
+        Err(anyhow::anyhow!("Chain is missing block {}", current_hash))
     }
 
-    pub fn get_block(&self, block_hash: &[u8]) -> Result<Option<Block>> {
-        let blocks_tree = self.db.open_tree(BLOCKS_TREE)?;
-        if let Some(block_bytes) = blocks_tree.get(block_hash)? {
-            let block = Block::deserialize(&block_bytes)?;
-            return Ok(Some(block));
+    /// Checks a block against its parent: proof of work, height, a single
+    /// coinbase within the subsidy and the signatures of every transaction.
+    /// A block on top of the UTXO set is
+    /// also checked for spent or missing inputs and for inputs that do not
+    /// cover the outputs.
+    pub fn validate_block(&self, block: &Block) -> Result<()> {
+        if !ProofOfWork::new_proof_of_work(block.clone()).validate() {
+            return Err(anyhow::anyhow!(
+                "Block {} has an invalid proof of work",
+                block.get_hash()
+            ));
         }
 
-        Ok(None)
+        if block.get_pre_block_hash() == GENESIS_PRE_BLOCK_HASH {
+            if block.get_height() != 0 {
+                return Err(anyhow::anyhow!(
+                    "Block {} has no parent but height {}",
+                    block.get_hash(),
+                    block.get_height()
+                ));
+            }
+        } else {
+            let parent = self
+                .store
+                .get_header(block.get_pre_block_hash().as_bytes())?
+                .ok_or(anyhow::anyhow!(
+                    "Block {} has unknown parent {}",
+                    block.get_hash(),
+                    block.get_pre_block_hash()
+                ))?;
+            if block.get_height() != parent.get_height() + 1 {
+                return Err(anyhow::anyhow!(
+                    "Block {} has height {}, expected {}",
+                    block.get_hash(),
+                    block.get_height(),
+                    parent.get_height() + 1
+                ));
+            }
+        }
+
+        block.check_coinbase()?;
+
+        for tx in block.get_transactions() {
+            if !tx.has_valid_id()? || !tx.verify(self)? {
+                return Err(anyhow::anyhow!(
+                    "Block {} contains invalid transaction {}",
+                    block.get_hash(),
+                    data_encoding::HEXLOWER.encode(tx.get_id())
+                ));
+            }
+        }
+
+        // 只有接在 UTXO 集合末端的块才能检查输入是否已被花费
+        let utxo_set = UTXOSet::new(self.clone());
+        if utxo_set.get_best_block()?.as_deref() == Some(block.get_pre_block_hash().as_str()) {
+            utxo_set.check_spends(block)?;
+        }
+
+        Ok(())
     }
 
-    pub fn get_block_hashes(&self) -> Vec<Vec<u8>> {
+    pub fn get_block_hashes(&self) -> Result<Vec<Vec<u8>>> {
         let mut data = Vec::new();
-        let mut iterator = self.iterator();
+        let mut current_hash = self.get_tip_hash();
+
+        // 走 header，已裁剪的块也要列出来
+        while let Some(header) = self.store.get_header(current_hash.as_bytes())? {
+            data.push(header.get_hash().as_bytes().to_vec());
+            current_hash = header.get_pre_block_hash();
+        }
+
+        Ok(data)
+    }
+
+    pub fn is_pruned(&self) -> Result<bool> {
+        Ok(self
+            .store
+            .get_index(META_INDEX, PRUNED_KEY.as_bytes())?
+            .is_some())
+    }
+
+    /// Drops bodies and undo data of blocks buried more than `depth` blocks
+    /// below the tip. Returns the number of blocks pruned.
+    pub fn prune(&self, depth: usize) -> Result<usize> {
+        if depth < MIN_PRUNE_DEPTH {
+            return Err(anyhow::anyhow!(
+                "Prune depth must be at least {}",
+                MIN_PRUNE_DEPTH
+            ));
+        }
+
+        let best_height = self.get_best_height()?;
+        if best_height < depth {
+            return Ok(0);
+        }
+        let prune_height = best_height - depth;
+
+        let last_pruned = match self
+            .store
+            .get_index(META_INDEX, PRUNE_HEIGHT_KEY.as_bytes())?
+        {
+            Some(bytes) => Some(bincode::deserialize::<usize>(&bytes)?),
+            None => None,
+        };
 
-        while let Ok(Some(block)) = iterator.next() {
-            data.push(block.get_hash_bytes());
+        let mut count = 0;
+        let mut current_hash = self.get_tip_hash();
+        while let Some(header) = self.store.get_header(current_hash.as_bytes())? {
+            if let Some(last_pruned) = last_pruned
+                && header.get_height() <= last_pruned
+            {
+                break;
+            }
+            if header.get_height() <= prune_height {
+                // 删除第一个区块前标记为 pruned，之后永久保留
+                if count == 0 {
+                    self.store
+                        .put_index(META_INDEX, PRUNED_KEY.as_bytes(), &[1])?;
+                }
+                self.store.prune_block(current_hash.as_bytes())?;
+                self.store
+                    .remove_index(UNDO_INDEX, current_hash.as_bytes())?;
+                count += 1;
+            }
+            current_hash = header.get_pre_block_hash();
         }
 
-        data
+        self.store.put_index(
+            META_INDEX,
+            PRUNE_HEIGHT_KEY.as_bytes(),
+            &bincode::serialize(&prune_height)?,
+        )?;
+
+        Ok(count)
     }
 
-    pub fn find_utxo(&self) -> HashMap<String, Vec<TXOutput>> {
-        let mut utxo: HashMap<String, Vec<TXOutput>> = HashMap::new();
+    /// Every public key hash that has ever been paid in the stored blocks,
+    /// plus those still holding coins in the UTXO set for pruned history.
+    pub fn find_used_pub_key_hashes(&self) -> Result<HashSet<Vec<u8>>> {
+        let mut used = HashSet::new();
+
+        for hash in self.get_block_hashes()? {
+            if let Some(block) = self.store.get_block(&hash)? {
+                for tx in block.get_transactions() {
+                    for out in tx.get_vout() {
+                        used.insert(out.get_pub_key_hash().to_vec());
+                    }
+                }
+            }
+        }
+        for (_, outs) in self.store.utxo_entries()? {
+            for (_, out) in outs {
+                used.insert(out.get_pub_key_hash().to_vec());
+            }
+        }
+
+        Ok(used)
+    }
+
+    /// Stored transactions paying to or spending from `pub_key_hash`, newest first.
+    pub fn find_key_transactions(&self, pub_key_hash: &[u8]) -> Result<Vec<Transaction>> {
+        let mut txs = Vec::new();
+
+        for hash in self.get_block_hashes()? {
+            if let Some(block) = self.store.get_block(&hash)? {
+                for tx in block.get_transactions() {
+                    let pays = tx
+                        .get_vout()
+                        .iter()
+                        .any(|out| out.is_locked_with_key(pub_key_hash));
+                    let spends = !tx.is_coinbase()
+                        && tx.get_vin().iter().any(|vin| vin.use_key(pub_key_hash));
+                    if pays || spends {
+                        txs.push(tx.clone());
+                    }
+                }
+            }
+        }
+
+        Ok(txs)
+    }
+
+    pub fn find_utxo(&self) -> Result<HashMap<String, UTXOs>> {
+        self.find_utxo_at(self.get_tip_hash().as_str())
+    }
+
+    /// The UTXO set as it was right after `block_hash` was connected.
+    pub fn find_utxo_at(&self, block_hash: &str) -> Result<HashMap<String, UTXOs>> {
+        let mut utxo: HashMap<String, UTXOs> = HashMap::new();
         let mut spent_utxo: HashMap<String, Vec<usize>> = HashMap::new();
-        let mut iterator = self.iterator();
+        let mut iterator = self.iterator_from(block_hash);
 
-        while let Ok(Some(block)) = iterator.next() {
-            'outer: for tx in block.get_transactions() {
+        while let Some(block) = iterator.next()? {
+            for tx in block.get_transactions() {
                 let txid_hex = data_encoding::HEXLOWER.encode(tx.get_id());
 
                 for (idx, out) in tx.get_vout().iter().enumerate() {
                     if let Some(outs) = spent_utxo.get(&txid_hex)
                         && outs.contains(&idx)
                     {
-                        continue 'outer;
+                        continue;
                     }
 
-                    utxo.entry(txid_hex.clone()).or_default().push(out.clone());
+                    utxo.entry(txid_hex.clone())
+                        .or_default()
+                        .push((idx, out.clone()));
                 }
 
                 if tx.is_coinbase() {
@@ -213,48 +427,64 @@ impl Blockchain {
             }
         }
 
-        utxo
+        Ok(utxo)
     }
 
-    pub fn find_transaction(&self, txid: &[u8]) -> Option<Transaction> {
+    pub fn find_transaction(&self, txid: &[u8]) -> Result<Option<Transaction>> {
         let mut iterator = self.iterator();
 
-        while let Ok(Some(block)) = iterator.next() {
+        while let Some(block) = iterator.next()? {
             for transaction in block.get_transactions() {
                 if txid.eq(transaction.get_id()) {
-                    return Some(transaction.clone());
+                    return Ok(Some(transaction.clone()));
                 }
             }
         }
 
-        None
+        Ok(None)
+    }
+
+    /// Looks up a previous output, trying the UTXO set before scanning blocks
+    /// so unspent outputs stay reachable after their blocks are pruned.
+    pub fn find_output(&self, txid: &[u8], vout: usize) -> Result<Option<TXOutput>> {
+        if let Some(outs) = self.store.get_utxo(txid)?
+            && let Some((_, out)) = outs.into_iter().find(|(idx, _)| *idx == vout)
+        {
+            return Ok(Some(out));
+        }
+
+        let transaction = self.find_transaction(txid)?;
+        Ok(transaction.and_then(|tx| tx.get_vout().get(vout).cloned()))
     }
 }
 
 pub struct BlockchainIterator {
-    db: Db,
+    store: Arc<dyn ChainStore>,
     current_hash: String,
 }
 
 impl BlockchainIterator {
-    fn new(tip_hash: String, db: Db) -> Self {
+    fn new(tip_hash: String, store: Arc<dyn ChainStore>) -> Self {
         Self {
-            db,
+            store,
             current_hash: tip_hash,
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Result<Option<Block>> {
-        let block_tree = self.db.open_tree(BLOCKS_TREE)?;
-        let data = block_tree.get(self.current_hash.clone())?;
-
-        match data {
-            Some(value) => {
-                let block = Block::deserialize(value.to_vec().as_slice())?;
-                self.current_hash = block.get_pre_block_hash().clone();
-                Ok(Some(block))
-            }
-            None => Ok(None),
+        if let Some(block) = self.store.get_block(self.current_hash.as_bytes())? {
+            self.current_hash = block.get_pre_block_hash();
+            return Ok(Some(block));
+        }
+
+        if self.store.contains_block(self.current_hash.as_bytes())? {
+            return Err(anyhow::anyhow!(
+                "Block {} has been pruned",
+                self.current_hash
+            ));
         }
+
+        Ok(None)
     }
 }
@@ -0,0 +1,144 @@
+use anyhow::Result;
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use crate::{block::Block, blockchain::Blockchain, store::ChainStore, utils, utxo_set::UTXOSet};
+
+// 文件格式：
+//   magic(4) | version(u16 LE)
+//   然后每个区块一条记录：len(u32 LE) | bincode(Block) | checksum(4)
+const MAGIC: &[u8; 4] = b"RBCF";
+const FORMAT_VERSION: u16 = 1;
+const CHECKSUM_LEN: usize = 4;
+const MAX_RECORD_SIZE: usize = 32 * 1024 * 1024;
+
+fn checksum(payload: &[u8]) -> Vec<u8> {
+    let first_sha = utils::sha256_digest(payload);
+    let second_sha = utils::sha256_digest(first_sha.as_slice());
+    second_sha[0..CHECKSUM_LEN].to_vec()
+}
+
+/// Writes every block from genesis to tip to `path`. Returns the number of blocks written.
+pub fn export_chain(blockchain: &Blockchain, path: &Path) -> Result<usize> {
+    let mut hashes = blockchain.get_block_hashes()?;
+    hashes.reverse();
+
+    let file = File::create(path)?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+
+    for hash in &hashes {
+        let block = blockchain.get_block(hash)?.ok_or(anyhow::anyhow!(
+            "Block {} has been pruned, cannot export",
+            String::from_utf8_lossy(hash)
+        ))?;
+        let payload = block.serialize()?;
+
+        writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+        writer.write_all(payload.as_slice())?;
+        writer.write_all(checksum(payload.as_slice()).as_slice())?;
+    }
+    writer.flush()?;
+
+    Ok(hashes.len())
+}
+
+/// Reads the next block record, or `None` at a clean end of file.
+fn read_block<R: Read>(reader: &mut R) -> Result<Option<Block>> {
+    let mut len_bytes = [0u8; 4];
+    // 只有在记录边界处结束才算正常结束
+    match reader.read(&mut len_bytes[..1])? {
+        0 => return Ok(None),
+        _ => reader.read_exact(&mut len_bytes[1..]).map_err(truncated)?,
+    }
+
+    let len = u32::from_le_bytes(len_bytes) as usize;
+    if len > MAX_RECORD_SIZE {
+        return Err(anyhow::anyhow!(
+            "Block record of {} bytes is too large",
+            len
+        ));
+    }
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).map_err(truncated)?;
+    let mut actual_checksum = [0u8; CHECKSUM_LEN];
+    reader.read_exact(&mut actual_checksum).map_err(truncated)?;
+
+    if checksum(payload.as_slice()) != actual_checksum {
+        return Err(anyhow::anyhow!("Block record checksum mismatch"));
+    }
+
+    Ok(Some(Block::deserialize(payload.as_slice())?))
+}
+
+fn truncated(e: io::Error) -> anyhow::Error {
+    match e.kind() {
+        ErrorKind::UnexpectedEof => anyhow::anyhow!("Chain export is truncated"),
+        _ => e.into(),
+    }
+}
+
+/// Validates and connects every block in `path` on top of `store`, starting a
+/// new chain from the file's genesis block if the store is empty. Blocks the
+/// store already has are skipped. Returns the number of blocks imported.
+pub fn import_chain(store: Arc<dyn ChainStore>, path: &Path) -> Result<usize> {
+    let file = File::open(path)?;
+    let mut reader = BufReader::new(file);
+
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(anyhow::anyhow!("Not a chain export file"));
+    }
+    let mut version_bytes = [0u8; 2];
+    reader.read_exact(&mut version_bytes)?;
+    let version = u16::from_le_bytes(version_bytes);
+    if version != FORMAT_VERSION {
+        return Err(anyhow::anyhow!(
+            "Unsupported chain export version {}",
+            version
+        ));
+    }
+
+    let genesis = read_block(&mut reader)?.ok_or(anyhow::anyhow!("Chain export is empty"))?;
+    let is_new_chain = store.get_tip_hash()?.is_none();
+    let blockchain = Blockchain::create_with_genesis(store, &genesis)?;
+    let utxo_set = UTXOSet::new(blockchain.clone());
+    let mut imported = 0;
+    if is_new_chain {
+        utxo_set.reindex()?;
+        imported += 1;
+    } else {
+        utxo_set.catch_up()?;
+    }
+
+    while let Some(block) = read_block(&mut reader)? {
+        if blockchain
+            .get_header(block.get_hash().as_bytes())?
+            .is_some()
+        {
+            continue;
+        }
+
+        // 只接受接在当前 tip 上的块，这样存入前就能对照 UTXO 集合检查输入
+        if block.get_pre_block_hash() != blockchain.get_tip_hash() {
+            return Err(anyhow::anyhow!(
+                "Block {} does not extend the local tip {}, the file forks from the local chain",
+                block.get_hash(),
+                blockchain.get_tip_hash()
+            ));
+        }
+        blockchain.validate_block(&block)?;
+        blockchain.add_block(&block)?;
+        utxo_set.catch_up()?;
+        imported += 1;
+    }
+
+    Ok(imported)
+}
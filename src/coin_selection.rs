@@ -0,0 +1,332 @@
+use anyhow::Result;
+use std::{fmt, str::FromStr};
+
+use crate::transaction::TXOutput;
+
+/// Branch-and-bound gives up after this many steps and falls back.
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// An unspent output that can be spent.
+#[derive(Clone, Debug)]
+pub struct Coin {
+    pub txid: Vec<u8>,
+    pub vout: usize,
+    pub output: TXOutput,
+    /// Height of the block that created it, `None` if unknown (e.g. loaded
+    /// from a UTXO snapshot).
+    pub height: Option<usize>,
+}
+
+/// A specific output, written as `<txid hex>:<vout>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutPoint {
+    pub txid: Vec<u8>,
+    pub vout: usize,
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}",
+            data_encoding::HEXLOWER.encode(&self.txid),
+            self.vout
+        )
+    }
+}
+
+impl FromStr for OutPoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (txid_hex, vout) = s
+            .split_once(':')
+            .ok_or(anyhow::anyhow!("Outpoint {} is not <txid>:<vout>", s))?;
+        let txid = data_encoding::HEXLOWER_PERMISSIVE
+            .decode(txid_hex.as_bytes())
+            .map_err(|_| anyhow::anyhow!("Outpoint {} has an invalid txid", s))?;
+        let vout = vout
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Outpoint {} has an invalid output index", s))?;
+        Ok(OutPoint { txid, vout })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CoinSelection {
+    /// Look for coins adding up to exactly the amount so no change is
+    /// needed, falling back to largest-first.
+    #[default]
+    BranchAndBound,
+    /// Fewest inputs.
+    LargestFirst,
+    /// Consolidates dust, at the cost of more inputs.
+    SmallestFirst,
+    /// Spends coins in the order they were received.
+    OldestFirst,
+}
+
+impl fmt::Display for CoinSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoinSelection::BranchAndBound => f.write_str("bnb"),
+            CoinSelection::LargestFirst => f.write_str("largest-first"),
+            CoinSelection::SmallestFirst => f.write_str("smallest-first"),
+            CoinSelection::OldestFirst => f.write_str("oldest-first"),
+        }
+    }
+}
+
+impl FromStr for CoinSelection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bnb" | "branch-and-bound" => Ok(CoinSelection::BranchAndBound),
+            "largest-first" | "largest" => Ok(CoinSelection::LargestFirst),
+            "smallest-first" | "smallest" => Ok(CoinSelection::SmallestFirst),
+            "oldest-first" | "oldest" => Ok(CoinSelection::OldestFirst),
+            _ => Err(anyhow::anyhow!("Unknown coin selection strategy: {}", s)),
+        }
+    }
+}
+
+/// How a send chooses the coins it spends.
+#[derive(Clone, Debug)]
+pub enum CoinControl {
+    Select(CoinSelection),
+    /// Spend exactly these outputs.
+    Outpoints(Vec<OutPoint>),
+}
+
+impl Default for CoinControl {
+    fn default() -> Self {
+        CoinControl::Select(CoinSelection::default())
+    }
+}
+
+impl CoinControl {
+    /// Chooses coins worth at least `amount` out of `coins`, the spendable
+    /// coins of the sender.
+    pub fn select(&self, coins: Vec<Coin>, amount: i32) -> Result<Vec<Coin>> {
+        let selected = match self {
+            CoinControl::Select(strategy) => {
+                let indices = strategy
+                    .select(&coins, amount)
+                    .ok_or(anyhow::anyhow!("ERROR: Not enough funds"))?;
+                let mut coins: Vec<Option<Coin>> = coins.into_iter().map(Some).collect();
+                indices
+                    .into_iter()
+                    .filter_map(|idx| coins[idx].take())
+                    .collect()
+            }
+            CoinControl::Outpoints(outpoints) => {
+                let mut selected: Vec<Coin> = Vec::new();
+                for outpoint in outpoints {
+                    if selected
+                        .iter()
+                        .any(|coin| coin.txid == outpoint.txid && coin.vout == outpoint.vout)
+                    {
+                        return Err(anyhow::anyhow!("Outpoint {} is listed twice", outpoint));
+                    }
+                    let coin = coins
+                        .iter()
+                        .find(|coin| coin.txid == outpoint.txid && coin.vout == outpoint.vout)
+                        .ok_or(anyhow::anyhow!(
+                            "Outpoint {} is not an unspent output of the sender",
+                            outpoint
+                        ))?;
+                    selected.push(coin.clone());
+                }
+                let total: i64 = selected
+                    .iter()
+                    .map(|coin| coin.output.get_value() as i64)
+                    .sum();
+                if total < amount as i64 {
+                    return Err(anyhow::anyhow!("ERROR: Not enough funds"));
+                }
+                selected
+            }
+        };
+
+        Ok(selected)
+    }
+}
+
+impl CoinSelection {
+    /// Picks coins worth at least `amount`. Returns their indices in `coins`,
+    /// or `None` if the coins are not enough.
+    pub fn select(&self, coins: &[Coin], amount: i32) -> Option<Vec<usize>> {
+        let mut order: Vec<usize> = (0..coins.len()).collect();
+        match self {
+            CoinSelection::BranchAndBound => {
+                return branch_and_bound(coins, amount)
+                    .or_else(|| CoinSelection::LargestFirst.select(coins, amount));
+            }
+            CoinSelection::LargestFirst => {
+                order.sort_by_key(|idx| std::cmp::Reverse(coins[*idx].output.get_value()))
+            }
+            CoinSelection::SmallestFirst => order.sort_by_key(|idx| coins[*idx].output.get_value()),
+            // 高度未知的币当作最老的
+            CoinSelection::OldestFirst => order.sort_by_key(|idx| coins[*idx].height),
+        }
+
+        // 累计值用 i64，币多时不会溢出
+        let amount = amount as i64;
+        let mut selected = Vec::new();
+        let mut accumulated: i64 = 0;
+        for idx in order {
+            if accumulated >= amount {
+                break;
+            }
+            accumulated += coins[idx].output.get_value() as i64;
+            selected.push(idx);
+        }
+
+        if accumulated >= amount {
+            Some(selected)
+        } else {
+            None
+        }
+    }
+}
+
+/// Depth-first search for a subset adding up to exactly `amount`.
+fn branch_and_bound(coins: &[Coin], amount: i32) -> Option<Vec<usize>> {
+    let mut order: Vec<usize> = (0..coins.len()).collect();
+    order.sort_by_key(|idx| std::cmp::Reverse(coins[*idx].output.get_value()));
+    let values: Vec<i64> = order
+        .iter()
+        .map(|idx| coins[*idx].output.get_value() as i64)
+        .collect();
+
+    // remaining[i] 是从第 i 个开始所有币的总额，用来剪枝
+    let mut remaining = vec![0; values.len() + 1];
+    for i in (0..values.len()).rev() {
+        remaining[i] = remaining[i + 1] + values[i];
+    }
+
+    let mut selected = Vec::new();
+    let mut tries = 0;
+    if search(
+        &values,
+        &remaining,
+        0,
+        amount as i64,
+        &mut selected,
+        &mut tries,
+    ) {
+        Some(selected.iter().map(|i| order[*i]).collect())
+    } else {
+        None
+    }
+}
+
+fn search(
+    values: &[i64],
+    remaining: &[i64],
+    i: usize,
+    target: i64,
+    selected: &mut Vec<usize>,
+    tries: &mut usize,
+) -> bool {
+    *tries += 1;
+    if target == 0 {
+        return true;
+    }
+    if i == values.len() || remaining[i] < target || *tries > BNB_MAX_TRIES {
+        return false;
+    }
+
+    if values[i] <= target {
+        selected.push(i);
+        if search(
+            values,
+            remaining,
+            i + 1,
+            target - values[i],
+            selected,
+            tries,
+        ) {
+            return true;
+        }
+        selected.pop();
+    }
+    search(values, remaining, i + 1, target, selected, tries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallets;
+
+    fn coins(values: &[i32]) -> Vec<Coin> {
+        let address = wallets::convert_address(&[7; 20]);
+        values
+            .iter()
+            .enumerate()
+            .map(|(vout, value)| Coin {
+                txid: vec![1; 32],
+                vout,
+                output: TXOutput::new(*value, &address).unwrap(),
+                height: Some(vout),
+            })
+            .collect()
+    }
+
+    fn total(coins: &[Coin], selected: &[usize]) -> i32 {
+        selected
+            .iter()
+            .map(|idx| coins[*idx].output.get_value())
+            .sum()
+    }
+
+    #[test]
+    fn bnb_finds_exact_match() {
+        let coins = coins(&[8, 5, 4, 3, 1]);
+        let selected = branch_and_bound(&coins, 7).unwrap();
+
+        // 5 + 1 凑不齐，4 + 3 正好，不需要找零
+        assert_eq!(total(&coins, &selected), 7);
+        let mut selected = selected;
+        selected.sort();
+        assert_eq!(selected, vec![2, 3]);
+    }
+
+    #[test]
+    fn bnb_without_exact_match_falls_back() {
+        let coins = coins(&[10, 6]);
+
+        assert_eq!(branch_and_bound(&coins, 7), None);
+        // 回退到从大到小，需要找零
+        let selected = CoinSelection::BranchAndBound.select(&coins, 7).unwrap();
+        assert_eq!(selected, vec![0]);
+    }
+
+    #[test]
+    fn sums_above_i32_do_not_overflow() {
+        let coins = coins(&[i32::MAX - 1, 2]);
+
+        for strategy in [CoinSelection::SmallestFirst, CoinSelection::BranchAndBound] {
+            let mut selected = strategy.select(&coins, i32::MAX).unwrap();
+            selected.sort();
+            assert_eq!(selected, vec![0, 1]);
+        }
+    }
+
+    #[test]
+    fn bnb_with_insufficient_funds() {
+        let coins = coins(&[2, 3]);
+
+        assert_eq!(branch_and_bound(&coins, 6), None);
+        assert_eq!(CoinSelection::BranchAndBound.select(&coins, 6), None);
+    }
+
+    #[test]
+    fn bnb_gives_up_after_max_tries() {
+        // 全是偶数凑不出奇数，搜索空间远超上限
+        let coins = coins(&[2; 40]);
+
+        assert_eq!(branch_and_bound(&coins, 41), None);
+    }
+}
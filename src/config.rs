@@ -1,5 +1,11 @@
 use anyhow::Result;
-use std::{collections::HashMap, env, sync::RwLock};
+use std::{
+    collections::HashMap,
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::RwLock,
+};
 
 use once_cell::sync::Lazy;
 
@@ -7,21 +13,110 @@ pub static GLOBAL_CONFIG: Lazy<Config> = Lazy::new(Config::new);
 
 pub static DEFAULT_NODE_ADDR: &str = "127.0.0.1:2001";
 
+pub static DEFAULT_DATA_DIR: &str = "data";
+
+/// Directory of the chain database inside a network directory.
+pub const CHAIN_DIR: &str = "chain";
+
+/// Config file read from the data dir unless another one is given.
+pub const CONFIG_FILE: &str = "blockchain.conf";
+
 const NODE_ADDRESS_KEY: &str = "NODE_ADDRESS";
 const MINING_ADDRESS_KEY: &str = "MINING_ADDRESS";
+const DATA_DIR_KEY: &str = "DATA_DIR";
+const NETWORK_KEY: &str = "NETWORK";
+const PRUNE_KEY: &str = "PRUNE";
+const CONFIG_FILE_KEY: &str = "CONF";
+
+/// Settings that may appear in the config file.
+const FILE_KEYS: &[&str] = &[NODE_ADDRESS_KEY, DATA_DIR_KEY, NETWORK_KEY, PRUNE_KEY];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+pub const NETWORKS: &[Network] = &[Network::Mainnet, Network::Testnet, Network::Regtest];
+
+impl Network {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Regtest => "regtest",
+        }
+    }
+
+    /// The human-readable part in front of addresses of this network.
+    pub fn address_prefix(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "brs",
+            Network::Testnet => "tbrs",
+            Network::Regtest => "brsrt",
+        }
+    }
+
+    /// Starts every frame on the wire, so nodes of different networks
+    /// cannot talk to each other.
+    pub fn magic(&self) -> [u8; 4] {
+        match self {
+            Network::Mainnet => *b"BRSM",
+            Network::Testnet => *b"BRST",
+            Network::Regtest => *b"BRSR",
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Network {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mainnet" | "main" => Ok(Network::Mainnet),
+            "testnet" | "test" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(anyhow::anyhow!("Unknown network: {}", s)),
+        }
+    }
+}
 
 pub struct Config {
     inner: RwLock<HashMap<String, String>>,
 }
 
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Config {
     pub fn new() -> Self {
-        let mut node_addr = String::from(DEFAULT_NODE_ADDR);
+        let mut map = HashMap::new();
         if let Ok(addr) = env::var(NODE_ADDRESS_KEY) {
-            node_addr = addr;
+            map.insert(String::from(NODE_ADDRESS_KEY), addr);
+        }
+        if let Ok(path) = env::var(CONFIG_FILE_KEY) {
+            map.insert(String::from(CONFIG_FILE_KEY), path);
+        }
+        if let Ok(data_dir) = env::var(DATA_DIR_KEY) {
+            map.insert(String::from(DATA_DIR_KEY), data_dir);
+        }
+        if let Ok(network) = env::var(NETWORK_KEY) {
+            map.insert(String::from(NETWORK_KEY), network);
+        }
+        if let Ok(depth) = env::var(PRUNE_KEY) {
+            map.insert(String::from(PRUNE_KEY), depth);
         }
-        let mut map = HashMap::new();
-        map.insert(String::from(NODE_ADDRESS_KEY), node_addr);
 
         Config {
             inner: RwLock::new(map),
@@ -33,7 +128,80 @@ impl Config {
             .inner
             .read()
             .map_err(|e| anyhow::anyhow!("failed to read addr: {:?}", e))?;
-        Ok(inner.get(NODE_ADDRESS_KEY).cloned())
+        Ok(Some(
+            inner
+                .get(NODE_ADDRESS_KEY)
+                .cloned()
+                .unwrap_or(String::from(DEFAULT_NODE_ADDR)),
+        ))
+    }
+
+    pub fn set_config_file(&self, path: String) -> Result<()> {
+        let mut inner = self
+            .inner
+            .write()
+            .map_err(|e| anyhow::anyhow!("failed to write config file: {:?}", e))?;
+        inner.insert(CONFIG_FILE_KEY.to_string(), path);
+        Ok(())
+    }
+
+    /// Reads the config file given with `set_config_file`, or `CONFIG_FILE`
+    /// in the data dir if there is one. Call it after applying the command
+    /// line, since settings already made are kept.
+    pub fn load_config_file(&self) -> Result<()> {
+        let path = {
+            let inner = self
+                .inner
+                .read()
+                .map_err(|e| anyhow::anyhow!("failed to read config file: {:?}", e))?;
+            inner.get(CONFIG_FILE_KEY).map(PathBuf::from)
+        };
+        match path {
+            Some(path) => self.load_file(&path),
+            None => {
+                let path = self.get_data_dir()?.join(CONFIG_FILE);
+                if path.is_file() {
+                    self.load_file(&path)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Reads `KEY=VALUE` lines, using the names of the environment variables.
+    /// Blank lines and lines starting with `#` are skipped.
+    fn load_file(&self, path: &Path) -> Result<()> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("failed to read {}: {}", path.display(), e))?;
+        let mut inner = self
+            .inner
+            .write()
+            .map_err(|e| anyhow::anyhow!("failed to write config: {:?}", e))?;
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or(anyhow::anyhow!(
+                "{}:{}: expected KEY=VALUE",
+                path.display(),
+                idx + 1
+            ))?;
+            let key = key.trim();
+            if !FILE_KEYS.contains(&key) {
+                return Err(anyhow::anyhow!(
+                    "{}:{}: unknown setting {}",
+                    path.display(),
+                    idx + 1,
+                    key
+                ));
+            }
+            // 命令行参数和环境变量优先
+            inner
+                .entry(key.to_string())
+                .or_insert_with(|| value.trim().to_string());
+        }
+        Ok(())
     }
 
     pub fn set_mining_addr(&self, addr: String) -> Result<()> {
@@ -60,4 +228,112 @@ impl Config {
             .map_err(|e| anyhow::anyhow!("failed to read addr: {:?}", e))?;
         Ok(inner.contains_key(MINING_ADDRESS_KEY))
     }
+
+    pub fn set_data_dir(&self, data_dir: String) -> Result<()> {
+        let mut inner = self
+            .inner
+            .write()
+            .map_err(|e| anyhow::anyhow!("failed to write data dir: {:?}", e))?;
+        inner.insert(DATA_DIR_KEY.to_string(), data_dir);
+        Ok(())
+    }
+
+    pub fn get_data_dir(&self) -> Result<PathBuf> {
+        let inner = self
+            .inner
+            .read()
+            .map_err(|e| anyhow::anyhow!("failed to read data dir: {:?}", e))?;
+        match inner.get(DATA_DIR_KEY) {
+            Some(dir) => Ok(PathBuf::from(dir)),
+            None => Ok(env::current_dir()?.join(DEFAULT_DATA_DIR)),
+        }
+    }
+
+    pub fn set_network(&self, network: Network) -> Result<()> {
+        let mut inner = self
+            .inner
+            .write()
+            .map_err(|e| anyhow::anyhow!("failed to write network: {:?}", e))?;
+        inner.insert(NETWORK_KEY.to_string(), network.to_string());
+        Ok(())
+    }
+
+    pub fn get_network(&self) -> Result<Network> {
+        let inner = self
+            .inner
+            .read()
+            .map_err(|e| anyhow::anyhow!("failed to read network: {:?}", e))?;
+        match inner.get(NETWORK_KEY) {
+            Some(network) => network.parse(),
+            None => Ok(Network::default()),
+        }
+    }
+
+    /// The directory holding the chain database and wallets for the selected
+    /// network. Every network has its own directory under the data dir.
+    pub fn get_network_dir(&self) -> Result<PathBuf> {
+        Ok(self.get_data_dir()?.join(self.get_network()?.as_str()))
+    }
+
+    /// The chain database of the selected network.
+    pub fn get_chain_dir(&self) -> Result<PathBuf> {
+        Ok(self.get_network_dir()?.join(CHAIN_DIR))
+    }
+
+    pub fn set_prune_depth(&self, depth: usize) -> Result<()> {
+        let mut inner = self
+            .inner
+            .write()
+            .map_err(|e| anyhow::anyhow!("failed to write prune depth: {:?}", e))?;
+        inner.insert(PRUNE_KEY.to_string(), depth.to_string());
+        Ok(())
+    }
+
+    /// Depth below the tip past which block bodies are discarded, if pruning is on.
+    pub fn get_prune_depth(&self) -> Result<Option<usize>> {
+        let inner = self
+            .inner
+            .read()
+            .map_err(|e| anyhow::anyhow!("failed to read prune depth: {:?}", e))?;
+        match inner.get(PRUNE_KEY) {
+            Some(depth) => Ok(Some(depth.parse()?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_config() -> Config {
+        Config {
+            inner: RwLock::new(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn config_file_fills_unset_values() {
+        let path = env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::write(
+            &path,
+            "# test node\nNETWORK = testnet\n\nPRUNE=20\nNODE_ADDRESS=127.0.0.1:3001\n",
+        )
+        .unwrap();
+
+        let config = empty_config();
+        config.set_network(Network::Regtest).unwrap();
+        config.set_config_file(path.display().to_string()).unwrap();
+        config.load_config_file().unwrap();
+        assert_eq!(config.get_network().unwrap(), Network::Regtest);
+        assert_eq!(config.get_prune_depth().unwrap(), Some(20));
+        assert_eq!(
+            config.get_node_addr().unwrap().as_deref(),
+            Some("127.0.0.1:3001")
+        );
+
+        fs::write(&path, "MINING_ADDRESS=x\n").unwrap();
+        assert!(empty_config().load_file(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,223 @@
+use anyhow::Result;
+use p256::elliptic_curve::PrimeField;
+use ring::hmac;
+
+use crate::signature::SignatureScheme;
+
+pub const HARDENED_OFFSET: u32 = 0x8000_0000;
+
+/// A private key with the chain code needed to derive its children.
+/// Only hardened derivation is supported, as in SLIP-0010.
+#[derive(Clone)]
+pub struct ExtendedKey {
+    scheme: SignatureScheme,
+    key: Vec<u8>,
+    chain_code: Vec<u8>,
+}
+
+// SLIP-0010 中各曲线的主密钥 HMAC key
+fn master_key_salt(scheme: SignatureScheme) -> &'static [u8] {
+    match scheme {
+        SignatureScheme::P256 => b"Nist256p1 seed",
+        SignatureScheme::Ed25519 => b"ed25519 seed",
+        SignatureScheme::Secp256k1 => b"Bitcoin seed",
+    }
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let key = hmac::Key::new(hmac::HMAC_SHA512, key);
+    let tag = hmac::sign(&key, data);
+    let (left, right) = tag.as_ref().split_at(32);
+    (left.to_vec(), right.to_vec())
+}
+
+/// `bytes` as a scalar, or `None` if it is not below the curve order.
+fn parse_scalar<S: PrimeField>(bytes: &[u8]) -> Option<S>
+where
+    S::Repr: From<[u8; 32]>,
+{
+    let repr: [u8; 32] = bytes.try_into().ok()?;
+    S::from_repr(repr.into()).into()
+}
+
+/// `tweak + parent` modulo the curve order, or `None` if `tweak` is out of
+/// range or the sum is zero. Without a parent, `tweak` itself is checked.
+fn add_scalars<S: PrimeField>(tweak: &[u8], parent: Option<&[u8]>) -> Option<Vec<u8>>
+where
+    S::Repr: From<[u8; 32]>,
+{
+    let mut key: S = parse_scalar(tweak)?;
+    if let Some(parent) = parent {
+        key += parse_scalar::<S>(parent)?;
+    }
+    if bool::from(key.is_zero()) {
+        return None;
+    }
+    Some(key.to_repr().as_ref().to_vec())
+}
+
+/// The child key for HMAC output `left`, or `None` if SLIP-0010 says to
+/// retry with the next input.
+fn child_key(scheme: SignatureScheme, left: &[u8], parent: Option<&[u8]>) -> Option<Vec<u8>> {
+    match scheme {
+        SignatureScheme::P256 => add_scalars::<p256::Scalar>(left, parent),
+        SignatureScheme::Secp256k1 => add_scalars::<k256::Scalar>(left, parent),
+        // Ed25519 的私钥就是 HMAC 输出本身
+        SignatureScheme::Ed25519 => Some(left.to_vec()),
+    }
+}
+
+impl ExtendedKey {
+    pub fn from_seed(seed: &[u8], scheme: SignatureScheme) -> Result<Self> {
+        let mut data = seed.to_vec();
+        loop {
+            let (left, right) = hmac_sha512(master_key_salt(scheme), &data);
+            if let Some(key) = child_key(scheme, &left, None) {
+                return Ok(ExtendedKey {
+                    scheme,
+                    key,
+                    chain_code: right,
+                });
+            }
+            // 结果无效时按 SLIP-0010 用整个输出重新计算
+            data = [left, right].concat();
+        }
+    }
+
+    /// Derives hardened child `index` (the hardened offset is added here).
+    pub fn derive_hardened(&self, index: u32) -> Result<Self> {
+        if index >= HARDENED_OFFSET {
+            return Err(anyhow::anyhow!("Child index {} is out of range", index));
+        }
+        let index_bytes = (index + HARDENED_OFFSET).to_be_bytes();
+
+        let mut data = [&[0u8][..], &self.key, &index_bytes].concat();
+        loop {
+            let (left, right) = hmac_sha512(&self.chain_code, &data);
+            if let Some(key) = child_key(self.scheme, &left, Some(&self.key)) {
+                return Ok(ExtendedKey {
+                    scheme: self.scheme,
+                    key,
+                    chain_code: right,
+                });
+            }
+            data = [&[1u8][..], &right, &index_bytes].concat();
+        }
+    }
+
+    pub fn derive_path(&self, path: &[u32]) -> Result<Self> {
+        let mut key = self.clone();
+        for index in path {
+            key = key.derive_hardened(*index)?;
+        }
+        Ok(key)
+    }
+
+    /// The PKCS#8 document and public key, in the same form
+    /// `SignatureScheme::new_key_pair` returns.
+    pub fn to_key_pair(&self) -> Result<(Vec<u8>, Vec<u8>)> {
+        self.scheme.key_pair_from_secret(&self.key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SLIP-0010 的测试向量
+    const SEED: &str = "000102030405060708090a0b0c0d0e0f";
+
+    fn hex(bytes: &[u8]) -> String {
+        data_encoding::HEXLOWER.encode(bytes)
+    }
+
+    fn derive(seed: &str, scheme: SignatureScheme, path: &[u32]) -> ExtendedKey {
+        let seed = data_encoding::HEXLOWER.decode(seed.as_bytes()).unwrap();
+        ExtendedKey::from_seed(&seed, scheme)
+            .unwrap()
+            .derive_path(path)
+            .unwrap()
+    }
+
+    fn check(key: &ExtendedKey, chain_code: &str, private_key: &str) {
+        assert_eq!(hex(&key.chain_code), chain_code);
+        assert_eq!(hex(&key.key), private_key);
+    }
+
+    #[test]
+    fn ed25519_vector_1() {
+        let scheme = SignatureScheme::Ed25519;
+        check(
+            &derive(SEED, scheme, &[]),
+            "90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb",
+            "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7",
+        );
+        check(
+            &derive(SEED, scheme, &[0]),
+            "8b59aa11380b624e81507a27fedda59fea6d0b779a778918a2fd3590e16e9c69",
+            "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3",
+        );
+        check(
+            &derive(SEED, scheme, &[0, 1, 2]),
+            "2e69929e00b5ab250f49c3fb1c12f252de4fed2c1db88387094a0f8c4c9ccd6c",
+            "92a5b23c0b8a99e37d07df3fb9966917f5d06e02ddbd909c7e184371463e9fc9",
+        );
+    }
+
+    #[test]
+    fn nist256p1_vector_1() {
+        let scheme = SignatureScheme::P256;
+        check(
+            &derive(SEED, scheme, &[]),
+            "beeb672fe4621673f722f38529c07392fecaa61015c80c34f29ce8b41b3cb6ea",
+            "612091aaa12e22dd2abef664f8a01a82cae99ad7441b7ef8110424915c268bc2",
+        );
+        check(
+            &derive(SEED, scheme, &[0]),
+            "3460cea53e6a6bb5fb391eeef3237ffd8724bf0a40e94943c98b83825342ee11",
+            "6939694369114c67917a182c59ddb8cafc3004e63ca5d3b84403ba8613debc0c",
+        );
+    }
+
+    #[test]
+    fn secp256k1_vector_1() {
+        let scheme = SignatureScheme::Secp256k1;
+        check(
+            &derive(SEED, scheme, &[]),
+            "873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508",
+            "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35",
+        );
+        check(
+            &derive(SEED, scheme, &[0]),
+            "47fdacbd0f1097043b78c63c20c34ef4ed9a111d980047ad16282c7ae6236141",
+            "edb2e14f9ee77d26dd93b4ecede8d16ed408ce149b6cd80b0715a2d911a0afea",
+        );
+    }
+
+    #[test]
+    fn nist256p1_retries() {
+        let scheme = SignatureScheme::P256;
+        // 子密钥第一次计算超出曲线阶
+        check(
+            &derive(SEED, scheme, &[28578]),
+            "e94c8ebe30c2250a14713212f6449b20f3329105ea15b652ca5bdfc68f6c65c2",
+            "06f0db126f023755d0b8d86d4591718a5210dd8d024e3e14b6159d63f53aa669",
+        );
+        // 主密钥第一次计算超出曲线阶
+        check(
+            &derive(
+                "a7305bc8df8d0951f0cb224c0e95d7707cbdf2c6ce7e8d481fec69c7ff5e9446",
+                scheme,
+                &[],
+            ),
+            "7762f9729fed06121fd13f326884c82f59aa95c57ac492ce8c9654e60efd130c",
+            "3b8c18469a4634517d6d0b65448f8e6c62091b45540a1743c5846be55d47d88f",
+        );
+    }
+
+    #[test]
+    fn rejects_hardened_index() {
+        let key = derive(SEED, SignatureScheme::P256, &[]);
+        assert!(key.derive_hardened(HARDENED_OFFSET).is_err());
+    }
+}
@@ -0,0 +1,414 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt, fs,
+    path::Path,
+};
+
+use crate::{
+    block::Block,
+    blockchain::Blockchain,
+    transaction::TXOutput,
+    utils,
+    utxo_set::UTXOSet,
+    wallets::{self, Wallets},
+};
+
+/// The wallet-side history index, kept next to the wallet file. It is only
+/// a cache of the chain and is rebuilt when missing or unreadable.
+pub const HISTORY_FILE: &str = "history.dat";
+
+/// Outputs spent by a block, keyed by (txid, vout).
+pub type SpentOutputs = HashMap<(Vec<u8>, usize), TXOutput>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Direction {
+    Received,
+    Sent,
+    Mined,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Received => f.write_str("received"),
+            Direction::Sent => f.write_str("sent"),
+            Direction::Mined => f.write_str("mined"),
+        }
+    }
+}
+
+/// A transaction touching the wallet, with every party of it. The direction
+/// and amount depend on which addresses belong to the wallet, so they are
+/// worked out in `get_history`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct HistoryRecord {
+    txid: Vec<u8>,
+    block_hash: String,
+    height: usize,
+    timestamp: i64,
+    coinbase: bool,
+    /// Coins each pub key hash received.
+    received: Vec<(Vec<u8>, i32)>,
+    /// Coins each pub key hash spent.
+    sent: Vec<(Vec<u8>, i32)>,
+}
+
+impl HistoryRecord {
+    fn touches(&self, pub_key_hash: &[u8]) -> bool {
+        self.received
+            .iter()
+            .chain(self.sent.iter())
+            .any(|(hash, _)| hash.as_slice() == pub_key_hash)
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct WalletHistory {
+    /// The block the index was last brought up to.
+    best_block: Option<String>,
+    /// Pub key hashes the index was built for.
+    tracked: BTreeSet<Vec<u8>>,
+    /// In chain order.
+    records: Vec<HistoryRecord>,
+}
+
+pub struct HistoryEntry {
+    pub txid: String,
+    pub direction: Direction,
+    /// Coins the wallet received, or for a send the coins that left the
+    /// wallet, netted over all of its addresses.
+    pub amount: i32,
+    /// Addresses outside the wallet, so change is never listed.
+    pub counterparties: Vec<String>,
+    pub block_hash: String,
+    pub height: usize,
+    pub confirmations: usize,
+    pub timestamp: i64,
+}
+
+/// The records of the transactions in `block` that touch `tracked`.
+fn block_records(
+    block: &Block,
+    spent: &SpentOutputs,
+    tracked: &BTreeSet<Vec<u8>>,
+) -> Vec<HistoryRecord> {
+    let mut records = Vec::new();
+
+    for tx in block.get_transactions() {
+        let mut received: HashMap<Vec<u8>, i32> = HashMap::new();
+        let mut sent: HashMap<Vec<u8>, i32> = HashMap::new();
+        for out in tx.get_vout() {
+            *received.entry(out.get_pub_key_hash().to_vec()).or_default() += out.get_value();
+        }
+        if !tx.is_coinbase() {
+            for vin in tx.get_vin() {
+                // 没有撤销数据的块查不到被花费的输出
+                if let Some(out) = spent.get(&(vin.get_txid().to_vec(), vin.get_vout())) {
+                    *sent.entry(out.get_pub_key_hash().to_vec()).or_default() += out.get_value();
+                }
+            }
+        }
+        if !received
+            .keys()
+            .chain(sent.keys())
+            .any(|hash| tracked.contains(hash))
+        {
+            continue;
+        }
+
+        let sorted = |side: HashMap<Vec<u8>, i32>| {
+            let mut side: Vec<(Vec<u8>, i32)> = side.into_iter().collect();
+            side.sort();
+            side
+        };
+        records.push(HistoryRecord {
+            txid: tx.get_id_bytes(),
+            block_hash: block.get_hash().to_string(),
+            height: block.get_height(),
+            timestamp: block.get_timestamp(),
+            coinbase: tx.is_coinbase(),
+            received: sorted(received),
+            sent: sorted(sent),
+        });
+    }
+
+    records
+}
+
+impl WalletHistory {
+    fn load(dir: &Path) -> Self {
+        fs::read(dir.join(HISTORY_FILE))
+            .ok()
+            .and_then(|bytes| bincode::deserialize(&bytes).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        utils::write_file_atomically(&dir.join(HISTORY_FILE), &bincode::serialize(self)?)
+    }
+
+    /// Brings the index from its best block to the chain tip: records of
+    /// blocks that left the active chain are dropped, then the new blocks are
+    /// connected. Coins spent are looked up in the undo data of the UTXO set,
+    /// so they are not counted for blocks without it. Pruned blocks are
+    /// skipped, while on a full node the index stops at a block that has not
+    /// been downloaded yet.
+    fn sync(&mut self, blockchain: &Blockchain, utxo_set: &UTXOSet) -> Result<()> {
+        let tip_hash = blockchain.get_tip_hash();
+        if self.best_block.as_deref() == Some(tip_hash.as_str()) {
+            return Ok(());
+        }
+
+        // 找到索引所在分支与当前主链的分叉点
+        let header_of = |hash: &str| blockchain.get_header(hash.as_bytes());
+        let mut to_connect = Vec::new();
+        let mut to_disconnect = HashSet::new();
+        let mut main = header_of(&tip_hash)?;
+        let mut side = match &self.best_block {
+            Some(hash) => header_of(hash)?,
+            None => None,
+        };
+        while let Some(main_header) = main.clone() {
+            match &side {
+                Some(side_header) if side_header.get_hash() == main_header.get_hash() => break,
+                Some(side_header) if side_header.get_height() > main_header.get_height() => {
+                    to_disconnect.insert(side_header.get_hash().to_string());
+                    side = header_of(&side_header.get_pre_block_hash())?;
+                }
+                _ => {
+                    to_connect.push(main_header.get_hash().to_string());
+                    main = header_of(&main_header.get_pre_block_hash())?;
+                }
+            }
+        }
+        // 走到了创世块之前：没有共同祖先，从头建立
+        if main.is_none() {
+            self.records.clear();
+        } else {
+            self.records
+                .retain(|record| !to_disconnect.contains(&record.block_hash));
+        }
+
+        let pruned = blockchain.is_pruned()?;
+        for hash in to_connect.iter().rev() {
+            let block = match blockchain.get_block(hash.as_bytes())? {
+                Some(block) => block,
+                None if pruned => continue,
+                None => return Ok(()),
+            };
+            let spent = utxo_set.get_spent_outputs(hash)?.unwrap_or_default();
+            self.records
+                .extend(block_records(&block, &spent, &self.tracked));
+            self.best_block = Some(hash.clone());
+        }
+        self.best_block = Some(tip_hash);
+
+        Ok(())
+    }
+}
+
+/// Transactions touching `address` of `wallets` in the active chain, newest
+/// first. The wallet's history index is brought up to the tip first, and
+/// rebuilt when the wallet has addresses the index does not cover yet.
+/// Amounts are netted over every address the wallet holds keys for, and
+/// those are never shown as counterparties.
+pub fn get_history(
+    blockchain: &Blockchain,
+    wallets: &Wallets,
+    address: &str,
+) -> Result<Vec<HistoryEntry>> {
+    let pub_key_hash = wallets::get_pub_key_hash(address)?;
+    let own = wallets.get_own_pub_key_hashes()?;
+    let mut tracked: BTreeSet<Vec<u8>> = own.iter().cloned().collect();
+    for watched in wallets.get_watch_only_addresses() {
+        tracked.insert(wallets::get_pub_key_hash(&watched)?);
+    }
+    if !tracked.contains(&pub_key_hash) {
+        return Err(anyhow::anyhow!(
+            "{} is not an address of this wallet, watch it first",
+            address
+        ));
+    }
+    // 监视地址不属于钱包，只按它自己计算
+    let own = if wallets.is_watch_only(address) {
+        HashSet::new()
+    } else {
+        own
+    };
+
+    let utxo_set = UTXOSet::new(blockchain.clone());
+    utxo_set.catch_up()?;
+    let mut history = WalletHistory::load(wallets.get_data_dir());
+    if !tracked.is_subset(&history.tracked) {
+        history = WalletHistory {
+            tracked,
+            ..Default::default()
+        };
+    }
+    history.sync(blockchain, &utxo_set)?;
+    history.save(wallets.get_data_dir())?;
+
+    let best_height = blockchain.get_best_height()?;
+    let is_own = |hash: &Vec<u8>| hash.as_slice() == pub_key_hash || own.contains(hash);
+    let mut entries = Vec::new();
+
+    for record in history.records.into_iter().rev() {
+        if !record.touches(&pub_key_hash) {
+            continue;
+        }
+
+        let own_total = |side: &[(Vec<u8>, i32)]| -> i32 {
+            side.iter()
+                .filter(|(hash, _)| is_own(hash))
+                .map(|(_, value)| value)
+                .sum()
+        };
+        let others = |side: &[(Vec<u8>, i32)]| -> Vec<String> {
+            side.iter()
+                .filter(|(hash, _)| !is_own(hash))
+                .map(|(hash, _)| wallets::convert_address(hash))
+                .collect()
+        };
+        let own_received = own_total(&record.received);
+        let own_sent = own_total(&record.sent);
+        // 找零回到钱包自己的地址，按净额计算
+        let (direction, amount, counterparties) = if record.coinbase {
+            (Direction::Mined, own_received, vec![])
+        } else if own_sent > 0 {
+            (
+                Direction::Sent,
+                own_sent - own_received,
+                others(&record.received),
+            )
+        } else {
+            (Direction::Received, own_received, others(&record.sent))
+        };
+
+        entries.push(HistoryEntry {
+            txid: data_encoding::HEXLOWER.encode(&record.txid),
+            direction,
+            amount,
+            counterparties,
+            block_hash: record.block_hash,
+            height: record.height,
+            confirmations: best_height.saturating_sub(record.height) + 1,
+            timestamp: record.timestamp,
+        });
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block::Block, coin_selection::CoinControl, signature::SignatureScheme, store::MemoryStore,
+        transaction::Transaction, tx_builder::Recipient, wallets::Wallet,
+    };
+    use std::sync::Arc;
+
+    fn summary(
+        blockchain: &Blockchain,
+        address: &str,
+        wallets: &Wallets,
+    ) -> Vec<(Direction, i32, Vec<String>)> {
+        get_history(blockchain, wallets, address)
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.direction, entry.amount, entry.counterparties))
+            .collect()
+    }
+
+    #[test]
+    fn index_follows_the_active_chain() {
+        let temp_dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let mut wallets = Wallets::try_new(&temp_dir.join("alice")).unwrap();
+        let mut other = Wallets::try_new(&temp_dir.join("bob")).unwrap();
+        let alice = wallets.create_wallet(SignatureScheme::P256).unwrap();
+        let bob = other.create_wallet(SignatureScheme::P256).unwrap();
+        let carol = Wallet::try_new(SignatureScheme::P256)
+            .unwrap()
+            .get_address();
+        let blockchain =
+            Blockchain::create_with_store(Arc::new(MemoryStore::new()), &alice).unwrap();
+        let genesis_hash = blockchain.get_tip_hash();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex().unwrap();
+        assert_eq!(
+            summary(&blockchain, &alice, &wallets),
+            vec![(Direction::Mined, 10, vec![])]
+        );
+
+        let tx = Transaction::new_utxo_transaction(
+            &alice,
+            &[Recipient::new(&bob, 3)],
+            &mut wallets,
+            &utxo_set,
+            &CoinControl::default(),
+        )
+        .unwrap();
+        let coinbase = || Transaction::new_coinbase_tx(&carol).unwrap();
+        blockchain.mine_block(&[tx, coinbase()]).unwrap();
+        // 最新的记录在前；找零回到钱包自己的地址，不算对方
+        assert_eq!(
+            summary(&blockchain, &alice, &wallets),
+            vec![
+                (Direction::Sent, 3, vec![bob.clone()]),
+                (Direction::Mined, 10, vec![]),
+            ]
+        );
+        assert_eq!(
+            summary(&blockchain, &bob, &other),
+            vec![(Direction::Received, 3, vec![alice.clone()])]
+        );
+        // 索引里只有钱包自己的交易
+        assert_eq!(WalletHistory::load(other.get_data_dir()).records.len(), 1);
+
+        // 更长的分叉替换掉发送交易所在的块
+        let fork = Block::new_block(genesis_hash, &[coinbase()], 1);
+        blockchain.add_block(&fork).unwrap();
+        let fork = Block::new_block(fork.get_hash().to_string(), &[coinbase()], 2);
+        blockchain.add_block(&fork).unwrap();
+        assert!(summary(&blockchain, &bob, &other).is_empty());
+        assert_eq!(summary(&blockchain, &alice, &wallets).len(), 1);
+        std::fs::remove_dir_all(&temp_dir).unwrap();
+    }
+
+    #[test]
+    fn new_addresses_rebuild_the_index() {
+        let temp_dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let mut wallets = Wallets::try_new(&temp_dir).unwrap();
+        let alice = wallets.create_wallet(SignatureScheme::P256).unwrap();
+        let bob = Wallet::try_new(SignatureScheme::P256)
+            .unwrap()
+            .get_address();
+        let blockchain =
+            Blockchain::create_with_store(Arc::new(MemoryStore::new()), &alice).unwrap();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex().unwrap();
+
+        let tx = Transaction::new_utxo_transaction(
+            &alice,
+            &[Recipient::new(&bob, 3)],
+            &mut wallets,
+            &utxo_set,
+            &CoinControl::default(),
+        )
+        .unwrap();
+        blockchain.mine_block(&[tx]).unwrap();
+        assert_eq!(summary(&blockchain, &alice, &wallets).len(), 2);
+        assert!(get_history(&blockchain, &wallets, &bob).is_err());
+
+        // 之后才监视的地址也能看到以前的交易
+        wallets.add_watch_only(Some(&bob), None).unwrap();
+        assert_eq!(
+            summary(&blockchain, &bob, &wallets),
+            vec![(Direction::Received, 3, vec![alice.clone()])]
+        );
+        std::fs::remove_dir_all(&temp_dir).unwrap();
+    }
+}
@@ -1,11 +1,26 @@
 pub mod block;
 pub mod blockchain;
+pub mod chain_file;
+pub mod coin_selection;
 pub mod config;
+pub mod hd;
+pub mod history;
 pub mod memory_pool;
+pub mod message;
+pub mod migration;
 pub mod node;
+pub mod peer;
 pub mod proof_of_work;
+pub mod protocol;
+pub mod psbt;
 pub mod server;
+pub mod signature;
+pub mod snapshot;
+pub mod store;
 pub mod transaction;
+pub mod tx_builder;
 pub mod utils;
 pub mod utxo_set;
+pub mod verify;
+pub mod wallet_agent;
 pub mod wallets;
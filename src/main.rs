@@ -1,14 +1,26 @@
 use anyhow::Result;
 use clap::Parser;
+use std::{
+    io::{self, BufRead as _, IsTerminal as _, Read as _},
+    path::PathBuf,
+    sync::Arc,
+};
 
 use blockchain_rust::{
     blockchain::Blockchain,
-    config,
+    chain_file,
+    coin_selection::{CoinControl, CoinSelection, OutPoint},
+    config, history, message, migration,
+    psbt::PartiallySignedTransaction,
     server::{self, Server},
+    signature::SignatureScheme,
+    snapshot,
+    store::SledStore,
     transaction::Transaction,
-    utils,
+    tx_builder::{self, Recipient, TransactionBuilder},
     utxo_set::UTXOSet,
-    wallets::{self, Wallets},
+    verify,
+    wallets::{self, KeyFormat, Wallets},
 };
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::{
@@ -17,9 +29,96 @@ use tracing_subscriber::{
 
 const MINE_TRUE: usize = 1;
 
+/// Prompts on the terminal, or reads a line from stdin when it is piped.
+fn read_passphrase(prompt: &str) -> Result<String> {
+    if io::stdin().is_terminal() {
+        return Ok(rpassword::prompt_password(prompt)?);
+    }
+
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// `address` followed by its label or contact name, if it has one.
+fn labeled(wallets: &Wallets, address: &str) -> String {
+    match wallets.get_label(address) {
+        Some(label) => format!("{} ({})", address, label),
+        None => address.to_string(),
+    }
+}
+
+fn coin_control(selection: Option<CoinSelection>, outpoints: Vec<OutPoint>) -> CoinControl {
+    if outpoints.is_empty() {
+        CoinControl::Select(selection.unwrap_or_default())
+    } else {
+        CoinControl::Outpoints(outpoints)
+    }
+}
+
+/// Mines `transaction` right away or hands it to the local node.
+fn submit_transaction(
+    blockchain: &Blockchain,
+    utxo_set: &UTXOSet,
+    from: &str,
+    transaction: Transaction,
+    mine: usize,
+) -> Result<()> {
+    if mine == MINE_TRUE {
+        let coinbase_tx = Transaction::new_coinbase_tx(from)?;
+        let block = blockchain.mine_block(&[transaction, coinbase_tx])?;
+
+        utxo_set.update(&block)?;
+
+        if let Some(depth) = config::GLOBAL_CONFIG.get_prune_depth()? {
+            blockchain.prune(depth)?;
+        }
+    } else {
+        server::send_tx(config::DEFAULT_NODE_ADDR, blockchain, &transaction)?;
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, Parser)]
 #[command(author, about, version, long_about=None)]
 struct Args {
+    #[arg(
+        long,
+        global = true,
+        help = "Directory for chain and wallet data (env: DATA_DIR)"
+    )]
+    pub datadir: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Config file of KEY=VALUE settings, named like the env variables \
+                (env: CONF, default: <datadir>/blockchain.conf)"
+    )]
+    pub conf: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Network to use: mainnet, testnet or regtest (env: NETWORK)"
+    )]
+    pub network: Option<config::Network>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Discard block bodies buried deeper than DEPTH blocks (env: PRUNE)"
+    )]
+    pub prune: Option<usize>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Named wallet to use instead of the default wallet"
+    )]
+    pub wallet: Option<String>,
+
     #[command(subcommand)]
     pub cmd: Command,
 }
@@ -27,7 +126,41 @@ struct Args {
 #[derive(Debug, Parser)]
 enum Command {
     #[command(name = "create-wallet", about = "Create a new wallet")]
-    CreateWallet,
+    CreateWallet {
+        #[arg(
+            long,
+            default_value_t = SignatureScheme::P256,
+            help = "Key type: p256, ed25519 or secp256k1"
+        )]
+        scheme: SignatureScheme,
+    },
+
+    #[command(
+        name = "create-named-wallet",
+        about = "Create a separate wallet file, used with --wallet"
+    )]
+    CreateNamedWallet {
+        #[arg(long, help = "The name of the wallet")]
+        name: String,
+    },
+
+    #[command(name = "load-wallet", about = "Allow a named wallet to be used")]
+    LoadWallet {
+        #[arg(long, help = "The name of the wallet")]
+        name: String,
+    },
+
+    #[command(
+        name = "unload-wallet",
+        about = "Lock a named wallet and refuse to use it until loaded again"
+    )]
+    UnloadWallet {
+        #[arg(long, help = "The name of the wallet")]
+        name: String,
+    },
+
+    #[command(name = "list-wallets", about = "List the named wallets")]
+    ListWallets,
 
     #[command(name = "create-blockchain", about = "Create a new blockchain")]
     CreateBlockchain {
@@ -37,23 +170,260 @@ enum Command {
 
     #[command(name = "get-balance", about = "Get the balance of a wallet")]
     GetBalance {
-        #[arg(long, help = "The address of the wallet")]
+        #[arg(long, help = "The address to check, every wallet address by default")]
+        address: Option<String>,
+    },
+
+    #[command(name = "history", about = "List past transactions of a wallet address")]
+    History {
+        #[arg(long, help = "The address or label to list")]
         address: String,
     },
 
+    #[command(
+        name = "watch-address",
+        about = "Track an address without its private key"
+    )]
+    WatchAddress {
+        #[arg(
+            long,
+            help = "The address to watch",
+            required_unless_present = "public_key"
+        )]
+        address: Option<String>,
+        #[arg(long, help = "Hex encoded public key to watch instead")]
+        public_key: Option<String>,
+    },
+
     #[command(name = "list-addresses", about = "List all addresses in the wallet")]
     ListAddresses,
 
+    #[command(name = "set-label", about = "Name an address of the wallet")]
+    SetLabel {
+        #[arg(long, help = "The address to label")]
+        address: String,
+        #[arg(long, help = "The label, leave out to remove it")]
+        label: Option<String>,
+    },
+
+    #[command(
+        name = "add-contact",
+        about = "Add or rename an external address in the address book"
+    )]
+    AddContact {
+        #[arg(long, help = "The address of the contact")]
+        address: String,
+        #[arg(long, help = "The name of the contact")]
+        name: String,
+    },
+
+    #[command(name = "remove-contact", about = "Remove an address book entry")]
+    RemoveContact {
+        #[arg(long, help = "The name or address of the contact")]
+        name: String,
+    },
+
+    #[command(name = "list-contacts", about = "List the address book")]
+    ListContacts,
+
+    #[command(
+        name = "restore-wallet",
+        about = "Restore the wallet and its addresses from a mnemonic"
+    )]
+    RestoreWallet {
+        #[arg(long, help = "The mnemonic phrase, in quotes")]
+        mnemonic: String,
+    },
+
+    #[command(name = "show-mnemonic", about = "Show the mnemonic backup phrase")]
+    ShowMnemonic,
+
+    #[command(name = "export-key", about = "Print the private key of an address")]
+    ExportKey {
+        #[arg(long, help = "The address of the key")]
+        address: String,
+        #[arg(long, default_value_t = KeyFormat::Pem, help = "Key format: pem or base58")]
+        format: KeyFormat,
+    },
+
+    #[command(
+        name = "import-key",
+        about = "Import a PEM or base58 private key, read from stdin by default"
+    )]
+    ImportKey {
+        #[arg(long, help = "The key itself", conflicts_with = "file")]
+        key: Option<String>,
+        #[arg(long, help = "A file containing the key")]
+        file: Option<PathBuf>,
+    },
+
+    #[command(
+        name = "encrypt-wallet",
+        about = "Encrypt the wallet with a passphrase"
+    )]
+    EncryptWallet,
+
+    #[command(
+        name = "unlock",
+        about = "Unlock the encrypted wallet in the running node for a while"
+    )]
+    Unlock {
+        #[arg(long, default_value_t = 300, help = "Seconds to stay unlocked")]
+        timeout: u64,
+    },
+
+    #[command(name = "lock", about = "Lock the encrypted wallet now")]
+    Lock,
+
+    #[command(name = "change-passphrase", about = "Change the wallet passphrase")]
+    ChangePassphrase,
+
     #[command(name = "send", about = "Add new block to chain")]
     Send {
         #[arg(long, help = "The address of the sender")]
         from: String,
-        #[arg(long, help = "The address of the recipient")]
+        #[arg(long, help = "The address, label or contact name of the recipient")]
         to: String,
         #[arg(long, help = "The amount to send")]
         amount: i32,
         #[arg(long, help = "Mine immediately on the same node")]
         mine: usize,
+        #[arg(
+            long,
+            conflicts_with = "outpoints",
+            help = "How to choose the coins to spend: bnb, largest-first, smallest-first or oldest-first"
+        )]
+        coin_selection: Option<CoinSelection>,
+        #[arg(
+            long = "outpoint",
+            help = "Spend exactly this output, given as <txid>:<vout>, can be repeated"
+        )]
+        outpoints: Vec<OutPoint>,
+    },
+
+    #[command(
+        name = "send-many",
+        about = "Pay several recipients in one transaction"
+    )]
+    SendMany {
+        #[arg(long, help = "The address of the sender")]
+        from: String,
+        #[arg(
+            long,
+            help = "A payment as <address or label>:<amount>, can be repeated"
+        )]
+        to: Vec<Recipient>,
+        #[arg(
+            long,
+            help = "CSV file of address,amount lines, or a JSON array of {address, amount}"
+        )]
+        file: Option<PathBuf>,
+        #[arg(long, help = "Mine immediately on the same node")]
+        mine: usize,
+        #[arg(
+            long,
+            conflicts_with = "outpoints",
+            help = "How to choose the coins to spend: bnb, largest-first, smallest-first or oldest-first"
+        )]
+        coin_selection: Option<CoinSelection>,
+        #[arg(
+            long = "outpoint",
+            help = "Spend exactly this output, given as <txid>:<vout>, can be repeated"
+        )]
+        outpoints: Vec<OutPoint>,
+    },
+
+    #[command(
+        name = "sign-message",
+        about = "Prove ownership of an address by signing a message"
+    )]
+    SignMessage {
+        #[arg(long, help = "The address whose key signs")]
+        address: String,
+        #[arg(long, help = "The message to sign")]
+        message: String,
+    },
+
+    #[command(name = "verify-message", about = "Check a signed message")]
+    VerifyMessage {
+        #[arg(long, help = "The address that claims to have signed")]
+        address: String,
+        #[arg(long, help = "The signature from sign-message")]
+        signature: String,
+        #[arg(long, help = "The signed message")]
+        message: String,
+    },
+
+    #[command(
+        name = "create-psbt",
+        about = "Create an unsigned transaction, e.g. from a watch-only address"
+    )]
+    CreatePsbt {
+        #[arg(long, help = "The address of the sender")]
+        from: String,
+        #[arg(long, help = "The address, label or contact name of the recipient")]
+        to: String,
+        #[arg(long, help = "The amount to send")]
+        amount: i32,
+        #[arg(long, help = "The file to write")]
+        out: PathBuf,
+        #[arg(
+            long,
+            conflicts_with = "outpoints",
+            help = "How to choose the coins to spend: bnb, largest-first, smallest-first or oldest-first"
+        )]
+        coin_selection: Option<CoinSelection>,
+        #[arg(
+            long = "outpoint",
+            help = "Spend exactly this output, given as <txid>:<vout>, can be repeated"
+        )]
+        outpoints: Vec<OutPoint>,
+    },
+
+    #[command(
+        name = "sign-psbt",
+        about = "Sign the inputs this wallet has keys for, works offline"
+    )]
+    SignPsbt {
+        #[arg(long = "in", help = "The file to sign")]
+        input: PathBuf,
+        #[arg(long, help = "The file to write")]
+        out: PathBuf,
+    },
+
+    #[command(
+        name = "combine-psbt",
+        about = "Merge signatures from several copies of a transaction"
+    )]
+    CombinePsbt {
+        #[arg(
+            long = "in",
+            required = true,
+            help = "A file to merge, repeat for each"
+        )]
+        inputs: Vec<PathBuf>,
+        #[arg(long, help = "The file to write")]
+        out: PathBuf,
+    },
+
+    #[command(
+        name = "inspect-psbt",
+        about = "Show what a transaction spends and pays"
+    )]
+    InspectPsbt {
+        #[arg(long = "in", help = "The file to show")]
+        input: PathBuf,
+    },
+
+    #[command(
+        name = "broadcast-psbt",
+        about = "Finalize a fully signed transaction and send it"
+    )]
+    BroadcastPsbt {
+        #[arg(long = "in", help = "The signed file")]
+        input: PathBuf,
+        #[arg(long, help = "Mine immediately on the same node")]
+        mine: usize,
     },
 
     #[command(name = "print-chain", about = "Print blockchain all block")]
@@ -62,6 +432,47 @@ enum Command {
     #[command(name = "reindex-utxo", about = "rebuild UTXO index set")]
     ReindexUtxo,
 
+    #[command(name = "verify-chain", about = "Check stored blocks and the UTXO set")]
+    VerifyChain {
+        #[arg(long, help = "Only check this many blocks below the tip")]
+        depth: Option<usize>,
+    },
+
+    #[command(name = "export-chain", about = "Export all blocks to a file")]
+    ExportChain {
+        #[arg(long, help = "The file to write")]
+        out: PathBuf,
+    },
+
+    #[command(
+        name = "import-chain",
+        about = "Validate and import blocks from a file"
+    )]
+    ImportChain {
+        #[arg(long = "in", help = "The file to read")]
+        input: PathBuf,
+    },
+
+    #[command(
+        name = "dump-utxo",
+        about = "Write a snapshot of the UTXO set to a file"
+    )]
+    DumpUtxo {
+        #[arg(long, help = "The file to write")]
+        out: PathBuf,
+    },
+
+    #[command(name = "load-utxo", about = "Start an empty node from a UTXO snapshot")]
+    LoadUtxo {
+        #[arg(long = "in", help = "The file to read")]
+        input: PathBuf,
+        #[arg(
+            long,
+            help = "Content hash of the snapshot, taken from a source you trust"
+        )]
+        hash: String,
+    },
+
     #[command(name = "start-node", about = "Start a node")]
     StartNode {
         #[arg(long, help = "Enable mining mode and send reward to ADDRESS")]
@@ -75,16 +486,69 @@ fn main() -> Result<()> {
 
     let args = Args::parse();
 
+    if let Some(datadir) = args.datadir {
+        config::GLOBAL_CONFIG.set_data_dir(datadir)?;
+    }
+    if let Some(network) = args.network {
+        config::GLOBAL_CONFIG.set_network(network)?;
+    }
+    if let Some(depth) = args.prune {
+        config::GLOBAL_CONFIG.set_prune_depth(depth)?;
+    }
+    if let Some(conf) = args.conf {
+        config::GLOBAL_CONFIG.set_config_file(conf)?;
+    }
+    config::GLOBAL_CONFIG.load_config_file()?;
+    migration::migrate_data_dir(&config::GLOBAL_CONFIG.get_data_dir()?)?;
+    let data_dir = config::GLOBAL_CONFIG.get_network_dir()?;
+    let chain_dir = config::GLOBAL_CONFIG.get_chain_dir()?;
+    let open_wallets = || Wallets::load(&data_dir, args.wallet.as_deref());
+
     match args.cmd {
-        Command::CreateWallet => {
-            let mut wallets = Wallets::try_new()?;
-            let address = wallets.create_wallet()?;
+        Command::CreateWallet { scheme } => {
+            let mut wallets = open_wallets()?;
+            let had_seed = wallets.has_seed();
+            let address = wallets.create_wallet(scheme)?;
             println!("Your new address: {}", address);
+            if !had_seed {
+                println!("Write down this mnemonic, it restores every address of the wallet:");
+                println!("{}", wallets.get_mnemonic()?);
+            }
+
+            Ok(())
+        }
+        Command::CreateNamedWallet { name } => {
+            wallets::create_named_wallet(&data_dir, &name)?;
+            println!("Created and loaded wallet {}", name);
+
+            Ok(())
+        }
+        Command::LoadWallet { name } => {
+            wallets::load_wallet(&data_dir, &name)?;
+            println!("Loaded wallet {}", name);
+
+            Ok(())
+        }
+        Command::UnloadWallet { name } => {
+            wallets::unload_wallet(&data_dir, &name)?;
+            println!("Unloaded wallet {}", name);
+
+            Ok(())
+        }
+        Command::ListWallets => {
+            println!("(default)");
+            for (name, loaded) in wallets::list_wallets(&data_dir)? {
+                if loaded {
+                    println!("{}", name);
+                } else {
+                    println!("{} (not loaded)", name);
+                }
+            }
 
             Ok(())
         }
         Command::CreateBlockchain { address } => {
-            let blockchain = Blockchain::create_blockchain(&address)?;
+            let blockchain = Blockchain::create_blockchain(&chain_dir, &address)?;
             let utxo_set = UTXOSet::new(blockchain);
             utxo_set.reindex()?;
             println!("Done!");
@@ -92,30 +556,271 @@ fn main() -> Result<()> {
             Ok(())
         }
         Command::GetBalance { address } => {
-            if !wallets::validate_address(&address) {
-                return Err(anyhow::anyhow!("Invalid address"));
+            let blockchain = Blockchain::new_blockchain(&chain_dir)?;
+            let utxo_set = UTXOSet::new(blockchain);
+            let balance_of = |address: &str| -> Result<i32> {
+                let pub_key_hash = wallets::get_pub_key_hash(address)?;
+                let utxos = utxo_set.find_utxo(pub_key_hash.as_slice())?;
+                Ok(utxos.iter().map(|utxo| utxo.get_value()).sum())
+            };
+
+            if let Some(address) = address {
+                println!("Balance of {}: {}", address, balance_of(&address)?);
+                return Ok(());
             }
 
-            let payload = utils::base58_decode(address.as_str());
-            let pub_key_hash = &payload[1..payload.len() - wallets::ADDRESS_CHECK_SUM_LEN];
+            // 不指定地址时列出钱包里所有地址，包括只读地址
+            let wallets = open_wallets()?;
+            let mut total = 0;
+            for address in wallets.get_addresses() {
+                let balance = balance_of(&address)?;
+                total += balance;
+                let address_text = labeled(&wallets, &address);
+                if wallets.is_change(&address) {
+                    println!("Balance of {} (change): {}", address_text, balance);
+                } else {
+                    println!("Balance of {}: {}", address_text, balance);
+                }
+            }
+            let mut watched_total = 0;
+            for address in wallets.get_watch_only_addresses() {
+                let balance = balance_of(&address)?;
+                watched_total += balance;
+                println!(
+                    "Balance of {} (watch-only): {}",
+                    labeled(&wallets, &address),
+                    balance
+                );
+            }
+            println!("Total: {}, watch-only: {}", total, watched_total);
 
-            let blockchain = Blockchain::new_blockchain()?;
-            let utxo_set = UTXOSet::new(blockchain);
-            let utxos = utxo_set.find_utxo(pub_key_hash)?;
+            Ok(())
+        }
+        Command::History { address } => {
+            let wallets = open_wallets()?;
+            let address = wallets.resolve_address(&address)?;
+            let blockchain = Blockchain::new_blockchain(&chain_dir)?;
 
-            let mut balance = 0;
-            for utxo in utxos {
-                balance += utxo.get_value();
+            let entries = history::get_history(&blockchain, &wallets, &address)?;
+            if entries.is_empty() {
+                println!("No transactions for {}", labeled(&wallets, &address));
+            }
+            for entry in entries {
+                let amount = match entry.direction {
+                    history::Direction::Sent => -entry.amount,
+                    _ => entry.amount,
+                };
+                println!(
+                    "{} {:+} at height {} ({} confirmations), time {}",
+                    entry.direction, amount, entry.height, entry.confirmations, entry.timestamp
+                );
+                println!("    txid: {}", entry.txid);
+                for counterparty in &entry.counterparties {
+                    match entry.direction {
+                        history::Direction::Sent => {
+                            println!("    to: {}", labeled(&wallets, counterparty))
+                        }
+                        _ => println!("    from: {}", labeled(&wallets, counterparty)),
+                    }
+                }
             }
-            println!("Balance of {}: {}", address, balance);
+
+            Ok(())
+        }
+        Command::WatchAddress {
+            address,
+            public_key,
+        } => {
+            let public_key = match public_key {
+                Some(hex) => Some(
+                    data_encoding::HEXLOWER_PERMISSIVE
+                        .decode(hex.as_bytes())
+                        .map_err(|_| anyhow::anyhow!("Public key is not valid hex"))?,
+                ),
+                None => None,
+            };
+
+            let mut wallets = open_wallets()?;
+            let address = wallets.add_watch_only(address.as_deref(), public_key)?;
+            println!("Watching {}", address);
 
             Ok(())
         }
         Command::ListAddresses => {
-            let wallets = Wallets::try_new()?;
+            let wallets = open_wallets()?;
             for address in wallets.get_addresses() {
-                println!("address: {}", address);
+                let address_text = labeled(&wallets, &address);
+                if wallets.is_imported(&address) {
+                    println!(
+                        "address: {} (imported, not covered by the seed)",
+                        address_text
+                    );
+                } else if wallets.is_change(&address) {
+                    println!("address: {} (change)", address_text);
+                } else {
+                    println!("address: {}", address_text);
+                }
             }
+            for address in wallets.get_watch_only_addresses() {
+                println!("address: {} (watch-only)", labeled(&wallets, &address));
+            }
+
+            Ok(())
+        }
+        Command::SetLabel { address, label } => {
+            let mut wallets = open_wallets()?;
+            wallets.set_label(&address, label.as_deref())?;
+            match label {
+                Some(label) => println!("Labeled {} as {}", address, label),
+                None => println!("Removed the label of {}", address),
+            }
+
+            Ok(())
+        }
+        Command::AddContact { address, name } => {
+            let mut wallets = open_wallets()?;
+            wallets.add_contact(&address, &name)?;
+            println!("Saved {} as {}", address, name);
+
+            Ok(())
+        }
+        Command::RemoveContact { name } => {
+            let mut wallets = open_wallets()?;
+            let address = wallets.remove_contact(&name)?;
+            println!("Removed {} from the address book", address);
+
+            Ok(())
+        }
+        Command::ListContacts => {
+            let wallets = open_wallets()?;
+            for (address, name) in wallets.get_contacts() {
+                println!("{}: {}", name, address);
+            }
+
+            Ok(())
+        }
+        Command::RestoreWallet { mnemonic } => {
+            let mut wallets = open_wallets()?;
+            let blockchain = Blockchain::new_blockchain(&chain_dir).ok();
+            let used = match &blockchain {
+                Some(blockchain) => blockchain.find_used_pub_key_hashes()?,
+                None => {
+                    println!("No local chain, only the first address will be restored");
+                    Default::default()
+                }
+            };
+
+            let addresses =
+                wallets.restore(&mnemonic, |pub_key_hash| used.contains(pub_key_hash))?;
+            for address in addresses {
+                let balance = match &blockchain {
+                    Some(blockchain) => {
+                        let pub_key_hash = wallets::hash_pub_key(
+                            wallets
+                                .get_wallet(&address)
+                                .ok_or(anyhow::anyhow!("ERROR: Wallet not found"))?
+                                .get_public_key(),
+                        );
+                        UTXOSet::new(blockchain.clone())
+                            .find_utxo(pub_key_hash.as_slice())?
+                            .iter()
+                            .map(|utxo| utxo.get_value())
+                            .sum()
+                    }
+                    None => 0,
+                };
+                println!("Restored {}, balance {}", address, balance);
+            }
+
+            Ok(())
+        }
+        Command::ExportKey { address, format } => {
+            let wallets = open_wallets()?;
+            print!("{}", wallets.export_key(&address, format)?);
+            if format == KeyFormat::Base58 {
+                println!();
+            }
+
+            Ok(())
+        }
+        Command::ImportKey { key, file } => {
+            let text = match (key, file) {
+                (Some(key), _) => key,
+                (None, Some(file)) => std::fs::read_to_string(file)?,
+                (None, None) => {
+                    let mut text = String::new();
+                    io::stdin().read_to_string(&mut text)?;
+                    text
+                }
+            };
+            let (pkcs8, public_key) = wallets::decode_private_key(&text)?;
+            let pub_key_hash = wallets::hash_pub_key(public_key.as_slice());
+
+            let mut wallets = open_wallets()?;
+            let address = wallets.import_key(pkcs8, public_key)?;
+            println!("Imported {}", address);
+            println!("This key is not covered by the mnemonic backup");
+
+            // 重新扫描链上这个密钥的余额和历史
+            if let Ok(blockchain) = Blockchain::new_blockchain(&chain_dir) {
+                let utxo_set = UTXOSet::new(blockchain.clone());
+                utxo_set.catch_up()?;
+                let balance: i32 = utxo_set
+                    .find_utxo(pub_key_hash.as_slice())?
+                    .iter()
+                    .map(|utxo| utxo.get_value())
+                    .sum();
+                let history = blockchain.find_key_transactions(pub_key_hash.as_slice())?;
+                println!(
+                    "Balance {}, found in {} transactions",
+                    balance,
+                    history.len()
+                );
+            }
+
+            Ok(())
+        }
+        Command::ShowMnemonic => {
+            let wallets = open_wallets()?;
+            println!("{}", wallets.get_mnemonic()?);
+
+            Ok(())
+        }
+        Command::EncryptWallet => {
+            let mut wallets = open_wallets()?;
+            let passphrase = read_passphrase("New passphrase: ")?;
+            if read_passphrase("Repeat passphrase: ")? != passphrase {
+                return Err(anyhow::anyhow!("Passphrases do not match"));
+            }
+            wallets.encrypt(&passphrase)?;
+            println!("Wallet encrypted, it is now locked");
+
+            Ok(())
+        }
+        Command::Unlock { timeout } => {
+            let mut wallets = open_wallets()?;
+            let passphrase = read_passphrase("Passphrase: ")?;
+            wallets.unlock(&passphrase, timeout)?;
+            println!("Wallet unlocked for {} seconds", timeout);
+
+            Ok(())
+        }
+        Command::Lock => {
+            let mut wallets = open_wallets()?;
+            wallets.lock()?;
+            println!("Wallet locked");
+
+            Ok(())
+        }
+        Command::ChangePassphrase => {
+            let mut wallets = open_wallets()?;
+            let old_passphrase = read_passphrase("Current passphrase: ")?;
+            let new_passphrase = read_passphrase("New passphrase: ")?;
+            if read_passphrase("Repeat new passphrase: ")? != new_passphrase {
+                return Err(anyhow::anyhow!("Passphrases do not match"));
+            }
+            wallets.change_passphrase(&old_passphrase, &new_passphrase)?;
+            println!("Passphrase changed, the wallet is now locked");
 
             Ok(())
         }
@@ -124,35 +829,216 @@ fn main() -> Result<()> {
             to,
             amount,
             mine,
+            coin_selection,
+            outpoints,
+        } => {
+            if !wallets::validate_address(&from) {
+                return Err(anyhow::anyhow!("Invalid from address"));
+            }
+            let mut wallets = open_wallets()?;
+            let to = wallets.resolve_address(&to)?;
+
+            let blockchain = Blockchain::new_blockchain(&chain_dir)?;
+            let utxo_set = UTXOSet::new(blockchain.clone());
+            utxo_set.catch_up()?;
+
+            let transaction = TransactionBuilder::new(&from)
+                .add_recipient(&to, amount)
+                .coin_control(coin_control(coin_selection, outpoints))
+                .build(&mut wallets, &utxo_set)?;
+
+            submit_transaction(&blockchain, &utxo_set, &from, transaction, mine)?;
+            println!("Send success!");
+
+            Ok(())
+        }
+        Command::SendMany {
+            from,
+            to,
+            file,
+            mine,
+            coin_selection,
+            outpoints,
         } => {
             if !wallets::validate_address(&from) {
                 return Err(anyhow::anyhow!("Invalid from address"));
             }
-            if !wallets::validate_address(&to) {
-                return Err(anyhow::anyhow!("Invalid to address"));
+            let mut recipients = to;
+            if let Some(file) = file {
+                recipients.extend(tx_builder::load_recipients(&file)?);
+            }
+            let mut wallets = open_wallets()?;
+            for recipient in recipients.iter_mut() {
+                recipient.address = wallets.resolve_address(&recipient.address)?;
             }
+            let total = tx_builder::total_amount(&recipients)?;
 
-            let blockchain = Blockchain::new_blockchain()?;
+            let blockchain = Blockchain::new_blockchain(&chain_dir)?;
             let utxo_set = UTXOSet::new(blockchain.clone());
+            utxo_set.catch_up()?;
 
-            let transaction =
-                Transaction::new_utxo_transaction(from.as_str(), to.as_str(), amount, &utxo_set)?;
+            let transaction = TransactionBuilder::new(&from)
+                .add_recipients(recipients.iter().cloned())
+                .coin_control(coin_control(coin_selection, outpoints))
+                .build(&mut wallets, &utxo_set)?;
+
+            submit_transaction(&blockchain, &utxo_set, &from, transaction, mine)?;
+            println!("Sent {} to {} recipients", total, recipients.len());
+
+            Ok(())
+        }
+        Command::SignMessage { address, message } => {
+            let wallets = open_wallets()?;
+            println!("{}", message::sign_message(&wallets, &address, &message)?);
+
+            Ok(())
+        }
+        Command::VerifyMessage {
+            address,
+            signature,
+            message,
+        } => {
+            if message::verify_message(&address, &signature, &message)? {
+                println!("Signature is valid");
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("Signature is NOT valid"))
+            }
+        }
+        Command::CreatePsbt {
+            from,
+            to,
+            amount,
+            out,
+            coin_selection,
+            outpoints,
+        } => {
+            let blockchain = Blockchain::new_blockchain(&chain_dir)?;
+            let utxo_set = UTXOSet::new(blockchain);
+            utxo_set.catch_up()?;
+
+            let mut wallets = open_wallets()?;
+            let to = wallets.resolve_address(&to)?;
+            let psbt = TransactionBuilder::new(&from)
+                .add_recipient(&to, amount)
+                .coin_control(coin_control(coin_selection, outpoints))
+                .build_psbt(&mut wallets, &utxo_set)?;
+            psbt.save(&out)?;
+            println!(
+                "Wrote unsigned transaction with {} inputs to {}",
+                psbt.get_prev_outputs().len(),
+                out.display()
+            );
+
+            Ok(())
+        }
+        Command::SignPsbt { input, out } => {
+            let mut psbt = PartiallySignedTransaction::load(&input)?;
+            let wallets = open_wallets()?;
+            let signed = psbt.sign(&wallets)?;
+            psbt.save(&out)?;
+            println!("Signed {} inputs", signed);
+            if psbt.is_complete() {
+                println!("Transaction is fully signed");
+            }
+
+            Ok(())
+        }
+        Command::CombinePsbt { inputs, out } => {
+            let mut psbt = PartiallySignedTransaction::load(&inputs[0])?;
+            for input in &inputs[1..] {
+                psbt.combine(&PartiallySignedTransaction::load(input)?)?;
+            }
+            psbt.save(&out)?;
+            if psbt.is_complete() {
+                println!("Transaction is fully signed");
+            } else {
+                println!("Transaction still needs signatures");
+            }
+
+            Ok(())
+        }
+        Command::InspectPsbt { input } => {
+            let psbt = PartiallySignedTransaction::load(&input)?;
+            let tx = psbt.get_transaction();
+            for (idx, (vin, prev_out)) in
+                tx.get_vin().iter().zip(psbt.get_prev_outputs()).enumerate()
+            {
+                println!(
+                    "Input {}: {}:{} spends {} from {} ({})",
+                    idx,
+                    data_encoding::HEXLOWER.encode(vin.get_txid()),
+                    vin.get_vout(),
+                    prev_out.get_value(),
+                    wallets::convert_address(prev_out.get_pub_key_hash()),
+                    if psbt.is_input_signed(idx) {
+                        "signed"
+                    } else {
+                        "unsigned"
+                    }
+                );
+            }
+            for (idx, out) in tx.get_vout().iter().enumerate() {
+                println!(
+                    "Output {}: {} to {}",
+                    idx,
+                    out.get_value(),
+                    wallets::convert_address(out.get_pub_key_hash())
+                );
+            }
+
+            Ok(())
+        }
+        Command::BroadcastPsbt { input, mine } => {
+            let psbt = PartiallySignedTransaction::load(&input)?;
+            let transaction = psbt.finalize()?;
+
+            let blockchain = Blockchain::new_blockchain(&chain_dir)?;
+            let utxo_set = UTXOSet::new(blockchain.clone());
+            utxo_set.catch_up()?;
+            for vin in transaction.get_vin() {
+                if !utxo_set.is_unspent(vin.get_txid(), vin.get_vout())? {
+                    return Err(anyhow::anyhow!(
+                        "Input {}:{} is already spent",
+                        data_encoding::HEXLOWER.encode(vin.get_txid()),
+                        vin.get_vout()
+                    ));
+                }
+            }
+            if !transaction.verify(&blockchain)? {
+                return Err(anyhow::anyhow!(
+                    "Transaction does not verify against the local chain"
+                ));
+            }
 
             if mine == MINE_TRUE {
-                let coinbase_tx = Transaction::new_coinbase_tx(from.as_str())?;
-                let block = blockchain.mine_block(&vec![transaction, coinbase_tx])?;
+                let miner = wallets::convert_address(psbt.get_prev_outputs()[0].get_pub_key_hash());
+                let coinbase_tx = Transaction::new_coinbase_tx(miner.as_str())?;
+                let block = blockchain.mine_block(&[transaction, coinbase_tx])?;
 
                 utxo_set.update(&block)?;
+
+                if let Some(depth) = config::GLOBAL_CONFIG.get_prune_depth()? {
+                    blockchain.prune(depth)?;
+                }
             } else {
-                server::send_tx(config::DEFAULT_NODE_ADDR, &transaction)?;
+                server::send_tx(config::DEFAULT_NODE_ADDR, &blockchain, &transaction)?;
             }
-            println!("Send success!");
+            println!("Broadcast success!");
 
             Ok(())
         }
         Command::PrintChain => {
-            let mut block_iterator = Blockchain::new_blockchain()?.iterator();
-            while let Ok(Some(block)) = block_iterator.next() {
+            let mut block_iterator = Blockchain::new_blockchain(&chain_dir)?.iterator();
+            loop {
+                let block = match block_iterator.next() {
+                    Ok(Some(block)) => block,
+                    Ok(None) => break,
+                    Err(e) => {
+                        println!("{}, older blocks are not available", e);
+                        break;
+                    }
+                };
                 println!("Pre block hash: {}", block.get_pre_block_hash());
                 println!("Cur block hash: {}", block.get_hash());
                 println!("Cur block Timestamp: {}", block.get_timestamp());
@@ -186,7 +1072,7 @@ fn main() -> Result<()> {
             Ok(())
         }
         Command::ReindexUtxo => {
-            let blockchain = Blockchain::new_blockchain()?;
+            let blockchain = Blockchain::new_blockchain(&chain_dir)?;
             let utxo_set = UTXOSet::new(blockchain.clone());
             utxo_set.reindex()?;
             let count = utxo_set.count_transactions()?;
@@ -194,15 +1080,88 @@ fn main() -> Result<()> {
 
             Ok(())
         }
+        Command::VerifyChain { depth } => {
+            let blockchain = Blockchain::new_blockchain(&chain_dir)?;
+            let report = verify::verify_chain(&blockchain, depth)?;
+
+            println!("Checked {} blocks", report.blocks_checked);
+            if let Some(hash) = &report.stopped_at_pruned {
+                println!("Stopped at pruned block {}", hash);
+            }
+            if let Some(bad) = &report.first_bad_block {
+                println!(
+                    "First bad block: height {}, hash {}: {}",
+                    bad.height, bad.hash, bad.reason
+                );
+            }
+            if report.utxo_checked {
+                for mismatch in &report.utxo_mismatches {
+                    println!("UTXO mismatch: {}", mismatch);
+                }
+            } else {
+                println!("Skipped UTXO set check without the full block history");
+            }
+
+            if !report.is_ok() {
+                return Err(anyhow::anyhow!("Chain verification failed"));
+            }
+            println!("Chain is valid");
+
+            Ok(())
+        }
+        Command::ExportChain { out } => {
+            let blockchain = Blockchain::new_blockchain(&chain_dir)?;
+            let count = chain_file::export_chain(&blockchain, &out)?;
+            println!("Exported {} blocks to {}", count, out.display());
+
+            Ok(())
+        }
+        Command::ImportChain { input } => {
+            let store = SledStore::open(&chain_dir)?;
+            let count = chain_file::import_chain(Arc::new(store), &input)?;
+            println!("Imported {} blocks from {}", count, input.display());
+
+            Ok(())
+        }
+        Command::DumpUtxo { out } => {
+            let blockchain = Blockchain::new_blockchain(&chain_dir)?;
+            let info = snapshot::dump_utxo(&blockchain, &out)?;
+            println!(
+                "Wrote {} transactions at height {} (block {}) to {}",
+                info.transactions,
+                info.height,
+                info.block_hash,
+                out.display()
+            );
+            println!("Content hash: {}", info.content_hash);
+
+            Ok(())
+        }
+        Command::LoadUtxo { input, hash } => {
+            let store = SledStore::open(&chain_dir)?;
+            let info = snapshot::load_utxo(Arc::new(store), &input, &hash)?;
+            println!(
+                "Loaded {} transactions at height {} (block {})",
+                info.transactions, info.height, info.block_hash
+            );
+            println!("Content hash: {}", info.content_hash);
+            println!("History will be validated once the node has downloaded it.");
+
+            Ok(())
+        }
         Command::StartNode { miner } => {
             if let Some(addr) = miner {
-                if wallets::validate_address(addr.as_str()) == false {
+                if !wallets::validate_address(addr.as_str()) {
                     return Err(anyhow::anyhow!("Wrong miner address!"));
                 }
                 println!("Mining is on. Address to receive rewards: {}", addr);
                 config::GLOBAL_CONFIG.set_mining_addr(addr)?;
             }
-            let blockchain = Blockchain::new_blockchain()?;
+            let blockchain = Blockchain::new_blockchain(&chain_dir)?;
+            if let Some(depth) = config::GLOBAL_CONFIG.get_prune_depth()? {
+                let pruned = blockchain.prune(depth)?;
+                println!("Pruning is on. Pruned {} blocks.", pruned);
+            }
             let sockert_addr = config::GLOBAL_CONFIG.get_node_addr()?;
             match sockert_addr {
                 Some(addr) => Server::new(blockchain).run(addr.as_str()),
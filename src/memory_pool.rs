@@ -7,6 +7,12 @@ pub struct MemoryPool {
     inner: RwLock<HashMap<String, Transaction>>,
 }
 
+impl Default for MemoryPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MemoryPool {
     pub fn new() -> Self {
         MemoryPool {
@@ -69,12 +75,22 @@ impl MemoryPool {
             .len();
         Ok(len)
     }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
 }
 
 pub struct BlockInTransit {
     inner: RwLock<Vec<Vec<u8>>>,
 }
 
+impl Default for BlockInTransit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl BlockInTransit {
     pub fn new() -> Self {
         BlockInTransit {
@@ -103,6 +119,10 @@ impl BlockInTransit {
         Ok(len)
     }
 
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
     pub fn first(&self) -> Result<Option<Vec<u8>>> {
         let first = self
             .inner
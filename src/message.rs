@@ -0,0 +1,84 @@
+use anyhow::Result;
+
+use crate::{
+    signature::{self, SCHEMES, SignatureScheme},
+    wallets::{self, Wallets},
+};
+
+/// Keeps a message signature from being valid as a transaction signature.
+const MESSAGE_PREFIX: &[u8] = b"Blockchain Rust Signed Message:\n";
+
+// 签名格式：base64(public_key | signature)，公钥长度由首字节的方案决定，P-256 无法从签名恢复公钥
+fn message_payload(message: &str) -> Vec<u8> {
+    let mut payload = MESSAGE_PREFIX.to_vec();
+    payload.extend_from_slice(&(message.len() as u64).to_le_bytes());
+    payload.extend_from_slice(message.as_bytes());
+    payload
+}
+
+/// Signs `message` with the key of `address`.
+pub fn sign_message(wallets: &Wallets, address: &str, message: &str) -> Result<String> {
+    let pkcs8 = wallets.get_private_key(address)?;
+    let public_key = wallets
+        .get_public_key(address)
+        .ok_or(anyhow::anyhow!("ERROR: Wallet not found"))?;
+
+    let signature = signature::sign(
+        pkcs8.as_slice(),
+        public_key,
+        message_payload(message).as_slice(),
+    )?;
+    let mut blob = public_key.to_vec();
+    blob.extend_from_slice(signature.as_slice());
+
+    Ok(data_encoding::BASE64.encode(&blob))
+}
+
+/// True if `signature` was made for `message` by the key behind `address`.
+pub fn verify_message(address: &str, signature: &str, message: &str) -> Result<bool> {
+    let pub_key_hash = wallets::get_pub_key_hash(address)?;
+    let blob = data_encoding::BASE64
+        .decode(signature.trim().as_bytes())
+        .map_err(|_| anyhow::anyhow!("Signature is not valid base64"))?;
+    let public_key_len = SCHEMES
+        .iter()
+        .map(|scheme| scheme.public_key_len())
+        .find(|len| blob.len() > *len && SignatureScheme::of_public_key(&blob[..*len]).is_ok())
+        .ok_or(anyhow::anyhow!(
+            "Signature is too short or has an unknown key type"
+        ))?;
+
+    let (public_key, signature) = blob.split_at(public_key_len);
+    if wallets::hash_pub_key(public_key) != pub_key_hash {
+        return Ok(false);
+    }
+
+    Ok(signature::verify(
+        public_key,
+        signature,
+        message_payload(message).as_slice(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_and_verify_round_trip() {
+        let data_dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let mut wallets = Wallets::try_new(&data_dir).unwrap();
+        let other = wallets.create_wallet(SignatureScheme::P256).unwrap();
+
+        for scheme in SCHEMES {
+            let address = wallets.create_wallet(*scheme).unwrap();
+            let signature = sign_message(&wallets, &address, "hello").unwrap();
+            assert!(verify_message(&address, &signature, "hello").unwrap());
+            assert!(!verify_message(&address, &signature, "hello!").unwrap());
+            // 签名里的公钥不属于这个地址
+            assert!(!verify_message(&other, &signature, "hello").unwrap());
+        }
+        assert!(verify_message(&other, "not base64", "hello").is_err());
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+}
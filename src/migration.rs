@@ -0,0 +1,492 @@
+use anyhow::Result;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use crate::{
+    blockchain::Blockchain,
+    config::{CHAIN_DIR, NETWORKS, Network},
+    store::{ChainStore, HISTORY_INDEX, META_INDEX},
+    utxo_set::{self, UTXOSet},
+    wallets,
+};
+
+/// Layout of the chain database written by this build.
+pub const SCHEMA_VERSION: u32 = 5;
+
+/// Layout of the wallet file written by this build.
+pub const WALLET_VERSION: u32 = 9;
+
+/// Databases and wallet files written before versioning was introduced.
+const LEGACY_VERSION: u32 = 1;
+
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+// 迁移使用冻结的旧格式，钱包结构以后再改也不影响旧文件的读取
+
+/// `Wallet`: (pkcs8, public_key).
+type WalletV1 = (Vec<u8>, Vec<u8>);
+
+/// `WalletEncryption`: (salt, iterations, check).
+type WalletEncryptionV3 = (Vec<u8>, u32, Vec<u8>);
+
+/// `HdChain` before change addresses: (mnemonic, next_index).
+type HdChainV4 = (Vec<u8>, u32);
+
+/// `HdChain` with change addresses: (mnemonic, next_index, next_change_index).
+type HdChainV7 = (Vec<u8>, u32, u32);
+
+/// `WatchOnly`: (public_key,).
+type WatchOnlyV6 = (Option<Vec<u8>>,);
+
+/// Wallet payload of version 6.
+type WalletsV6 = (
+    HashMap<String, WalletV1>,
+    Option<WalletEncryptionV3>,
+    Option<HdChainV4>,
+    HashSet<String>,
+    HashMap<String, WatchOnlyV6>,
+);
+
+/// Wallet payload of version 7.
+type WalletsV7 = (
+    HashMap<String, WalletV1>,
+    Option<WalletEncryptionV3>,
+    Option<HdChainV7>,
+    HashSet<String>,
+    HashMap<String, WatchOnlyV6>,
+    HashSet<String>,
+);
+
+/// Wallet payload of versions 8 and 9.
+type WalletsV8 = (
+    HashMap<String, WalletV1>,
+    Option<WalletEncryptionV3>,
+    Option<HdChainV7>,
+    HashSet<String>,
+    HashMap<String, WatchOnlyV6>,
+    HashSet<String>,
+    HashMap<String, String>,
+    HashMap<String, String>,
+);
+
+/// Files sled keeps at the top of its directory. A network directory that
+/// has them directly, instead of under `CHAIN_DIR`, uses the old layout.
+const SLED_CONF: &str = "conf";
+const SLED_ENTRIES: &[&str] = &["conf", "db", "blobs"];
+
+/// Wallet files that live next to the chain database.
+const WALLET_ENTRIES: &[&str] = &[wallets::WALLET_FILE, wallets::WALLETS_DIR];
+
+/// Older builds kept the key of an unlocked wallet in this file.
+const LEGACY_UNLOCK_FILE: &str = "wallet.unlock";
+
+/// Rewrites a chain database from schema `from` to `from + 1`.
+struct Migration {
+    from: u32,
+    description: &'static str,
+    run: fn(&Blockchain) -> Result<()>,
+}
+
+/// Rewrites a wallet payload from version `from` to `from + 1`.
+struct WalletMigration {
+    from: u32,
+    description: &'static str,
+    run: fn(Vec<u8>) -> Result<Vec<u8>>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 1,
+        description: "rebuild chainstate keyed by raw txid with output indices",
+        run: rebuild_chainstate,
+    },
+    Migration {
+        from: 2,
+        description: "nothing to do",
+        run: no_changes,
+    },
+    Migration {
+        from: 3,
+        description: "build the transaction height index",
+        run: utxo_set::rebuild_tx_index,
+    },
+    Migration {
+        from: 4,
+        description: "remove the address history index, wallets keep their own",
+        run: remove_history_index,
+    },
+];
+
+const WALLET_MIGRATIONS: &[WalletMigration] = &[
+    WalletMigration {
+        from: 1,
+        description: "wrap wallets in a versioned file header",
+        run: Ok,
+    },
+    WalletMigration {
+        from: 2,
+        description: "add passphrase encryption settings",
+        run: add_wallet_encryption,
+    },
+    WalletMigration {
+        from: 3,
+        description: "add hierarchical deterministic seed",
+        run: add_wallet_seed,
+    },
+    WalletMigration {
+        from: 4,
+        description: "flag keys not backed up by the seed",
+        run: add_imported_keys,
+    },
+    WalletMigration {
+        from: 5,
+        description: "add watch-only addresses",
+        run: add_watch_only,
+    },
+    WalletMigration {
+        from: 6,
+        description: "add change addresses",
+        run: add_change_addresses,
+    },
+    WalletMigration {
+        from: 7,
+        description: "add address labels and contacts",
+        run: add_labels,
+    },
+    WalletMigration {
+        from: 8,
+        description: "store addresses in the bech32m format",
+        run: convert_addresses,
+    },
+];
+
+// 保留版本号，让已经升级过的数据库版本保持一致
+fn no_changes(_: &Blockchain) -> Result<()> {
+    Ok(())
+}
+
+fn remove_history_index(blockchain: &Blockchain) -> Result<()> {
+    blockchain.get_store().clear_index(HISTORY_INDEX)
+}
+
+fn rebuild_chainstate(blockchain: &Blockchain) -> Result<()> {
+    // 裁剪过的节点缺少旧区块，无法重建，必须在清空之前检查
+    if blockchain.is_pruned()? {
+        return Err(anyhow::anyhow!(
+            "The chainstate must be rebuilt from every block, but this node is pruned. \
+             Re-sync required: remove the chain directory and sync again"
+        ));
+    }
+    // 旧的 chainstate 格式无法按新结构读取，直接清空后从区块重建
+    blockchain.get_store().clear_utxo()?;
+    UTXOSet::new(blockchain.clone()).reindex()
+}
+
+fn add_wallet_encryption(payload: Vec<u8>) -> Result<Vec<u8>> {
+    let wallets: HashMap<String, WalletV1> = bincode::deserialize(&payload)?;
+    Ok(bincode::serialize(&(wallets, None::<WalletEncryptionV3>))?)
+}
+
+fn add_wallet_seed(payload: Vec<u8>) -> Result<Vec<u8>> {
+    let (wallets, encryption): (HashMap<String, WalletV1>, Option<WalletEncryptionV3>) =
+        bincode::deserialize(&payload)?;
+    Ok(bincode::serialize(&(
+        wallets,
+        encryption,
+        None::<HdChainV4>,
+    ))?)
+}
+
+fn add_imported_keys(payload: Vec<u8>) -> Result<Vec<u8>> {
+    let (wallets, encryption, hd): (
+        HashMap<String, WalletV1>,
+        Option<WalletEncryptionV3>,
+        Option<HdChainV4>,
+    ) = bincode::deserialize(&payload)?;
+    // 没有种子的钱包里全是随机生成的密钥
+    let imported: HashSet<String> = match hd {
+        Some(_) => HashSet::new(),
+        None => wallets.keys().cloned().collect(),
+    };
+    Ok(bincode::serialize(&(wallets, encryption, hd, imported))?)
+}
+
+fn add_watch_only(payload: Vec<u8>) -> Result<Vec<u8>> {
+    let (wallets, encryption, hd, imported): (
+        HashMap<String, WalletV1>,
+        Option<WalletEncryptionV3>,
+        Option<HdChainV4>,
+        HashSet<String>,
+    ) = bincode::deserialize(&payload)?;
+    let watch_only: HashMap<String, WatchOnlyV6> = HashMap::new();
+    Ok(bincode::serialize(&(
+        wallets, encryption, hd, imported, watch_only,
+    ))?)
+}
+
+fn add_change_addresses(payload: Vec<u8>) -> Result<Vec<u8>> {
+    let (wallets, encryption, hd, imported, watch_only): WalletsV6 =
+        bincode::deserialize(&payload)?;
+    // HdChain 多了 next_change_index
+    let hd = hd.map(|(mnemonic, next_index)| (mnemonic, next_index, 0u32));
+    let change: HashSet<String> = HashSet::new();
+    Ok(bincode::serialize(&(
+        wallets, encryption, hd, imported, watch_only, change,
+    ))?)
+}
+
+fn add_labels(payload: Vec<u8>) -> Result<Vec<u8>> {
+    let (wallets, encryption, hd, imported, watch_only, change): WalletsV7 =
+        bincode::deserialize(&payload)?;
+    let labels: HashMap<String, String> = HashMap::new();
+    let contacts: HashMap<String, String> = HashMap::new();
+    Ok(bincode::serialize(&(
+        wallets, encryption, hd, imported, watch_only, change, labels, contacts,
+    ))?)
+}
+
+fn convert_addresses(payload: Vec<u8>) -> Result<Vec<u8>> {
+    let (wallets, encryption, hd, imported, watch_only, change, labels, contacts): WalletsV8 =
+        bincode::deserialize(&payload)?;
+    // 钱包文件按网络分目录保存，用当前网络的前缀即可
+    let convert = |address: String| wallets::normalize_address(&address).unwrap_or(address);
+    let wallets: HashMap<String, WalletV1> = wallets
+        .into_iter()
+        .map(|(address, wallet)| (convert(address), wallet))
+        .collect();
+    let imported: HashSet<String> = imported.into_iter().map(convert).collect();
+    let watch_only: HashMap<String, WatchOnlyV6> = watch_only
+        .into_iter()
+        .map(|(address, entry)| (convert(address), entry))
+        .collect();
+    let change: HashSet<String> = change.into_iter().map(convert).collect();
+    let labels: HashMap<String, String> = labels
+        .into_iter()
+        .map(|(address, label)| (convert(address), label))
+        .collect();
+    let contacts: HashMap<String, String> = contacts
+        .into_iter()
+        .map(|(address, name)| (convert(address), name))
+        .collect();
+    Ok(bincode::serialize(&(
+        wallets, encryption, hd, imported, watch_only, change, labels, contacts,
+    ))?)
+}
+
+/// Moves data directories of the old layout into one directory per network.
+///
+/// Mainnet used to live in the data dir itself, with the sled database, the
+/// wallets and the testnet and regtest directories side by side. Now each
+/// network has `<data dir>/<network>/`, with the database in `chain/`.
+pub fn migrate_data_dir(data_dir: &Path) -> Result<()> {
+    for network in NETWORKS {
+        // 旧布局中 mainnet 直接使用数据目录本身
+        let old_dir = match network {
+            Network::Mainnet => data_dir.to_path_buf(),
+            network => data_dir.join(network.as_str()),
+        };
+        let network_dir = data_dir.join(network.as_str());
+        remove_unlock_files(&old_dir)?;
+        remove_unlock_files(&network_dir)?;
+
+        // 钱包可能在没有链数据库的目录里，单独迁移
+        if old_dir != network_dir {
+            for name in WALLET_ENTRIES {
+                let source = old_dir.join(name);
+                if !source.exists() {
+                    continue;
+                }
+                let target = network_dir.join(name);
+                if target.exists() {
+                    eprintln!(
+                        "Not moving {}: {} already exists",
+                        source.display(),
+                        target.display()
+                    );
+                    continue;
+                }
+                println!("Moving {} to {}", source.display(), target.display());
+                fs::create_dir_all(&network_dir)?;
+                fs::rename(source, target)?;
+            }
+        }
+
+        if !old_dir.join(SLED_CONF).is_file() {
+            continue;
+        }
+        let chain_dir = network_dir.join(CHAIN_DIR);
+        println!(
+            "Moving the {} chain database from {} to {}",
+            network,
+            old_dir.display(),
+            chain_dir.display()
+        );
+        fs::create_dir_all(&chain_dir)?;
+
+        for entry in fs::read_dir(&old_dir)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if SLED_ENTRIES.contains(&name.as_ref()) || name.starts_with("snap.") {
+                fs::rename(old_dir.join(name.as_ref()), chain_dir.join(name.as_ref()))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Deletes unlocked wallet keys that older builds left in `dir` and in its
+/// named wallets.
+fn remove_unlock_files(dir: &Path) -> Result<()> {
+    let mut dirs = vec![dir.to_path_buf()];
+    let named_dir = dir.join(wallets::WALLETS_DIR);
+    if named_dir.is_dir() {
+        for entry in fs::read_dir(named_dir)? {
+            dirs.push(entry?.path());
+        }
+    }
+    for dir in dirs {
+        let path = dir.join(LEGACY_UNLOCK_FILE);
+        if path.is_file() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn get_schema_version(store: &dyn ChainStore) -> Result<Option<u32>> {
+    match store.get_index(META_INDEX, SCHEMA_VERSION_KEY.as_bytes())? {
+        Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn set_schema_version(store: &dyn ChainStore, version: u32) -> Result<()> {
+    store.put_index(
+        META_INDEX,
+        SCHEMA_VERSION_KEY.as_bytes(),
+        &bincode::serialize(&version)?,
+    )
+}
+
+/// Refuses databases written by a newer build and upgrades older ones in place.
+pub fn migrate_chain(blockchain: &Blockchain) -> Result<()> {
+    let store = blockchain.get_store();
+    let mut version = get_schema_version(store)?.unwrap_or(LEGACY_VERSION);
+
+    if version > SCHEMA_VERSION {
+        return Err(anyhow::anyhow!(
+            "Database schema version {} is newer than the supported version {}, upgrade the node",
+            version,
+            SCHEMA_VERSION
+        ));
+    }
+
+    while version < SCHEMA_VERSION {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|m| m.from == version)
+            .ok_or(anyhow::anyhow!(
+                "No migration from database schema version {}",
+                version
+            ))?;
+        println!(
+            "Migrating database schema {} -> {}: {}",
+            version,
+            version + 1,
+            migration.description
+        );
+
+        (migration.run)(blockchain)?;
+        version += 1;
+        set_schema_version(store, version)?;
+    }
+    store.flush()?;
+
+    Ok(())
+}
+
+/// Brings a wallet payload read at `version` up to `WALLET_VERSION`.
+pub fn migrate_wallet(version: Option<u32>, payload: Vec<u8>) -> Result<Vec<u8>> {
+    let mut version = version.unwrap_or(LEGACY_VERSION);
+    let mut payload = payload;
+
+    if version > WALLET_VERSION {
+        return Err(anyhow::anyhow!(
+            "Wallet file version {} is newer than the supported version {}, upgrade the node",
+            version,
+            WALLET_VERSION
+        ));
+    }
+
+    while version < WALLET_VERSION {
+        let migration =
+            WALLET_MIGRATIONS
+                .iter()
+                .find(|m| m.from == version)
+                .ok_or(anyhow::anyhow!(
+                    "No migration from wallet file version {}",
+                    version
+                ))?;
+        println!(
+            "Migrating wallet file {} -> {}: {}",
+            version,
+            version + 1,
+            migration.description
+        );
+
+        payload = (migration.run)(payload)?;
+        version += 1;
+    }
+
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signature::SignatureScheme;
+
+    #[test]
+    fn legacy_wallet_payload_reads_as_current() {
+        let (pkcs8, public_key) = SignatureScheme::P256.new_key_pair().unwrap();
+        let address = wallets::address_of_pub_key(&public_key);
+        let legacy: HashMap<String, WalletV1> =
+            HashMap::from([(address.clone(), (pkcs8, public_key.clone()))]);
+
+        let payload = migrate_wallet(None, bincode::serialize(&legacy).unwrap()).unwrap();
+        let wallets: wallets::Wallets = bincode::deserialize(&payload).unwrap();
+        assert_eq!(wallets.get_addresses(), vec![address.clone()]);
+        assert_eq!(
+            wallets.get_public_key(&address),
+            Some(public_key.as_slice())
+        );
+    }
+
+    #[test]
+    fn wallets_move_without_a_chain_database() {
+        let data_dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(data_dir.join(wallets::WALLETS_DIR).join("savings")).unwrap();
+        fs::write(data_dir.join(wallets::WALLET_FILE), b"wallet").unwrap();
+
+        migrate_data_dir(&data_dir).unwrap();
+
+        let mainnet_dir = data_dir.join(Network::Mainnet.as_str());
+        assert!(!data_dir.join(wallets::WALLET_FILE).exists());
+        assert_eq!(
+            fs::read(mainnet_dir.join(wallets::WALLET_FILE)).unwrap(),
+            b"wallet"
+        );
+        assert!(
+            mainnet_dir
+                .join(wallets::WALLETS_DIR)
+                .join("savings")
+                .is_dir()
+        );
+        assert!(!mainnet_dir.join(CHAIN_DIR).exists());
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+}
@@ -24,6 +24,12 @@ pub struct Nodes {
     inner: RwLock<Vec<Node>>,
 }
 
+impl Default for Nodes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Nodes {
     pub fn new() -> Self {
         Nodes {
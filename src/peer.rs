@@ -0,0 +1,286 @@
+use anyhow::Result;
+use std::{
+    net::SocketAddr,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    io::AsyncWriteExt as _,
+    net::{
+        TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    sync::{mpsc, watch},
+    time::timeout,
+};
+
+use crate::{config::GLOBAL_CONFIG, server::Package};
+
+/// Packages waiting for a peer beyond this many are refused.
+const OUTBOUND_QUEUE_LEN: usize = 1024;
+
+/// What a peer told about itself in its version message.
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    pub listen_addr: String,
+    pub version: usize,
+    pub best_height: usize,
+    pub services: u64,
+    pub user_agent: String,
+}
+
+/// A live connection to another node. Packages queued with `send` are
+/// written by a writer task, so a slow peer does not block the caller.
+pub struct Peer {
+    addr: SocketAddr,
+    outbound: mpsc::Sender<Package>,
+    // 写失败时通知读任务结束
+    closed: watch::Receiver<bool>,
+    // 握手状态：双方都发了 version 并收到对方的 verack 才算完成
+    info: RwLock<Option<PeerInfo>>,
+    version_sent: AtomicBool,
+    verack_received: AtomicBool,
+}
+
+impl Peer {
+    /// Starts the writer task for `stream` and returns the session with the
+    /// read half, which is left to the caller.
+    pub fn start(stream: TcpStream, write_timeout: Duration) -> Result<(Arc<Peer>, OwnedReadHalf)> {
+        let addr = stream.peer_addr()?;
+        let (reader, writer) = stream.into_split();
+        let (outbound, queue) = mpsc::channel::<Package>(OUTBOUND_QUEUE_LEN);
+        let (close, closed) = watch::channel(false);
+
+        tokio::spawn(async move {
+            if let Err(e) = write_queue(addr, writer, queue, write_timeout).await {
+                eprintln!("Error writing to {}: {}", addr, e);
+            }
+            let _ = close.send(true);
+        });
+
+        Ok((
+            Arc::new(Peer {
+                addr,
+                outbound,
+                closed,
+                info: RwLock::new(None),
+                version_sent: AtomicBool::new(false),
+                verack_received: AtomicBool::new(false),
+            }),
+            reader,
+        ))
+    }
+
+    pub fn get_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The version message of the peer, once received.
+    pub fn get_info(&self) -> Option<PeerInfo> {
+        self.info.read().expect("failed to read peer info").clone()
+    }
+
+    pub fn set_info(&self, info: PeerInfo) -> Result<()> {
+        let mut inner = self.info.write().expect("failed to write peer info");
+        if inner.is_some() {
+            return Err(anyhow::anyhow!(
+                "{} sent a second version message",
+                self.addr
+            ));
+        }
+        *inner = Some(info);
+        Ok(())
+    }
+
+    /// Records that our version message went out. Returns whether it had
+    /// already.
+    pub fn mark_version_sent(&self) -> bool {
+        self.version_sent.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn is_version_sent(&self) -> bool {
+        self.version_sent.load(Ordering::SeqCst)
+    }
+
+    pub fn set_verack_received(&self) -> Result<()> {
+        if !self.is_version_sent() {
+            return Err(anyhow::anyhow!(
+                "{} sent verack before our version",
+                self.addr
+            ));
+        }
+        if self.verack_received.swap(true, Ordering::SeqCst) {
+            return Err(anyhow::anyhow!("{} sent a second verack", self.addr));
+        }
+        Ok(())
+    }
+
+    /// True once both sides have sent a version and acknowledged the
+    /// other's. Only then are other messages accepted.
+    pub fn is_handshake_done(&self) -> bool {
+        self.verack_received.load(Ordering::SeqCst) && self.get_info().is_some()
+    }
+
+    /// Resolves once the writer task has stopped.
+    pub async fn closed(&self) {
+        let mut closed = self.closed.clone();
+        let _ = closed.wait_for(|closed| *closed).await;
+    }
+
+    /// Queues `pkg` for the peer.
+    pub fn send(&self, pkg: Package) -> Result<()> {
+        println!("send data to {}, package: {:?}", self.addr, pkg);
+        self.outbound.try_send(pkg).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => {
+                anyhow::anyhow!("Outbound queue to {} is full", self.addr)
+            }
+            mpsc::error::TrySendError::Closed(_) => {
+                anyhow::anyhow!("Connection to {} is closed", self.addr)
+            }
+        })
+    }
+}
+
+// 队列关闭（会话结束）或写失败时退出；编码失败的包只丢弃，不结束会话
+async fn write_queue(
+    addr: SocketAddr,
+    mut writer: OwnedWriteHalf,
+    mut queue: mpsc::Receiver<Package>,
+    write_timeout: Duration,
+) -> Result<()> {
+    let magic = GLOBAL_CONFIG.get_network()?.magic();
+    while let Some(pkg) = queue.recv().await {
+        let bytes = match pkg.to_frame() {
+            Ok(frame) => frame.encode(magic),
+            Err(e) => {
+                eprintln!("Dropping {} package to {}: {}", pkg.command(), addr, e);
+                continue;
+            }
+        };
+        timeout(write_timeout, writer.write_all(&bytes))
+            .await
+            .map_err(|_| anyhow::anyhow!("Write timed out"))??;
+    }
+    writer.shutdown().await?;
+
+    Ok(())
+}
+
+/// The open peer sessions, one per connection.
+pub struct Peers {
+    inner: RwLock<Vec<Arc<Peer>>>,
+}
+
+impl Default for Peers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Peers {
+    pub fn new() -> Self {
+        Peers {
+            inner: RwLock::new(vec![]),
+        }
+    }
+
+    pub fn get_peers(&self) -> Vec<Arc<Peer>> {
+        self.inner.read().expect("failed to read peers").to_vec()
+    }
+
+    pub fn add_peer(&self, peer: Arc<Peer>) {
+        self.inner
+            .write()
+            .expect("failed to write peers")
+            .push(peer);
+    }
+
+    pub fn remove_peer(&self, addr: SocketAddr) {
+        let mut inner = self.inner.write().expect("failed to write peers");
+        inner.retain(|peer| peer.get_addr() != addr);
+    }
+
+    pub fn find_peer(&self, addr: SocketAddr) -> Option<Arc<Peer>> {
+        self.inner
+            .read()
+            .expect("failed to read peers")
+            .iter()
+            .find(|peer| peer.get_addr() == addr)
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncReadExt as _, net::TcpListener};
+
+    #[tokio::test]
+    async fn session_writes_in_order_until_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut remote, _) = listener.accept().await.unwrap();
+
+        let peers = Peers::new();
+        let (peer, _reader) = Peer::start(stream, Duration::from_secs(5)).unwrap();
+        peers.add_peer(peer.clone());
+        assert!(peers.find_peer(peer.get_addr()).is_some());
+        for addr_from in ["a", "b"] {
+            peer.send(Package::GetBlocks {
+                addr_from: addr_from.to_string(),
+            })
+            .unwrap();
+        }
+
+        // 最后一个引用释放后写任务关闭连接
+        peers.remove_peer(peer.get_addr());
+        assert!(peers.get_peers().is_empty());
+        drop(peer);
+        let mut bytes = Vec::new();
+        remote.read_to_end(&mut bytes).await.unwrap();
+
+        let mut reader = bytes.as_slice();
+        for expected in ["a", "b"] {
+            match Package::read_from(&mut reader).unwrap() {
+                Some(Package::GetBlocks { addr_from }) => assert_eq!(addr_from, expected),
+                other => panic!("unexpected package {:?}", other),
+            }
+        }
+        assert!(Package::read_from(&mut reader).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn oversized_package_is_skipped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut remote, _) = listener.accept().await.unwrap();
+
+        let (peer, _reader) = Peer::start(stream, Duration::from_secs(5)).unwrap();
+        peer.send(Package::Block {
+            addr_from: "a".to_string(),
+            block: vec![0; crate::protocol::MAX_PAYLOAD_LEN + 1],
+        })
+        .unwrap();
+        peer.send(Package::GetBlocks {
+            addr_from: "b".to_string(),
+        })
+        .unwrap();
+        drop(peer);
+        let mut bytes = Vec::new();
+        remote.read_to_end(&mut bytes).await.unwrap();
+
+        let mut reader = bytes.as_slice();
+        match Package::read_from(&mut reader).unwrap() {
+            Some(Package::GetBlocks { addr_from }) => assert_eq!(addr_from, "b"),
+            other => panic!("unexpected package {:?}", other),
+        }
+        assert!(Package::read_from(&mut reader).unwrap().is_none());
+    }
+}
@@ -46,7 +46,7 @@ impl ProofOfWork {
             hash = utils::sha256_digest(data.as_slice());
             let hash_int = BigInt::from_bytes_be(Sign::Plus, hash.as_slice());
 
-            if hash_int.lt(&self.target.borrow()) {
+            if hash_int.lt(self.target.borrow()) {
                 println!("{}", HEXLOWER.encode(hash.as_slice()));
                 break;
             } else {
@@ -58,4 +58,14 @@ impl ProofOfWork {
 
         (nonce, HEXLOWER.encode(hash.as_slice()))
     }
+
+    /// Checks that the block's stored nonce and hash satisfy the target.
+    pub fn validate(&self) -> bool {
+        let data = self.prepare_data(self.block.get_nonce());
+        let hash = utils::sha256_digest(data.as_slice());
+        let hash_int = BigInt::from_bytes_be(Sign::Plus, hash.as_slice());
+
+        HEXLOWER.encode(hash.as_slice()) == self.block.get_hash()
+            && hash_int.lt(self.target.borrow())
+    }
 }
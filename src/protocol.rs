@@ -0,0 +1,229 @@
+use anyhow::Result;
+use std::io::{self, Read, Write};
+
+use crate::utils;
+
+// 帧格式：magic(4) | command(12) | payload_len(4, LE) | checksum(4) | payload
+pub const MAGIC_LEN: usize = 4;
+pub const COMMAND_LEN: usize = 12;
+pub const CHECKSUM_LEN: usize = 4;
+pub const HEADER_LEN: usize = MAGIC_LEN + COMMAND_LEN + 4 + CHECKSUM_LEN;
+
+/// Frames with a larger payload are rejected before it is read.
+pub const MAX_PAYLOAD_LEN: usize = 4 * 1024 * 1024;
+
+/// One message on the wire: a command tag and its payload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    command: String,
+    payload: Vec<u8>,
+}
+
+/// First bytes of the double SHA-256 of `payload`.
+pub fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let digest = utils::sha256_digest(utils::sha256_digest(payload).as_slice());
+    let mut checksum = [0u8; CHECKSUM_LEN];
+    checksum.copy_from_slice(&digest[..CHECKSUM_LEN]);
+    checksum
+}
+
+impl Frame {
+    pub fn new(command: &str, payload: Vec<u8>) -> Result<Self> {
+        if command.is_empty()
+            || command.len() > COMMAND_LEN
+            || !command.bytes().all(|b| b.is_ascii_graphic())
+        {
+            return Err(anyhow::anyhow!("Invalid frame command {:?}", command));
+        }
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return Err(anyhow::anyhow!(
+                "Frame payload of {} bytes exceeds the limit of {}",
+                payload.len(),
+                MAX_PAYLOAD_LEN
+            ));
+        }
+        Ok(Frame {
+            command: command.to_string(),
+            payload,
+        })
+    }
+
+    pub fn get_command(&self) -> &str {
+        self.command.as_str()
+    }
+
+    pub fn get_payload(&self) -> &[u8] {
+        self.payload.as_slice()
+    }
+
+    /// The frame as it goes on the wire.
+    pub fn encode(&self, magic: [u8; MAGIC_LEN]) -> Vec<u8> {
+        let mut command = [0u8; COMMAND_LEN];
+        command[..self.command.len()].copy_from_slice(self.command.as_bytes());
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        bytes.extend_from_slice(&magic);
+        bytes.extend_from_slice(&command);
+        bytes.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&checksum(&self.payload));
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write, magic: [u8; MAGIC_LEN]) -> Result<()> {
+        writer.write_all(&self.encode(magic))?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Reads the next frame, or `None` if the stream ended between frames.
+    /// Frames of another network, oversized or corrupt frames are errors.
+    pub fn read_from(reader: &mut impl Read, magic: [u8; MAGIC_LEN]) -> Result<Option<Self>> {
+        let mut header = [0u8; HEADER_LEN];
+        // 在帧边界处断开是正常关闭
+        match reader.read(&mut header[..1])? {
+            0 => return Ok(None),
+            _ => reader.read_exact(&mut header[1..]).map_err(truncated)?,
+        }
+        let header = FrameHeader::parse(&header, magic)?;
+
+        let mut payload = vec![0u8; header.get_payload_len()];
+        reader.read_exact(&mut payload).map_err(truncated)?;
+
+        Ok(Some(header.into_frame(payload)?))
+    }
+}
+
+/// A checked frame header, for readers that fetch the payload themselves,
+/// e.g. with their own timeouts.
+#[derive(Clone, Debug)]
+pub struct FrameHeader {
+    command: String,
+    payload_len: usize,
+    checksum: [u8; CHECKSUM_LEN],
+}
+
+impl FrameHeader {
+    /// Rejects headers of another network and payloads over
+    /// `MAX_PAYLOAD_LEN`, so nothing is allocated for them.
+    pub fn parse(header: &[u8; HEADER_LEN], magic: [u8; MAGIC_LEN]) -> Result<Self> {
+        let (frame_magic, rest) = header.split_at(MAGIC_LEN);
+        let (command, rest) = rest.split_at(COMMAND_LEN);
+        let (len, frame_checksum) = rest.split_at(4);
+        if frame_magic != magic {
+            return Err(anyhow::anyhow!("Frame is not for this network"));
+        }
+
+        let command_len = command.iter().position(|b| *b == 0).unwrap_or(COMMAND_LEN);
+        if command[command_len..].iter().any(|b| *b != 0) {
+            return Err(anyhow::anyhow!("Frame command is not NUL padded"));
+        }
+        let command = std::str::from_utf8(&command[..command_len])
+            .map_err(|_| anyhow::anyhow!("Frame command is not ASCII"))?;
+
+        let payload_len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        if payload_len > MAX_PAYLOAD_LEN {
+            return Err(anyhow::anyhow!(
+                "Frame payload of {} bytes exceeds the limit of {}",
+                payload_len,
+                MAX_PAYLOAD_LEN
+            ));
+        }
+
+        let mut checksum = [0u8; CHECKSUM_LEN];
+        checksum.copy_from_slice(frame_checksum);
+        Ok(FrameHeader {
+            command: command.to_string(),
+            payload_len,
+            checksum,
+        })
+    }
+
+    pub fn get_payload_len(&self) -> usize {
+        self.payload_len
+    }
+
+    /// Checks `payload` against the header checksum.
+    pub fn into_frame(self, payload: Vec<u8>) -> Result<Frame> {
+        if payload.len() != self.payload_len {
+            return Err(anyhow::anyhow!("Frame payload length mismatch"));
+        }
+        if checksum(&payload) != self.checksum {
+            return Err(anyhow::anyhow!("Frame checksum mismatch"));
+        }
+        Frame::new(&self.command, payload)
+    }
+}
+
+/// Turns an EOF inside a frame into a truncation error.
+pub fn truncated(e: io::Error) -> anyhow::Error {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => {
+            anyhow::anyhow!("Connection closed in the middle of a frame")
+        }
+        _ => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAGIC: [u8; MAGIC_LEN] = *b"BRSM";
+
+    fn read(bytes: &[u8]) -> Result<Option<Frame>> {
+        Frame::read_from(&mut &bytes[..], MAGIC)
+    }
+
+    fn error(bytes: &[u8]) -> String {
+        read(bytes).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn round_trip() {
+        let frame = Frame::new("block", vec![1, 2, 3]).unwrap();
+        let bytes = frame.encode(MAGIC);
+
+        assert_eq!(bytes.len(), HEADER_LEN + 3);
+        assert_eq!(read(&bytes).unwrap(), Some(frame));
+        // 帧边界处结束是正常关闭
+        assert_eq!(read(&[]).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_wrong_magic() {
+        let bytes = Frame::new("inv", vec![0]).unwrap().encode(*b"BRST");
+
+        assert_eq!(error(&bytes), "Frame is not for this network");
+    }
+
+    #[test]
+    fn rejects_oversized_payload() {
+        let mut bytes = Frame::new("block", vec![]).unwrap().encode(MAGIC);
+        let len = (MAX_PAYLOAD_LEN as u32 + 1).to_le_bytes();
+        bytes[MAGIC_LEN + COMMAND_LEN..MAGIC_LEN + COMMAND_LEN + 4].copy_from_slice(&len);
+
+        assert!(error(&bytes).contains("exceeds the limit"));
+        assert!(Frame::new("block", vec![0; MAX_PAYLOAD_LEN + 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        let mut bytes = Frame::new("tx", vec![1, 2, 3]).unwrap().encode(MAGIC);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+
+        assert_eq!(error(&bytes), "Frame checksum mismatch");
+    }
+
+    #[test]
+    fn rejects_truncated_frame() {
+        let bytes = Frame::new("tx", vec![1, 2, 3]).unwrap().encode(MAGIC);
+        let truncated = "Connection closed in the middle of a frame";
+
+        // 头部和负载中途断开都算截断
+        assert_eq!(error(&bytes[..HEADER_LEN / 2]), truncated);
+        assert_eq!(error(&bytes[..bytes.len() - 1]), truncated);
+    }
+}
@@ -0,0 +1,249 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use crate::{
+    coin_selection::CoinControl,
+    transaction::{TXOutput, Transaction},
+    tx_builder::Recipient,
+    utxo_set::UTXOSet,
+    wallets::{self, Wallets},
+};
+
+// 文件格式：magic(4) | version(u16 LE) | bincode(PartiallySignedTransaction)
+const MAGIC: &[u8; 4] = b"RBPT";
+const FORMAT_VERSION: u16 = 1;
+
+/// A transaction that may still be missing signatures, carrying the outputs
+/// its inputs spend so it can be signed without access to the chain.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PartiallySignedTransaction {
+    tx: Transaction,
+    prev_outputs: Vec<TXOutput>,
+}
+
+impl PartiallySignedTransaction {
+    /// Builds an unsigned transfer from `from`, which only needs to be known
+    /// to the wallet as a watch-only or regular address. Change goes to a
+    /// fresh change address when the wallet holds the key of `from` and is
+    /// unlocked, otherwise back to `from`.
+    pub fn create(
+        from: &str,
+        recipients: &[Recipient],
+        wallets: &mut Wallets,
+        utxo_set: &UTXOSet,
+        coin_control: &CoinControl,
+    ) -> Result<Self> {
+        // 只知道地址时公钥留空，由签名方补上
+        let public_key = wallets.get_public_key(from).unwrap_or_default().to_vec();
+        let mut change_address = None;
+        if wallets.get_wallet(from).is_some() && !wallets.is_locked() {
+            change_address = wallets.next_change_address()?;
+        }
+        let (tx, prev_outputs) = Transaction::new_unsigned(
+            from,
+            recipients,
+            change_address.as_deref().unwrap_or(from),
+            &public_key,
+            utxo_set,
+            coin_control,
+        )?;
+        if change_address.is_some() && tx.get_vout().len() > recipients.len() {
+            wallets.add_change_address()?;
+        }
+
+        Ok(PartiallySignedTransaction { tx, prev_outputs })
+    }
+
+    pub fn get_transaction(&self) -> &Transaction {
+        &self.tx
+    }
+
+    pub fn get_prev_outputs(&self) -> &[TXOutput] {
+        self.prev_outputs.as_slice()
+    }
+
+    pub fn is_input_signed(&self, idx: usize) -> bool {
+        self.tx
+            .get_vin()
+            .get(idx)
+            .is_some_and(|vin| !vin.get_signature().is_empty())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        (0..self.tx.get_vin().len()).all(|idx| self.is_input_signed(idx))
+    }
+
+    /// Signs every unsigned input whose key is in `wallets`. Returns the
+    /// number of inputs signed.
+    pub fn sign(&mut self, wallets: &Wallets) -> Result<usize> {
+        let mut signed = 0;
+
+        for idx in 0..self.prev_outputs.len() {
+            if self.is_input_signed(idx) {
+                continue;
+            }
+            let prev_out = self.prev_outputs[idx].clone();
+            let address = wallets::convert_address(prev_out.get_pub_key_hash());
+            let wallet = match wallets.get_wallet(&address) {
+                Some(wallet) => wallet,
+                None => continue,
+            };
+
+            let pkcs8 = wallets.get_private_key(&address)?;
+            self.tx
+                .sign_input(idx, &prev_out, pkcs8.as_slice(), wallet.get_public_key())?;
+            signed += 1;
+        }
+
+        Ok(signed)
+    }
+
+    /// Merges signatures from another copy of the same transaction. Every
+    /// signature taken over must be valid for the output its input spends.
+    pub fn combine(&mut self, other: &PartiallySignedTransaction) -> Result<()> {
+        let same_inputs = self.tx.get_vin().len() == other.tx.get_vin().len()
+            && self
+                .tx
+                .get_vin()
+                .iter()
+                .zip(other.tx.get_vin())
+                .all(|(a, b)| a.get_txid() == b.get_txid() && a.get_vout() == b.get_vout());
+        if !same_inputs
+            || self.tx.get_vout() != other.tx.get_vout()
+            || self.prev_outputs != other.prev_outputs
+        {
+            return Err(anyhow::anyhow!(
+                "Cannot combine partially signed transactions that spend different coins"
+            ));
+        }
+
+        for idx in 0..self.prev_outputs.len() {
+            if !self.is_input_signed(idx) && other.is_input_signed(idx) {
+                // 只接受能解锁被花费输出的公钥和签名
+                if !other.tx.verify_input(idx, &self.prev_outputs[idx])? {
+                    return Err(anyhow::anyhow!(
+                        "Input {} is signed with a key that cannot spend it",
+                        idx
+                    ));
+                }
+                let vin = &other.tx.get_vin()[idx];
+                self.tx
+                    .set_input_signature(idx, vin.get_pub_key(), vin.get_signature())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks every signature and returns the transaction ready to broadcast.
+    pub fn finalize(&self) -> Result<Transaction> {
+        for (idx, prev_out) in self.prev_outputs.iter().enumerate() {
+            if !self.is_input_signed(idx) {
+                return Err(anyhow::anyhow!("Input {} is not signed yet", idx));
+            }
+            if !self.tx.verify_input(idx, prev_out)? {
+                return Err(anyhow::anyhow!("Input {} has an invalid signature", idx));
+            }
+        }
+
+        let mut tx = self.tx.clone();
+        tx.update_id()?;
+        Ok(tx)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(bincode::serialize(self)?.as_slice())?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let mut reader = BufReader::new(file);
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(anyhow::anyhow!("Not a partially signed transaction file"));
+        }
+        let mut version_bytes = [0u8; 2];
+        reader.read_exact(&mut version_bytes)?;
+        let version = u16::from_le_bytes(version_bytes);
+        if version != FORMAT_VERSION {
+            return Err(anyhow::anyhow!(
+                "Unsupported partially signed transaction version {}",
+                version
+            ));
+        }
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
+        let psbt: PartiallySignedTransaction = bincode::deserialize(&buf)?;
+
+        if psbt.prev_outputs.len() != psbt.tx.get_vin().len() {
+            return Err(anyhow::anyhow!(
+                "Partially signed transaction has {} inputs but {} previous outputs",
+                psbt.tx.get_vin().len(),
+                psbt.prev_outputs.len()
+            ));
+        }
+
+        Ok(psbt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{blockchain::Blockchain, signature::SignatureScheme, store::MemoryStore};
+    use std::sync::Arc;
+
+    #[test]
+    fn sign_offline_and_finalize() {
+        let temp_dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        // 签名方持有私钥，在线节点只知道公钥
+        let mut signer = Wallets::try_new(&temp_dir.join("signer")).unwrap();
+        let alice = signer.create_wallet(SignatureScheme::P256).unwrap();
+        let bob = signer.create_wallet(SignatureScheme::P256).unwrap();
+        let mut online = Wallets::try_new(&temp_dir.join("online")).unwrap();
+        let public_key = signer.get_public_key(&alice).unwrap().to_vec();
+        online.add_watch_only(None, Some(public_key)).unwrap();
+
+        let blockchain =
+            Blockchain::create_with_store(Arc::new(MemoryStore::new()), &alice).unwrap();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex().unwrap();
+
+        let psbt = PartiallySignedTransaction::create(
+            &alice,
+            &[Recipient::new(&bob, 3)],
+            &mut online,
+            &utxo_set,
+            &CoinControl::default(),
+        )
+        .unwrap();
+        assert!(!psbt.is_complete());
+        assert!(psbt.finalize().is_err());
+        let path = temp_dir.join("transfer.psbt");
+        psbt.save(&path).unwrap();
+
+        let mut unsigned = PartiallySignedTransaction::load(&path).unwrap();
+        let mut signed = unsigned.clone();
+        assert!(online.get_private_key(&alice).is_err());
+        assert_eq!(signed.sign(&signer).unwrap(), 1);
+        assert!(signed.is_complete());
+
+        unsigned.combine(&signed).unwrap();
+        let tx = unsigned.finalize().unwrap();
+        assert!(tx.verify(&blockchain).unwrap());
+        std::fs::remove_dir_all(&temp_dir).unwrap();
+    }
+}
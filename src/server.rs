@@ -1,14 +1,18 @@
 use anyhow::Result;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
 use std::{
-    error::Error,
-    io::{BufReader, Write},
-    net::{SocketAddr, TcpListener, TcpStream},
-    thread,
+    io::{self, BufWriter, Read, Write},
+    net::{Shutdown, SocketAddr},
+    sync::Arc,
     time::Duration,
 };
+use tokio::{
+    io::AsyncReadExt as _,
+    net::{TcpListener, TcpStream, tcp::OwnedReadHalf},
+    sync::{Notify, OwnedSemaphorePermit, Semaphore, watch},
+    time::timeout,
+};
 
 use crate::{
     block::Block,
@@ -16,63 +20,197 @@ use crate::{
     config::GLOBAL_CONFIG,
     memory_pool::{BlockInTransit, MemoryPool},
     node::Nodes,
+    peer::{Peer, PeerInfo, Peers},
+    protocol::{self, Frame, FrameHeader, HEADER_LEN},
+    snapshot,
     transaction::Transaction,
     utxo_set::UTXOSet,
+    wallet_agent,
 };
 
-const NODE_VERSION: usize = 1;
+/// Version of the peer protocol spoken by this node.
+const PROTOCOL_VERSION: usize = 2;
+/// Peers below this version are disconnected during the handshake.
+const MIN_PROTOCOL_VERSION: usize = 2;
+const USER_AGENT: &str = concat!("/blockchain_rust:", env!("CARGO_PKG_VERSION"), "/");
+
+// 服务标志位，在 version 消息中告知对方本节点能提供什么
+/// Serves every block since genesis.
+pub const SERVICE_FULL_NODE: u64 = 1;
+/// Keeps only recent blocks, or has not downloaded the history yet.
+pub const SERVICE_PRUNED: u64 = 1 << 1;
+/// Mines new blocks.
+pub const SERVICE_MINER: u64 = 1 << 2;
+
 const CENTERAL_NODE: &str = "127.0.0.1:2001";
 
 static GLOBAL_NODES: Lazy<Nodes> = Lazy::new(|| {
     let nodes = Nodes::new();
     nodes.add_node(String::from(CENTERAL_NODE));
 
-    return nodes;
+    nodes
 });
 
-static GLOBAL_MEMORY_POOL: Lazy<MemoryPool> = Lazy::new(|| MemoryPool::new());
+static GLOBAL_PEERS: Lazy<Peers> = Lazy::new(Peers::new);
 
-static GLOBAL_BLOCK_IN_TRANSIT: Lazy<BlockInTransit> = Lazy::new(|| BlockInTransit::new());
+static GLOBAL_MEMORY_POOL: Lazy<MemoryPool> = Lazy::new(MemoryPool::new);
+
+static GLOBAL_BLOCK_IN_TRANSIT: Lazy<BlockInTransit> = Lazy::new(BlockInTransit::new);
+
+// 后台任务发现链状态无效时通知节点停止
+static GLOBAL_STOP: Lazy<Notify> = Lazy::new(Notify::new);
 
 const TCP_WRITE_TIMEOUT: u64 = 1000;
+const TCP_CONNECT_TIMEOUT: u64 = 5000;
+/// How long a started frame may take to arrive in full.
+const TCP_READ_TIMEOUT: u64 = 30_000;
+/// Peers that send nothing for this long are disconnected.
+const PEER_IDLE_TIMEOUT: u64 = 30 * 60 * 1000;
+
+/// Inbound and outbound connections together.
+const MAX_CONNECTIONS: usize = 125;
+/// Block hashes per `inv` package. An entry takes about 72 bytes, so a full
+/// package stays under `protocol::MAX_PAYLOAD_LEN`.
+const MAX_INV_ITEMS: usize = 50_000;
+/// Threads for chain and database work, kept apart from the network tasks.
+const BLOCKING_THREADS: usize = 8;
 
 pub struct Server {
     blockchain: Blockchain,
+    connections: Arc<Semaphore>,
+    shutdown: watch::Sender<bool>,
 }
 
 impl Server {
     pub fn new(blockchain: Blockchain) -> Server {
-        Server { blockchain }
+        Server {
+            blockchain,
+            connections: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
+            shutdown: watch::channel(false).0,
+        }
     }
 
+    /// Serves peers until Ctrl-C.
     pub fn run(&self, addr: &str) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .max_blocking_threads(BLOCKING_THREADS)
+            .build()?;
+        runtime.block_on(self.listen(addr))
+    }
+
+    async fn listen(&self, addr: &str) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
 
         if !addr.eq(CENTERAL_NODE) {
-            let best_height = self.blockchain.get_best_height()?;
-            send_version(CENTERAL_NODE, best_height)?;
+            match self.connect(CENTERAL_NODE).await {
+                Ok(peer) => {
+                    let blockchain = self.blockchain.clone();
+                    tokio::task::spawn_blocking(move || send_version(&peer, &blockchain)).await??;
+                }
+                Err(e) => println!("The {} is not valid, error: {}", CENTERAL_NODE, e),
+            }
         }
-
-        for stream in listener.incoming() {
-            let blockchain = self.blockchain.clone();
-
-            thread::spawn(move || match stream {
-                Ok(stream) => {
-                    if let Err(e) = serve(&blockchain, stream) {
+        snapshot::validate_in_background(&self.blockchain, stop_node)?;
+        let agent = tokio::spawn(wallet_agent::serve(
+            GLOBAL_CONFIG.get_network_dir()?,
+            self.shutdown.subscribe(),
+        ));
+
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, peer_addr) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            println!("error: {}", e);
+                            continue;
+                        }
+                    };
+                    // 连接数已满时直接拒绝
+                    let permit = match self.connections.clone().try_acquire_owned() {
+                        Ok(permit) => permit,
+                        Err(_) => {
+                            println!("Too many connections, refusing {}", peer_addr);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = self.start_session(stream, permit) {
                         eprintln!("Error handling connection: {}", e);
                     }
                 }
-                Err(e) => {
-                    println!("error: {}", e)
-                }
-            });
+                _ = tokio::signal::ctrl_c() => break,
+                _ = GLOBAL_STOP.notified() => break,
+            }
+        }
+
+        println!("Shutting down, waiting for open connections");
+        let _ = self.shutdown.send(true);
+        drop(listener);
+        if let Err(e) = agent.await? {
+            eprintln!("Wallet agent error: {}", e);
         }
+        // 所有会话结束后许可才会全部归还
+        let _ = self
+            .connections
+            .acquire_many(MAX_CONNECTIONS as u32)
+            .await?;
+        let blockchain = self.blockchain.clone();
+        tokio::task::spawn_blocking(move || blockchain.get_store().flush()).await??;
+
+        // 因链状态无效而停止时以错误退出
+        snapshot::check_chainstate(&self.blockchain)
+    }
+
+    /// Registers a session for `stream` and serves it on its own task until
+    /// either side closes it or the node shuts down.
+    fn start_session(&self, stream: TcpStream, permit: OwnedSemaphorePermit) -> Result<Arc<Peer>> {
+        let (peer, reader) = Peer::start(stream, Duration::from_millis(TCP_WRITE_TIMEOUT))?;
+        GLOBAL_PEERS.add_peer(peer.clone());
+
+        let blockchain = self.blockchain.clone();
+        let shutdown = self.shutdown.subscribe();
+        let session = peer.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve(blockchain, session.clone(), reader, shutdown).await {
+                eprintln!("Error handling connection: {}", e);
+            }
+            GLOBAL_PEERS.remove_peer(session.get_addr());
+            drop(permit);
+        });
+
+        Ok(peer)
+    }
 
-        Ok(())
+    /// The session with `addr`, opening a connection if there is none yet.
+    async fn connect(&self, addr: &str) -> Result<Arc<Peer>> {
+        let socket_addr: SocketAddr = addr.parse()?;
+        if let Some(peer) = GLOBAL_PEERS.find_peer(socket_addr) {
+            return Ok(peer);
+        }
+        let permit = self
+            .connections
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| anyhow::anyhow!("Too many connections"))?;
+        let connected = timeout(
+            Duration::from_millis(TCP_CONNECT_TIMEOUT),
+            TcpStream::connect(socket_addr),
+        )
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out")));
+        let stream = match connected {
+            Ok(stream) => stream,
+            Err(e) => {
+                GLOBAL_NODES.evict_node(addr);
+                return Err(e.into());
+            }
+        };
+        self.start_session(stream, permit)
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
 pub enum OpType {
     Block,
     Tx,
@@ -105,241 +243,490 @@ pub enum Package {
         addr_from: String,
         version: usize,
         best_height: usize,
+        services: u64,
+        user_agent: String,
+        // 从快照启动的节点可能没有创世区块
+        genesis_hash: Option<String>,
+    },
+    Verack,
+    // 对方请求的区块已被裁剪或不存在
+    NotFound {
+        addr_from: String,
+        op_type: OpType,
+        id: Vec<u8>,
     },
 }
 
-fn send_version(addr: &str, height: usize) -> Result<()> {
-    let socket_addr: SocketAddr = addr.parse()?;
-    let node_addr = match GLOBAL_CONFIG.get_node_addr()? {
-        Some(value) => value,
-        None => return Err(anyhow::anyhow!("get node addr none")),
-    };
+impl Package {
+    /// The command tag of the frame carrying this package.
+    pub fn command(&self) -> &'static str {
+        match self {
+            Package::Block { .. } => "block",
+            Package::GetBlocks { .. } => "getblocks",
+            Package::GetData { .. } => "getdata",
+            Package::Inv { .. } => "inv",
+            Package::Tx { .. } => "tx",
+            Package::Version { .. } => "version",
+            Package::Verack => "verack",
+            Package::NotFound { .. } => "notfound",
+        }
+    }
 
-    send_data(
-        socket_addr,
-        Package::Version {
-            addr_from: node_addr,
-            version: NODE_VERSION,
-            best_height: height,
-        },
-    )
+    pub fn to_frame(&self) -> Result<Frame> {
+        Frame::new(self.command(), bincode::serialize(self)?)
+    }
+
+    pub fn from_frame(frame: &Frame) -> Result<Self> {
+        let pkg: Package = bincode::deserialize(frame.get_payload())
+            .map_err(|e| anyhow::anyhow!("Malformed {} frame: {}", frame.get_command(), e))?;
+        if pkg.command() != frame.get_command() {
+            return Err(anyhow::anyhow!(
+                "Frame tagged {} carries a {} package",
+                frame.get_command(),
+                pkg.command()
+            ));
+        }
+        Ok(pkg)
+    }
+
+    /// Writes the package as one frame for the selected network.
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<()> {
+        self.to_frame()?
+            .write_to(writer, GLOBAL_CONFIG.get_network()?.magic())
+    }
+
+    /// Reads the next package, or `None` once the peer closed the connection.
+    pub fn read_from(reader: &mut impl Read) -> Result<Option<Self>> {
+        match Frame::read_from(reader, GLOBAL_CONFIG.get_network()?.magic())? {
+            Some(frame) => Ok(Some(Package::from_frame(&frame)?)),
+            None => Ok(None),
+        }
+    }
 }
 
-fn send_get_data(addr: &str, op_type: OpType, id: &[u8]) -> Result<()> {
-    let socket_addr: SocketAddr = addr.parse()?;
-    let node_addr = match GLOBAL_CONFIG.get_node_addr()? {
-        Some(value) => value,
-        None => return Err(anyhow::anyhow!("get node addr none")),
-    };
+/// Asks a running node to shut down, as on Ctrl-C.
+fn stop_node() {
+    GLOBAL_STOP.notify_one();
+}
 
-    send_data(
-        socket_addr,
-        Package::GetData {
-            addr_from: node_addr,
-            op_type,
-            id: id.to_vec(),
-        },
-    )
+/// Pruned nodes and nodes started from an unvalidated UTXO snapshot cannot
+/// serve old blocks, so they advertise themselves as pruned.
+fn lacks_history(blockchain: &Blockchain) -> Result<bool> {
+    Ok(blockchain.is_pruned()? || snapshot::pending_snapshot(blockchain)?.is_some())
 }
 
-fn send_data(socket_addr: SocketAddr, pkg: Package) -> Result<()> {
-    println!("send data to {:?}, package: {:?}", socket_addr, pkg);
-    let mut stream = match TcpStream::connect(socket_addr) {
-        Ok(stream) => stream,
-        Err(e) => {
-            println!("The {} is not valid, error: {}", socket_addr, e);
-            GLOBAL_NODES.evict_node(socket_addr.to_string().as_str());
-            return Ok(());
-        }
+fn local_addr() -> Result<String> {
+    GLOBAL_CONFIG
+        .get_node_addr()?
+        .ok_or(anyhow::anyhow!("get node addr none"))
+}
+
+fn local_services(blockchain: &Blockchain) -> Result<u64> {
+    let mut services = if lacks_history(blockchain)? {
+        SERVICE_PRUNED
+    } else {
+        SERVICE_FULL_NODE
     };
+    if GLOBAL_CONFIG.get_mining_addr()?.is_some() {
+        services |= SERVICE_MINER;
+    }
+    Ok(services)
+}
 
-    stream.set_write_timeout(Some(Duration::from_millis(TCP_WRITE_TIMEOUT)))?;
-    serde_json::to_writer(&stream, &pkg)?;
-    stream.flush()?;
+fn local_version(blockchain: &Blockchain, services: u64) -> Result<Package> {
+    Ok(Package::Version {
+        addr_from: local_addr()?,
+        version: PROTOCOL_VERSION,
+        best_height: blockchain.get_best_height()?,
+        services,
+        user_agent: USER_AGENT.to_string(),
+        genesis_hash: blockchain.get_genesis_hash().ok(),
+    })
+}
 
+/// Rejects peers that are too old or follow another chain. The genesis
+/// check is skipped when either side does not know its genesis block.
+fn check_version(
+    blockchain: &Blockchain,
+    version: usize,
+    genesis_hash: Option<&str>,
+) -> Result<()> {
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(anyhow::anyhow!(
+            "Peer protocol version {} is below the minimum of {}",
+            version,
+            MIN_PROTOCOL_VERSION
+        ));
+    }
+    if let (Some(theirs), Ok(ours)) = (genesis_hash, blockchain.get_genesis_hash())
+        && theirs != ours
+    {
+        return Err(anyhow::anyhow!(
+            "Peer is on another chain, genesis block {} instead of {}",
+            theirs,
+            ours
+        ));
+    }
     Ok(())
 }
 
-fn send_block(addr: &str, block: &Block) -> Result<()> {
-    let socket_addr: SocketAddr = addr.parse()?;
-    let node_addr = match GLOBAL_CONFIG.get_node_addr()? {
-        Some(value) => value,
-        None => return Err(anyhow::anyhow!("get node addr none")),
-    };
+fn send_version(peer: &Peer, blockchain: &Blockchain) -> Result<()> {
+    if peer.mark_version_sent() {
+        return Ok(());
+    }
+    peer.send(local_version(blockchain, local_services(blockchain)?)?)
+}
 
-    send_data(
-        socket_addr,
-        Package::Block {
-            addr_from: node_addr,
-            block: block.serialize()?,
-        },
-    )
+fn send_verack(peer: &Peer) -> Result<()> {
+    peer.send(Package::Verack)
 }
 
-pub fn send_tx(addr: &str, tx: &Transaction) -> Result<()> {
-    let socket_addr: SocketAddr = addr.parse()?;
-    let node_addr = match GLOBAL_CONFIG.get_node_addr()? {
-        Some(value) => value,
-        None => return Err(anyhow::anyhow!("get node addr none")),
-    };
+fn send_get_data(peer: &Peer, op_type: OpType, id: &[u8]) -> Result<()> {
+    peer.send(Package::GetData {
+        addr_from: local_addr()?,
+        op_type,
+        id: id.to_vec(),
+    })
+}
 
-    send_data(
-        socket_addr,
-        Package::Tx {
-            addr_from: node_addr,
-            transaction: tx.serialize()?,
-        },
-    )
+fn send_not_found(peer: &Peer, op_type: OpType, id: &[u8]) -> Result<()> {
+    peer.send(Package::NotFound {
+        addr_from: local_addr()?,
+        op_type,
+        id: id.to_vec(),
+    })
+}
+
+fn send_block(peer: &Peer, block: &Block) -> Result<()> {
+    peer.send(Package::Block {
+        addr_from: local_addr()?,
+        block: block.serialize()?,
+    })
+}
+
+fn send_tx_to(peer: &Peer, tx: &Transaction) -> Result<()> {
+    peer.send(Package::Tx {
+        addr_from: local_addr()?,
+        transaction: tx.serialize()?,
+    })
+}
+
+fn send_get_blocks(peer: &Peer) -> Result<()> {
+    peer.send(Package::GetBlocks {
+        addr_from: local_addr()?,
+    })
+}
+
+// 过长的列表分成多个 inv 发送
+fn send_inv(peer: &Peer, op_type: OpType, blocks: &[Vec<u8>]) -> Result<()> {
+    for chunk in blocks.chunks(MAX_INV_ITEMS) {
+        peer.send(Package::Inv {
+            addr_from: local_addr()?,
+            op_type,
+            items: chunk.to_vec(),
+        })?;
+    }
+
+    Ok(())
 }
 
-fn send_get_blocks(addr: &str) -> Result<()> {
+/// Hands a transaction to the node at `addr` over a short-lived connection,
+/// for wallets that are not running a node themselves. The connection does
+/// the handshake first, advertising no services.
+pub fn send_tx(addr: &str, blockchain: &Blockchain, tx: &Transaction) -> Result<()> {
     let socket_addr: SocketAddr = addr.parse()?;
-    let node_addr = match GLOBAL_CONFIG.get_node_addr()? {
-        Some(value) => value,
-        None => return Err(anyhow::anyhow!("get node addr none")),
+    let mut stream = std::net::TcpStream::connect_timeout(
+        &socket_addr,
+        Duration::from_millis(TCP_CONNECT_TIMEOUT),
+    )?;
+    stream.set_write_timeout(Some(Duration::from_millis(TCP_WRITE_TIMEOUT)))?;
+    stream.set_read_timeout(Some(Duration::from_millis(TCP_READ_TIMEOUT)))?;
+
+    local_version(blockchain, 0)?.write_to(&mut BufWriter::new(&mut stream))?;
+    let (mut version_received, mut verack_received) = (false, false);
+    while !(version_received && verack_received) {
+        match Package::read_from(&mut stream)? {
+            Some(Package::Version {
+                version,
+                genesis_hash,
+                ..
+            }) if !version_received => {
+                check_version(blockchain, version, genesis_hash.as_deref())?;
+                Package::Verack.write_to(&mut BufWriter::new(&mut stream))?;
+                version_received = true;
+            }
+            Some(Package::Verack) if !verack_received => verack_received = true,
+            Some(pkg) => {
+                return Err(anyhow::anyhow!(
+                    "{} sent {} during the handshake",
+                    socket_addr,
+                    pkg.command()
+                ));
+            }
+            None => {
+                return Err(anyhow::anyhow!(
+                    "{} closed the connection during the handshake",
+                    socket_addr
+                ));
+            }
+        }
+    }
+
+    let pkg = Package::Tx {
+        addr_from: local_addr()?,
+        transaction: tx.serialize()?,
     };
+    println!("send data to {:?}, package: {:?}", socket_addr, pkg);
+    pkg.write_to(&mut BufWriter::new(&mut stream))?;
+    stream.shutdown(Shutdown::Both)?;
 
-    send_data(
-        socket_addr,
-        Package::GetBlocks {
-            addr_from: node_addr,
-        },
-    )
+    Ok(())
 }
 
-fn send_inv(addr: &str, op_type: OpType, blocks: &[Vec<u8>]) -> Result<()> {
-    let socket_addr: SocketAddr = addr.parse()?;
-    let node_addr = match GLOBAL_CONFIG.get_node_addr()? {
-        Some(value) => value,
-        None => return Err(anyhow::anyhow!("get node addr none")),
+/// Asks the peer for blocks once the handshake is done, if it is ahead of
+/// us or we still need the history behind a snapshot.
+fn start_sync(blockchain: &Blockchain, peer: &Peer) -> Result<()> {
+    let info = match peer.get_info() {
+        Some(info) if peer.is_handshake_done() => info,
+        _ => return Ok(()),
     };
+    // 从快照启动的节点还需要下载历史区块来验证快照
+    let needs_history = snapshot::pending_snapshot(blockchain)?.is_some();
+    if blockchain.get_best_height()? < info.best_height || needs_history {
+        // 裁剪节点没有完整的区块，不能从它同步
+        if info.services & SERVICE_FULL_NODE == 0 {
+            println!(
+                "peer {} does not serve full history, not syncing from it",
+                info.listen_addr
+            );
+        } else {
+            send_get_blocks(peer)?;
+        }
+    }
+    Ok(())
+}
 
-    send_data(
-        socket_addr,
-        Package::Inv {
-            addr_from: node_addr,
-            op_type,
-            items: blocks.to_vec(),
-        },
+/// Reads the next package of a session, or `None` once the peer closed it
+/// between packages. A peer may stay idle before a package, but once its
+/// first byte arrived the rest must follow within `TCP_READ_TIMEOUT`.
+async fn read_package(reader: &mut OwnedReadHalf) -> Result<Option<Package>> {
+    let mut header = [0u8; HEADER_LEN];
+    let read = timeout(
+        Duration::from_millis(PEER_IDLE_TIMEOUT),
+        reader.read(&mut header[..1]),
     )
+    .await
+    .map_err(|_| anyhow::anyhow!("Peer was idle for too long"))??;
+    // 在帧边界处断开是正常关闭
+    if read == 0 {
+        return Ok(None);
+    }
+
+    let frame = timeout(Duration::from_millis(TCP_READ_TIMEOUT), async {
+        reader
+            .read_exact(&mut header[1..])
+            .await
+            .map_err(protocol::truncated)?;
+        let header = FrameHeader::parse(&header, GLOBAL_CONFIG.get_network()?.magic())?;
+
+        let mut payload = vec![0u8; header.get_payload_len()];
+        reader
+            .read_exact(&mut payload)
+            .await
+            .map_err(protocol::truncated)?;
+        header.into_frame(payload)
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("Read timed out"))??;
+
+    Ok(Some(Package::from_frame(&frame)?))
 }
 
-#[allow(dead_code)]
-fn serve(blockchain: &Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>> {
-    let peer_addr = stream.peer_addr()?;
-    let reader = BufReader::new(&stream);
-    let mut pkg_reader = Deserializer::from_reader(reader).into_iter::<Package>();
+async fn serve(
+    blockchain: Blockchain,
+    peer: Arc<Peer>,
+    mut reader: OwnedReadHalf,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    loop {
+        let pkg = tokio::select! {
+            pkg = read_package(&mut reader) => match pkg? {
+                Some(pkg) => pkg,
+                None => break,
+            },
+            _ = peer.closed() => break,
+            _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+        };
+        println!(
+            "receive request from {:?}, package: {:?}",
+            peer.get_addr(),
+            pkg
+        );
+
+        // 区块链读写都在阻塞线程池里做，不占用网络任务
+        let blockchain = blockchain.clone();
+        let peer = peer.clone();
+        tokio::task::spawn_blocking(move || handle_package(&blockchain, &peer, pkg)).await??;
+    }
 
-    while let Some(Ok(pkg)) = pkg_reader.next() {
-        println!("receive request from {:?}, package: {:?}", peer_addr, pkg);
+    Ok(())
+}
 
-        match pkg {
-            Package::Version {
-                addr_from,
+fn handle_package(blockchain: &Blockchain, peer: &Peer, pkg: Package) -> Result<()> {
+    let peer_addr = peer.get_addr();
+    // 握手完成前只接受 version 和 verack，其他消息直接断开
+    let in_handshake = matches!(pkg, Package::Version { .. } | Package::Verack);
+    if !in_handshake && !peer.is_handshake_done() {
+        return Err(anyhow::anyhow!(
+            "{} sent {} before the handshake finished",
+            peer_addr,
+            pkg.command()
+        ));
+    }
+    // 回复都走同一个连接，addr_from 只用来记录对方的监听地址
+    match pkg {
+        Package::Version {
+            addr_from,
+            version,
+            best_height,
+            services,
+            user_agent,
+            genesis_hash,
+        } => {
+            println!(
+                "version: {}, user_agent: {}, services: {:#x}, best_height: {}, addr_from: {}",
+                version, user_agent, services, best_height, addr_from
+            );
+            check_version(blockchain, version, genesis_hash.as_deref())?;
+            peer.set_info(PeerInfo {
+                listen_addr: addr_from.clone(),
                 version,
                 best_height,
-            } => {
-                println!(
-                    "version: {}, best_height: {}, addr_from: {}",
-                    version, best_height, addr_from
-                );
+                services,
+                user_agent,
+            })?;
+
+            send_version(peer, blockchain)?;
+            send_verack(peer)?;
+            // 不提供服务的对端（如钱包）不是可连接的节点
+            if services != 0 {
+                GLOBAL_NODES.add_node(addr_from);
+            }
+            start_sync(blockchain, peer)?;
+        }
+        Package::Verack => {
+            peer.set_verack_received()?;
+            start_sync(blockchain, peer)?;
+        }
+        Package::GetBlocks { .. } => {
+            let blocks = blockchain.get_block_hashes()?;
+            send_inv(peer, OpType::Block, &blocks)?;
+        }
+        Package::Inv { op_type, items, .. } => match op_type {
+            OpType::Block => {
+                // 后续的 inv 只追加到队列，由正在进行的下载继续取
+                let idle = GLOBAL_BLOCK_IN_TRANSIT.is_empty()?;
+                GLOBAL_BLOCK_IN_TRANSIT.add_blocks(items.as_slice())?;
+
+                if idle && let Some(block_hash) = items.first() {
+                    send_get_data(peer, OpType::Block, block_hash)?;
+                    GLOBAL_BLOCK_IN_TRANSIT.remove(block_hash)?;
+                }
+            }
+            OpType::Tx => {
+                let txid = items.first().ok_or(anyhow::anyhow!("get txid none"))?;
+                let txid_hex = data_encoding::HEXLOWER.encode(txid);
 
-                let local_best_height = blockchain.get_best_height()?;
-                if local_best_height < best_height {
-                    send_get_blocks(addr_from.as_str())?;
+                if !GLOBAL_MEMORY_POOL.contains(txid_hex.as_str())? {
+                    send_get_data(peer, OpType::Tx, txid)?;
                 }
-                if local_best_height > best_height {
-                    send_version(addr_from.as_str(), local_best_height)?;
+            }
+        },
+        Package::GetData {
+            addr_from,
+            op_type,
+            id,
+        } => match op_type {
+            OpType::Block => match blockchain.get_block(id.as_slice())? {
+                Some(block) => send_block(peer, &block)?,
+                None => {
+                    let reason = match blockchain.get_header(id.as_slice())? {
+                        Some(_) => "has been pruned",
+                        None => "is unknown",
+                    };
+                    println!(
+                        "block {} requested by {} {}",
+                        String::from_utf8_lossy(id.as_slice()),
+                        addr_from,
+                        reason
+                    );
+                    send_not_found(peer, OpType::Block, id.as_slice())?;
                 }
-
-                if GLOBAL_NODES.node_is_known(peer_addr.to_string().as_str()) == false {
-                    GLOBAL_NODES.add_node(addr_from);
+            },
+            OpType::Tx => {
+                let txid_hex = data_encoding::HEXLOWER.encode(id.as_slice());
+                match GLOBAL_MEMORY_POOL.get(txid_hex.as_str())? {
+                    Some(tx) => send_tx_to(peer, &tx)?,
+                    None => send_not_found(peer, OpType::Tx, id.as_slice())?,
                 }
             }
-            Package::GetBlocks { addr_from } => {
-                let blocks = blockchain.get_block_hashes();
-                send_inv(addr_from.as_str(), OpType::Block, &blocks)?;
+        },
+        Package::NotFound {
+            addr_from,
+            op_type,
+            id,
+        } => match op_type {
+            // 无法从该节点继续同步，放弃剩余的区块
+            OpType::Block => {
+                GLOBAL_BLOCK_IN_TRANSIT.clear()?;
+                println!(
+                    "peer {} does not have block {}, stopping sync from it",
+                    addr_from,
+                    String::from_utf8_lossy(id.as_slice())
+                );
             }
-            Package::Inv {
+            OpType::Tx => println!(
+                "peer {} no longer has transaction {}",
                 addr_from,
-                op_type,
-                items,
-            } => match op_type {
-                OpType::Block => {
-                    GLOBAL_BLOCK_IN_TRANSIT.add_blocks(items.as_slice())?;
-
-                    if let Some(block_hash) = items.get(0) {
-                        send_get_data(addr_from.as_str(), OpType::Block, block_hash)?;
-                        GLOBAL_BLOCK_IN_TRANSIT.remove(block_hash)?;
-                    }
-                }
-                OpType::Tx => {
-                    let txid = items.first().ok_or(anyhow::anyhow!("get txid none"))?;
-                    let txid_hex = data_encoding::HEXLOWER.encode(txid);
+                data_encoding::HEXLOWER.encode(id.as_slice())
+            ),
+        },
+        Package::Block { block, .. } => {
+            let block = Block::deserialize(&block)?;
+            blockchain.add_block(&block)?;
+            println!("add block: {:?}", block.get_hash());
 
-                    if !GLOBAL_MEMORY_POOL.contains(txid_hex.as_str())? {
-                        send_get_data(addr_from.as_str(), OpType::Tx, txid)?;
-                    }
-                }
-            },
-            Package::GetData {
-                addr_from,
-                op_type,
-                id,
-            } => match op_type {
-                OpType::Block => {
-                    if let Some(block) = blockchain.get_block(id.as_slice())? {
-                        send_block(addr_from.as_str(), &block)?;
-                    }
-                }
-                OpType::Tx => {
-                    let txid_hex = data_encoding::HEXLOWER.encode(id.as_slice());
-                    if let Some(tx) = GLOBAL_MEMORY_POOL.get(txid_hex.as_str())? {
-                        send_tx(addr_from.as_str(), &tx)?;
-                    }
-                }
-            },
-            Package::Block { addr_from, block } => {
-                let block = Block::deserialize(&block)?;
-                blockchain.add_block(&block)?;
-                println!("add block: {:?}", block.get_hash());
+            if GLOBAL_BLOCK_IN_TRANSIT.len()? > 0 {
+                let block_hash = GLOBAL_BLOCK_IN_TRANSIT.first()?;
+                if let Some(block_hash) = block_hash {
+                    send_get_data(peer, OpType::Block, block_hash.as_slice())?;
 
-                if GLOBAL_BLOCK_IN_TRANSIT.len()? > 0 {
-                    let block_hash = GLOBAL_BLOCK_IN_TRANSIT.first()?;
-                    if let Some(block_hash) = block_hash {
-                        send_get_data(addr_from.as_str(), OpType::Block, block_hash.as_slice())?;
+                    GLOBAL_BLOCK_IN_TRANSIT.remove(block_hash.as_slice())?;
+                }
+            } else {
+                let utxo_set = UTXOSet::new(blockchain.clone());
+                utxo_set.catch_up()?;
+                snapshot::validate_in_background(blockchain, stop_node)?;
 
-                        GLOBAL_BLOCK_IN_TRANSIT.remove(block_hash.as_slice())?;
-                    }
-                } else {
-                    let utxo_set = UTXOSet::new(blockchain.clone());
-                    utxo_set.reindex()?;
+                if let Some(depth) = GLOBAL_CONFIG.get_prune_depth()? {
+                    blockchain.prune(depth)?;
                 }
             }
-            Package::Tx {
-                addr_from,
-                transaction,
-            } => {
-                let tx = Transaction::deserialize(&transaction)?;
-                let txid = tx.get_id_bytes();
-                GLOBAL_MEMORY_POOL.add(tx)?;
-
-                let node_addr = GLOBAL_CONFIG
-                    .get_node_addr()?
-                    .ok_or(anyhow::anyhow!("get node addr none"))?;
-
-                if node_addr.eq(CENTERAL_NODE) {
-                    let nodes = GLOBAL_NODES.get_nodes();
-                    for node in &nodes {
-                        // 不发送给自己
-                        if node_addr.eq(node.get_addr().as_str()) {
-                            continue;
-                        }
-                        // 不发送给发送者
-                        if addr_from.eq(node.get_addr().as_str()) {
-                            continue;
-                        }
-                        send_inv(&node.get_addr(), OpType::Tx, &[txid.clone()])?;
+        }
+        Package::Tx { transaction, .. } => {
+            let tx = Transaction::deserialize(&transaction)?;
+            let txid = tx.get_id_bytes();
+            GLOBAL_MEMORY_POOL.add(tx)?;
+
+            if local_addr()?.eq(CENTERAL_NODE) {
+                for other in GLOBAL_PEERS.get_peers() {
+                    // 不发送给发送者和尚未完成握手的会话
+                    if other.get_addr() == peer_addr || !other.is_handshake_done() {
+                        continue;
+                    }
+                    // 其他会话可能刚断开，不影响当前连接
+                    if let Err(e) = send_inv(&other, OpType::Tx, std::slice::from_ref(&txid)) {
+                        eprintln!("{}", e);
                     }
                 }
             }
@@ -348,3 +735,114 @@ fn serve(blockchain: &Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt as _;
+
+    // 把 bytes 写进一条连接后关闭，返回对端的读半边
+    async fn reader_for(bytes: Vec<u8>) -> OwnedReadHalf {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (remote, _) = listener.accept().await.unwrap();
+        stream.write_all(&bytes).await.unwrap();
+        stream.shutdown().await.unwrap();
+        remote.into_split().0
+    }
+
+    fn frame_bytes(pkg: &Package) -> Vec<u8> {
+        pkg.to_frame()
+            .unwrap()
+            .encode(GLOBAL_CONFIG.get_network().unwrap().magic())
+    }
+
+    #[tokio::test]
+    async fn read_package_until_close() {
+        let pkg = Package::GetBlocks {
+            addr_from: "127.0.0.1:2001".to_string(),
+        };
+        let mut reader = reader_for([frame_bytes(&pkg), frame_bytes(&pkg)].concat()).await;
+
+        for _ in 0..2 {
+            assert!(matches!(
+                read_package(&mut reader).await.unwrap(),
+                Some(Package::GetBlocks { .. })
+            ));
+        }
+        assert!(read_package(&mut reader).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn read_package_rejects_corrupt_frame() {
+        let mut bytes = frame_bytes(&Package::GetBlocks {
+            addr_from: "127.0.0.1:2001".to_string(),
+        });
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        let mut reader = reader_for(bytes).await;
+
+        assert!(read_package(&mut reader).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_package_rejects_truncated_header() {
+        let bytes = frame_bytes(&Package::GetBlocks {
+            addr_from: "127.0.0.1:2001".to_string(),
+        });
+        let mut reader = reader_for(bytes[..HEADER_LEN / 2].to_vec()).await;
+
+        // 头部读到一半断开不是正常关闭
+        let error = read_package(&mut reader).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Connection closed in the middle of a frame"
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_must_come_first() {
+        let miner = crate::wallets::convert_address(&[7; 20]);
+        let store = Arc::new(crate::store::MemoryStore::new());
+        let blockchain = Blockchain::create_with_store(store, &miner).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let _remote = listener.accept().await.unwrap();
+        let (peer, _reader) = Peer::start(stream, Duration::from_secs(5)).unwrap();
+        let get_blocks = || Package::GetBlocks {
+            addr_from: "127.0.0.1:2001".to_string(),
+        };
+
+        assert!(handle_package(&blockchain, &peer, get_blocks()).is_err());
+        // 还没发出自己的 version 就收到 verack
+        assert!(handle_package(&blockchain, &peer, Package::Verack).is_err());
+
+        let version = local_version(&blockchain, 0).unwrap();
+        handle_package(&blockchain, &peer, version).unwrap();
+        assert!(peer.is_version_sent());
+        assert!(!peer.is_handshake_done());
+        assert!(handle_package(&blockchain, &peer, get_blocks()).is_err());
+
+        handle_package(&blockchain, &peer, Package::Verack).unwrap();
+        assert!(peer.is_handshake_done());
+        handle_package(&blockchain, &peer, get_blocks()).unwrap();
+        let version = local_version(&blockchain, 0).unwrap();
+        assert!(handle_package(&blockchain, &peer, version).is_err());
+        assert!(handle_package(&blockchain, &peer, Package::Verack).is_err());
+    }
+
+    #[test]
+    fn full_inv_fits_in_a_frame() {
+        let hash = "0".repeat(64).into_bytes();
+        let pkg = Package::Inv {
+            addr_from: "127.0.0.1:2001".to_string(),
+            op_type: OpType::Block,
+            items: vec![hash; MAX_INV_ITEMS],
+        };
+        assert!(pkg.to_frame().is_ok());
+    }
+}
@@ -0,0 +1,309 @@
+use anyhow::Result;
+use std::{fmt, str::FromStr};
+
+use k256::ecdsa::signature::{Signer as _, Verifier as _};
+use p256::{
+    elliptic_curve::sec1::ToEncodedPoint as _,
+    pkcs8::{DecodePrivateKey as _, EncodePrivateKey as _},
+};
+use ring::{
+    rand,
+    signature::{
+        ECDSA_P256_SHA256_FIXED, ECDSA_P256_SHA256_FIXED_SIGNING, ED25519, EcdsaKeyPair,
+        Ed25519KeyPair, KeyPair as _, UnparsedPublicKey,
+    },
+};
+
+// 公钥格式：P-256 为 65 字节未压缩点（兼容旧数据），其他方案为 scheme_id(1) | 原始公钥
+const P256_PUBLIC_KEY_LEN: usize = 65;
+const P256_PUBLIC_KEY_PREFIX: u8 = 0x04;
+
+const SECRET_LEN: usize = 32;
+
+// Ed25519 的 PKCS#8 v1 文档就是固定前缀加 32 字节种子
+const ED25519_PKCS8_PREFIX: &[u8] = &[
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+/// The key types a wallet can hold. Keys of every scheme can be spent on the
+/// same chain: public keys and public key hashes carry the scheme, except
+/// for P-256 whose keys and hashes keep the layout they had before other
+/// schemes existed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum SignatureScheme {
+    /// ECDSA on NIST P-256 with SHA-256.
+    #[default]
+    P256,
+    Ed25519,
+    /// ECDSA on secp256k1 with SHA-256.
+    Secp256k1,
+}
+
+pub const SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::P256,
+    SignatureScheme::Ed25519,
+    SignatureScheme::Secp256k1,
+];
+
+impl fmt::Display for SignatureScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureScheme::P256 => f.write_str("p256"),
+            SignatureScheme::Ed25519 => f.write_str("ed25519"),
+            SignatureScheme::Secp256k1 => f.write_str("secp256k1"),
+        }
+    }
+}
+
+impl FromStr for SignatureScheme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "p256" | "p-256" | "secp256r1" => Ok(SignatureScheme::P256),
+            "ed25519" => Ok(SignatureScheme::Ed25519),
+            "secp256k1" | "k256" => Ok(SignatureScheme::Secp256k1),
+            _ => Err(anyhow::anyhow!("Unknown signature scheme: {}", s)),
+        }
+    }
+}
+
+impl SignatureScheme {
+    /// The byte that tags keys and hashes of this scheme.
+    pub fn id(&self) -> u8 {
+        match self {
+            SignatureScheme::P256 => 0,
+            SignatureScheme::Ed25519 => 1,
+            SignatureScheme::Secp256k1 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self> {
+        SCHEMES
+            .iter()
+            .find(|scheme| scheme.id() == id)
+            .copied()
+            .ok_or(anyhow::anyhow!("Unknown signature scheme id {}", id))
+    }
+
+    /// Length of the raw public key, without the scheme tag.
+    fn raw_public_key_len(&self) -> usize {
+        match self {
+            SignatureScheme::P256 => P256_PUBLIC_KEY_LEN,
+            SignatureScheme::Ed25519 => 32,
+            SignatureScheme::Secp256k1 => 33,
+        }
+    }
+
+    /// Length of a public key of this scheme as it appears in wallets and
+    /// transaction inputs.
+    pub fn public_key_len(&self) -> usize {
+        match self {
+            SignatureScheme::P256 => P256_PUBLIC_KEY_LEN,
+            scheme => 1 + scheme.raw_public_key_len(),
+        }
+    }
+
+    /// The scheme of a public key, going by its first byte.
+    pub fn of_public_key(public_key: &[u8]) -> Result<Self> {
+        let scheme = match public_key.first() {
+            Some(&P256_PUBLIC_KEY_PREFIX) => SignatureScheme::P256,
+            Some(id) => Self::from_id(*id)?,
+            None => return Err(anyhow::anyhow!("Public key is empty")),
+        };
+        if public_key.len() != scheme.public_key_len() {
+            return Err(anyhow::anyhow!("Invalid {} public key length", scheme));
+        }
+        Ok(scheme)
+    }
+
+    /// The scheme of the key behind a public key hash.
+    pub fn of_pub_key_hash(pub_key_hash: &[u8]) -> Result<Self> {
+        if pub_key_hash.len() == crate::wallets::PUB_KEY_HASH_LEN {
+            return Ok(SignatureScheme::P256);
+        }
+        match pub_key_hash.first() {
+            Some(id) if *id != SignatureScheme::P256.id() => Self::from_id(*id),
+            _ => Err(anyhow::anyhow!("Invalid public key hash")),
+        }
+    }
+
+    fn tag_public_key(&self, raw: &[u8]) -> Vec<u8> {
+        match self {
+            SignatureScheme::P256 => raw.to_vec(),
+            scheme => [&[scheme.id()][..], raw].concat(),
+        }
+    }
+
+    fn raw_public_key<'a>(&self, public_key: &'a [u8]) -> &'a [u8] {
+        match self {
+            SignatureScheme::P256 => public_key,
+            _ => &public_key[1..],
+        }
+    }
+
+    /// Checks that `public_key` is a valid point of this scheme.
+    pub fn validate_public_key(&self, public_key: &[u8]) -> Result<()> {
+        if Self::of_public_key(public_key)? != *self {
+            return Err(anyhow::anyhow!("Not a {} public key", self));
+        }
+        let raw = self.raw_public_key(public_key);
+        let valid = match self {
+            SignatureScheme::P256 => p256::PublicKey::from_sec1_bytes(raw).is_ok(),
+            SignatureScheme::Ed25519 => raw.len() == 32,
+            SignatureScheme::Secp256k1 => k256::PublicKey::from_sec1_bytes(raw).is_ok(),
+        };
+        if !valid {
+            return Err(anyhow::anyhow!("Invalid {} public key", self));
+        }
+        Ok(())
+    }
+
+    /// A random key pair as (PKCS#8 document, public key).
+    pub fn new_key_pair(&self) -> Result<(Vec<u8>, Vec<u8>)> {
+        match self {
+            SignatureScheme::P256 => {
+                let rng = rand::SystemRandom::new();
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                    .map_err(|_| anyhow::anyhow!("Ring error: failed to generate pkcs8"))?;
+                let key_pair = EcdsaKeyPair::from_pkcs8(
+                    &ECDSA_P256_SHA256_FIXED_SIGNING,
+                    pkcs8.as_ref(),
+                    &rng,
+                )
+                .map_err(|_| anyhow::anyhow!("Ring error: failed to derive key pair"))?;
+                Ok((
+                    pkcs8.as_ref().to_vec(),
+                    key_pair.public_key().as_ref().to_vec(),
+                ))
+            }
+            _ => self.key_pair_from_secret(&crate::utils::random_bytes(SECRET_LEN)?),
+        }
+    }
+
+    /// The key pair for a raw 32-byte secret: a scalar for the ECDSA
+    /// curves, the seed for Ed25519.
+    pub fn key_pair_from_secret(&self, secret: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        match self {
+            SignatureScheme::P256 => {
+                let secret_key = p256::SecretKey::from_slice(secret)
+                    .map_err(|_| anyhow::anyhow!("Invalid P-256 private key"))?;
+                let pkcs8 = secret_key
+                    .to_pkcs8_der()
+                    .map_err(|e| anyhow::anyhow!("Failed to encode private key: {}", e))?;
+                let public_key = secret_key.public_key().to_encoded_point(false);
+                Ok((pkcs8.as_bytes().to_vec(), public_key.as_bytes().to_vec()))
+            }
+            SignatureScheme::Ed25519 => {
+                if secret.len() != SECRET_LEN {
+                    return Err(anyhow::anyhow!("Invalid Ed25519 private key"));
+                }
+                let pkcs8 = [ED25519_PKCS8_PREFIX, secret].concat();
+                let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&pkcs8)
+                    .map_err(|_| anyhow::anyhow!("Invalid Ed25519 private key"))?;
+                Ok((pkcs8, self.tag_public_key(key_pair.public_key().as_ref())))
+            }
+            SignatureScheme::Secp256k1 => {
+                let secret_key = k256::SecretKey::from_slice(secret)
+                    .map_err(|_| anyhow::anyhow!("Invalid secp256k1 private key"))?;
+                let pkcs8 = secret_key
+                    .to_pkcs8_der()
+                    .map_err(|e| anyhow::anyhow!("Failed to encode private key: {}", e))?;
+                let public_key = secret_key.public_key().to_encoded_point(true);
+                Ok((
+                    pkcs8.as_bytes().to_vec(),
+                    self.tag_public_key(public_key.as_bytes()),
+                ))
+            }
+        }
+    }
+
+    /// The raw secret inside a PKCS#8 document of this scheme.
+    pub fn secret_from_pkcs8(&self, pkcs8: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            SignatureScheme::P256 => {
+                let secret_key = p256::SecretKey::from_pkcs8_der(pkcs8)
+                    .map_err(|e| anyhow::anyhow!("Invalid PKCS#8 private key: {}", e))?;
+                Ok(secret_key.to_bytes().to_vec())
+            }
+            SignatureScheme::Ed25519 => {
+                Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8)
+                    .map_err(|_| anyhow::anyhow!("Invalid PKCS#8 private key"))?;
+                // v1 和 v2 文档里种子都紧跟在前缀之后
+                Ok(
+                    pkcs8[ED25519_PKCS8_PREFIX.len()..ED25519_PKCS8_PREFIX.len() + SECRET_LEN]
+                        .to_vec(),
+                )
+            }
+            SignatureScheme::Secp256k1 => {
+                let secret_key = k256::SecretKey::from_pkcs8_der(pkcs8)
+                    .map_err(|e| anyhow::anyhow!("Invalid PKCS#8 private key: {}", e))?;
+                Ok(secret_key.to_bytes().to_vec())
+            }
+        }
+    }
+
+    /// Finds the scheme of a PKCS#8 document and returns it with the key pair.
+    pub fn from_pkcs8(pkcs8: &[u8]) -> Result<(Self, Vec<u8>, Vec<u8>)> {
+        for scheme in SCHEMES {
+            if let Ok(secret) = scheme.secret_from_pkcs8(pkcs8) {
+                let (pkcs8, public_key) = scheme.key_pair_from_secret(&secret)?;
+                return Ok((*scheme, pkcs8, public_key));
+            }
+        }
+        Err(anyhow::anyhow!("Unsupported PKCS#8 private key"))
+    }
+}
+
+/// Signs `message` with the private key of `public_key`.
+pub fn sign(pkcs8: &[u8], public_key: &[u8], message: &[u8]) -> Result<Vec<u8>> {
+    match SignatureScheme::of_public_key(public_key)? {
+        SignatureScheme::P256 => {
+            let rng = rand::SystemRandom::new();
+            let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8, &rng)
+                .map_err(|_| anyhow::anyhow!("Invalid P-256 private key"))?;
+            let signature = key_pair
+                .sign(&rng, message)
+                .map_err(|_| anyhow::anyhow!("Ring error: failed to sign"))?;
+            Ok(signature.as_ref().to_vec())
+        }
+        SignatureScheme::Ed25519 => {
+            let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8)
+                .map_err(|_| anyhow::anyhow!("Invalid Ed25519 private key"))?;
+            Ok(key_pair.sign(message).as_ref().to_vec())
+        }
+        SignatureScheme::Secp256k1 => {
+            let signing_key = k256::ecdsa::SigningKey::from_pkcs8_der(pkcs8)
+                .map_err(|_| anyhow::anyhow!("Invalid secp256k1 private key"))?;
+            let signature: k256::ecdsa::Signature = signing_key.sign(message);
+            Ok(signature.to_bytes().to_vec())
+        }
+    }
+}
+
+/// True if `signature` over `message` was made by the key of `public_key`.
+pub fn verify(public_key: &[u8], signature: &[u8], message: &[u8]) -> bool {
+    let scheme = match SignatureScheme::of_public_key(public_key) {
+        Ok(scheme) => scheme,
+        Err(_) => return false,
+    };
+    let raw = scheme.raw_public_key(public_key);
+    match scheme {
+        SignatureScheme::P256 => UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, raw)
+            .verify(message, signature)
+            .is_ok(),
+        SignatureScheme::Ed25519 => UnparsedPublicKey::new(&ED25519, raw)
+            .verify(message, signature)
+            .is_ok(),
+        SignatureScheme::Secp256k1 => {
+            let verifying_key = match k256::ecdsa::VerifyingKey::from_sec1_bytes(raw) {
+                Ok(key) => key,
+                Err(_) => return false,
+            };
+            match k256::ecdsa::Signature::from_slice(signature) {
+                Ok(signature) => verifying_key.verify(message, &signature).is_ok(),
+                Err(_) => false,
+            }
+        }
+    }
+}
@@ -0,0 +1,378 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+};
+
+use crate::{
+    block::{Block, BlockHeader, GENESIS_PRE_BLOCK_HASH},
+    blockchain::Blockchain,
+    proof_of_work::ProofOfWork,
+    store::{ChainStore, META_INDEX, UTXOs},
+    utils,
+    utxo_set::{self, UTXOSet},
+};
+
+// 文件格式：magic(4) | version(u16 LE) | bincode(UtxoSnapshot)
+const MAGIC: &[u8; 4] = b"RBUS";
+const FORMAT_VERSION: u16 = 2;
+
+/// Base block of a loaded snapshot whose history has not been validated yet.
+const SNAPSHOT_BASE_KEY: &str = "snapshot_base";
+const SNAPSHOT_HASH_KEY: &str = "snapshot_content_hash";
+/// Set when a loaded snapshot turned out not to match the history.
+const CHAINSTATE_INVALID_KEY: &str = "chainstate_invalid";
+
+/// Unspent outputs of every transaction, keyed by txid.
+type UtxoEntries = Vec<(Vec<u8>, UTXOs)>;
+
+static VALIDATION_RUNNING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Deserialize, Serialize)]
+struct UtxoSnapshot {
+    best_block: BlockHeader,
+    entries: UtxoEntries,
+    content_hash: Vec<u8>,
+}
+
+pub struct SnapshotInfo {
+    pub block_hash: String,
+    pub height: usize,
+    pub transactions: usize,
+    pub content_hash: String,
+}
+
+/// SHA-256 over the base block header followed by the entries in txid order,
+/// independent of how they are stored. The header is covered so a trusted
+/// hash also pins the block the entries belong to.
+fn content_hash(best_block: &BlockHeader, entries: &[(Vec<u8>, UTXOs)]) -> Result<Vec<u8>> {
+    let mut sorted = entries.to_vec();
+    sorted.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut data = best_block.serialize()?;
+    data.extend(bincode::serialize(&sorted)?);
+    Ok(utils::sha256_digest(data.as_slice()))
+}
+
+/// Writes the chainstate at the current tip to `path`.
+pub fn dump_utxo(blockchain: &Blockchain, path: &Path) -> Result<SnapshotInfo> {
+    let utxo_set = UTXOSet::new(blockchain.clone());
+    utxo_set.catch_up()?;
+
+    let tip_hash = blockchain.get_tip_hash();
+    let best_block = blockchain
+        .get_header(tip_hash.as_bytes())?
+        .ok_or(anyhow::anyhow!("The tip hash is invalid."))?;
+    let entries = blockchain.get_store().utxo_entries()?;
+    let snapshot = UtxoSnapshot {
+        content_hash: content_hash(&best_block, &entries)?,
+        best_block,
+        entries,
+    };
+
+    let file = File::create(path)?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    writer.write_all(bincode::serialize(&snapshot)?.as_slice())?;
+    writer.flush()?;
+
+    Ok(SnapshotInfo {
+        block_hash: tip_hash,
+        height: snapshot.best_block.get_height(),
+        transactions: snapshot.entries.len(),
+        content_hash: data_encoding::HEXLOWER.encode(&snapshot.content_hash),
+    })
+}
+
+/// Starts an empty store from the snapshot in `path`. The snapshot's content
+/// hash must match `trusted_hash`, which has to come from a trusted source:
+/// a consistent snapshot is not necessarily a correct one.
+pub fn load_utxo(
+    store: Arc<dyn ChainStore>,
+    path: &Path,
+    trusted_hash: &str,
+) -> Result<SnapshotInfo> {
+    if store.get_tip_hash()?.is_some() {
+        return Err(anyhow::anyhow!(
+            "A UTXO snapshot can only be loaded into an empty data directory"
+        ));
+    }
+
+    let file = File::open(path)?;
+    let mut reader = BufReader::new(file);
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(anyhow::anyhow!("Not a UTXO snapshot file"));
+    }
+    let mut version_bytes = [0u8; 2];
+    reader.read_exact(&mut version_bytes)?;
+    let version = u16::from_le_bytes(version_bytes);
+    if version != FORMAT_VERSION {
+        return Err(anyhow::anyhow!(
+            "Unsupported UTXO snapshot version {}",
+            version
+        ));
+    }
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf)?;
+    let snapshot: UtxoSnapshot = bincode::deserialize(&buf)?;
+
+    if content_hash(&snapshot.best_block, &snapshot.entries)? != snapshot.content_hash {
+        return Err(anyhow::anyhow!("UTXO snapshot content hash mismatch"));
+    }
+    let hash_hex = data_encoding::HEXLOWER.encode(&snapshot.content_hash);
+    if trusted_hash != hash_hex {
+        return Err(anyhow::anyhow!(
+            "UTXO snapshot hash {} does not match the trusted hash {}",
+            hash_hex,
+            trusted_hash
+        ));
+    }
+
+    let block_hash = String::from(snapshot.best_block.get_hash());
+    let blockchain = Blockchain::create_from_snapshot(store.clone(), &snapshot.best_block)?;
+    UTXOSet::new(blockchain).replace(&snapshot.entries, block_hash.as_str())?;
+    store.put_index(
+        META_INDEX,
+        SNAPSHOT_BASE_KEY.as_bytes(),
+        block_hash.as_bytes(),
+    )?;
+    store.put_index(
+        META_INDEX,
+        SNAPSHOT_HASH_KEY.as_bytes(),
+        &snapshot.content_hash,
+    )?;
+    store.flush()?;
+
+    Ok(SnapshotInfo {
+        block_hash,
+        height: snapshot.best_block.get_height(),
+        transactions: snapshot.entries.len(),
+        content_hash: hash_hex,
+    })
+}
+
+/// The snapshot base block, if the node started from a snapshot whose history
+/// has not been validated yet.
+pub fn pending_snapshot(blockchain: &Blockchain) -> Result<Option<String>> {
+    match blockchain
+        .get_store()
+        .get_index(META_INDEX, SNAPSHOT_BASE_KEY.as_bytes())?
+    {
+        Some(bytes) => Ok(Some(String::from_utf8(bytes)?)),
+        None => Ok(None),
+    }
+}
+
+/// Fails if a loaded snapshot was found not to match the history. The
+/// chainstate is wrong then and the node must sync again.
+pub fn check_chainstate(blockchain: &Blockchain) -> Result<()> {
+    match blockchain
+        .get_store()
+        .get_index(META_INDEX, CHAINSTATE_INVALID_KEY.as_bytes())?
+    {
+        Some(base) => Err(anyhow::anyhow!(
+            "The UTXO snapshot loaded at block {} does not match the block history, \
+             so the chainstate is invalid. Remove the chain directory and sync again",
+            String::from_utf8_lossy(&base)
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Replays the blocks from genesis up to `base` and returns the header of
+/// `base` and the unspent outputs at it, or `None` while blocks are still
+/// missing. Every block must have a valid proof of work and a single coinbase
+/// within the subsidy, and every input must spend an unspent output with a
+/// valid signature. The snapshot itself is not consulted.
+fn replay_history(
+    blockchain: &Blockchain,
+    base: &str,
+) -> Result<Option<(BlockHeader, UtxoEntries)>> {
+    // 从 base 到创世块的区块都下载完才能验证
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut current_hash = base.to_string();
+    while current_hash != GENESIS_PRE_BLOCK_HASH {
+        let block = match blockchain.get_block(current_hash.as_bytes())? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        current_hash = block.get_pre_block_hash();
+        blocks.push(block);
+    }
+    blocks.reverse();
+
+    let mut outputs: HashMap<Vec<u8>, UTXOs> = HashMap::new();
+    for (height, block) in blocks.iter().enumerate() {
+        if block.get_height() != height || !ProofOfWork::new_proof_of_work(block.clone()).validate()
+        {
+            return Err(anyhow::anyhow!(
+                "Block {} below the snapshot base is invalid",
+                block.get_hash()
+            ));
+        }
+        block.check_coinbase()?;
+
+        for tx in block.get_transactions() {
+            let txid_hex = data_encoding::HEXLOWER.encode(tx.get_id());
+            if !tx.has_valid_id()? {
+                return Err(anyhow::anyhow!(
+                    "Block {} contains invalid transaction {}",
+                    block.get_hash(),
+                    txid_hex
+                ));
+            }
+            if !tx.is_coinbase() {
+                for (idx, vin) in tx.get_vin().iter().enumerate() {
+                    let outs = outputs.entry(vin.get_txid().to_vec()).or_default();
+                    let prev_out = outs
+                        .iter()
+                        .position(|(vout, _)| *vout == vin.get_vout())
+                        .map(|pos| outs.remove(pos).1);
+                    if outs.is_empty() {
+                        outputs.remove(vin.get_txid());
+                    }
+                    let valid = match prev_out {
+                        Some(prev_out) => tx.verify_input(idx, &prev_out)?,
+                        None => false,
+                    };
+                    if !valid {
+                        return Err(anyhow::anyhow!(
+                            "Transaction {} in block {} spends an output it may not",
+                            txid_hex,
+                            block.get_hash()
+                        ));
+                    }
+                }
+            }
+            outputs.insert(
+                tx.get_id().to_vec(),
+                tx.get_vout().iter().cloned().enumerate().collect(),
+            );
+        }
+    }
+
+    let header = blocks
+        .last()
+        .ok_or(anyhow::anyhow!("Snapshot base {} has no blocks", base))?
+        .get_header();
+
+    Ok(Some((header, outputs.into_iter().collect())))
+}
+
+/// Rebuilds the UTXO set at the snapshot base from downloaded blocks and
+/// compares it with the snapshot. Returns `None` while history is still
+/// incomplete, otherwise whether the snapshot was correct. A wrong snapshot
+/// marks the chainstate invalid, see `check_chainstate`.
+pub fn validate_snapshot(blockchain: &Blockchain) -> Result<Option<bool>> {
+    let base = match pending_snapshot(blockchain)? {
+        Some(base) => base,
+        None => return Ok(Some(true)),
+    };
+
+    let (header, entries) = match replay_history(blockchain, base.as_str())? {
+        Some(replayed) => replayed,
+        None => return Ok(None),
+    };
+
+    let store = blockchain.get_store();
+    let expected = store
+        .get_index(META_INDEX, SNAPSHOT_HASH_KEY.as_bytes())?
+        .ok_or(anyhow::anyhow!("Snapshot content hash is missing"))?;
+    if content_hash(&header, &entries)? != expected {
+        store.put_index(
+            META_INDEX,
+            CHAINSTATE_INVALID_KEY.as_bytes(),
+            base.as_bytes(),
+        )?;
+        store.flush()?;
+        return Ok(Some(false));
+    }
+
+    store.remove_index(META_INDEX, SNAPSHOT_BASE_KEY.as_bytes())?;
+    store.remove_index(META_INDEX, SNAPSHOT_HASH_KEY.as_bytes())?;
+    // 历史完整之后才能建立交易索引
+    utxo_set::rebuild_tx_index(blockchain)?;
+
+    Ok(Some(true))
+}
+
+/// Runs `validate_snapshot` on its own thread, at most one at a time.
+/// `on_invalid` is called if the snapshot turns out to be wrong.
+pub fn validate_in_background(blockchain: &Blockchain, on_invalid: fn()) -> Result<()> {
+    if pending_snapshot(blockchain)?.is_none() || VALIDATION_RUNNING.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let blockchain = blockchain.clone();
+    thread::spawn(move || {
+        match validate_snapshot(&blockchain) {
+            Ok(Some(true)) => println!("UTXO snapshot validated against the full history"),
+            Ok(Some(false)) => {
+                eprintln!("UTXO snapshot does NOT match the downloaded history!");
+                if let Err(e) = check_chainstate(&blockchain) {
+                    eprintln!("{}", e);
+                }
+                on_invalid();
+            }
+            Ok(None) => println!("UTXO snapshot history is still incomplete"),
+            Err(e) => eprintln!("Error validating UTXO snapshot: {}", e),
+        }
+        VALIDATION_RUNNING.store(false, Ordering::SeqCst);
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        signature::SignatureScheme, store::MemoryStore, transaction::Transaction, wallets::Wallet,
+    };
+
+    fn temp_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(uuid::Uuid::new_v4().to_string())
+    }
+
+    #[test]
+    fn content_hash_pins_the_base_block() {
+        let address = Wallet::try_new(SignatureScheme::P256)
+            .unwrap()
+            .get_address();
+        let blockchain =
+            Blockchain::create_with_store(Arc::new(MemoryStore::new()), &address).unwrap();
+        let genesis_hash = blockchain.get_tip_hash();
+        blockchain
+            .mine_block(&[Transaction::new_coinbase_tx(&address).unwrap()])
+            .unwrap();
+
+        let path = temp_path();
+        let info = dump_utxo(&blockchain, &path).unwrap();
+        load_utxo(Arc::new(MemoryStore::new()), &path, &info.content_hash).unwrap();
+
+        // 同样的输出挂到另一个块上，哈希随之改变
+        let bytes = std::fs::read(&path).unwrap();
+        let mut snapshot: UtxoSnapshot = bincode::deserialize(&bytes[6..]).unwrap();
+        snapshot.best_block = blockchain
+            .get_header(genesis_hash.as_bytes())
+            .unwrap()
+            .unwrap();
+        snapshot.content_hash = content_hash(&snapshot.best_block, &snapshot.entries).unwrap();
+        let mut tampered = bytes[..6].to_vec();
+        tampered.extend(bincode::serialize(&snapshot).unwrap());
+        std::fs::write(&path, tampered).unwrap();
+
+        assert!(load_utxo(Arc::new(MemoryStore::new()), &path, &info.content_hash).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
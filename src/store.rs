@@ -0,0 +1,495 @@
+use anyhow::Result;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::RwLock,
+};
+
+use sled::{Db, Transactional as _, Tree, transaction::ConflictableTransactionError};
+
+use crate::{
+    block::{Block, BlockHeader},
+    transaction::TXOutput,
+};
+
+const TIP_BLOCK_HASH_KEY: &str = "tip_block_hash";
+const BLOCKS_TREE: &str = "blocks";
+const HEADERS_TREE: &str = "headers";
+const UTXO_TREE: &str = "chainstate";
+
+/// Node-wide flags and markers.
+pub const META_INDEX: &str = "meta";
+/// Outputs spent by each block, keyed by block hash, so it can be disconnected.
+pub const UNDO_INDEX: &str = "undo";
+/// Address history of schema 3 and 4. Wallets keep their own history now,
+/// see `history`, and the schema 5 migration clears this index.
+pub const HISTORY_INDEX: &str = "history";
+/// Height of the block each transaction was mined in, keyed by raw txid.
+pub const TX_INDEX: &str = "tx";
+
+/// Unspent outputs of one transaction, keyed by their index in `vout`.
+pub type UTXOs = Vec<(usize, TXOutput)>;
+
+/// Persistence used by `Blockchain` and `UTXOSet`.
+///
+/// Blocks and the tip live together so a tip update can be atomic with the
+/// block it points to. Anything else (lookup tables, metadata) goes through
+/// the named byte indexes.
+pub trait ChainStore: Send + Sync {
+    fn get_tip_hash(&self) -> Result<Option<String>>;
+
+    fn set_tip_hash(&self, block_hash: &str) -> Result<()>;
+
+    fn get_block(&self, block_hash: &[u8]) -> Result<Option<Block>>;
+
+    /// Header of a known block, still available after its body was pruned.
+    fn get_header(&self, block_hash: &[u8]) -> Result<Option<BlockHeader>>;
+
+    /// True if the block is known, whether or not its body was pruned.
+    fn contains_block(&self, block_hash: &[u8]) -> Result<bool>;
+
+    /// Stores `block`, moving the tip to it in the same write when `set_tip` is true.
+    fn put_block(&self, block: &Block, set_tip: bool) -> Result<()>;
+
+    /// Drops the body of a stored block, keeping its header.
+    fn prune_block(&self, block_hash: &[u8]) -> Result<()>;
+
+    /// Stores a header whose body is not available, e.g. the base of a UTXO snapshot.
+    fn put_header(&self, header: &BlockHeader) -> Result<()>;
+
+    fn get_utxo(&self, txid: &[u8]) -> Result<Option<UTXOs>>;
+
+    fn put_utxo(&self, txid: &[u8], outs: &UTXOs) -> Result<()>;
+
+    fn remove_utxo(&self, txid: &[u8]) -> Result<()>;
+
+    fn clear_utxo(&self) -> Result<()>;
+
+    fn utxo_entries(&self) -> Result<Vec<(Vec<u8>, UTXOs)>>;
+
+    fn count_utxo(&self) -> Result<usize>;
+
+    fn get_index(&self, index: &str, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn put_index(&self, index: &str, key: &[u8], value: &[u8]) -> Result<()>;
+
+    fn remove_index(&self, index: &str, key: &[u8]) -> Result<()>;
+
+    /// Returns every entry of `index` whose key starts with `prefix`, in key order.
+    fn index_entries(&self, index: &str, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    fn clear_index(&self, index: &str) -> Result<()>;
+
+    fn flush(&self) -> Result<()>;
+}
+
+pub struct SledStore {
+    db: Db,
+    blocks: Tree,
+    headers: Tree,
+    utxo: Tree,
+}
+
+impl SledStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = sled::open(path)?;
+        Self::from_db(db)
+    }
+
+    pub fn from_db(db: Db) -> Result<Self> {
+        let blocks = db.open_tree(BLOCKS_TREE)?;
+        let headers = db.open_tree(HEADERS_TREE)?;
+        let utxo = db.open_tree(UTXO_TREE)?;
+
+        Ok(SledStore {
+            db,
+            blocks,
+            headers,
+            utxo,
+        })
+    }
+
+    fn index_tree(&self, index: &str) -> Result<Tree> {
+        Ok(self.db.open_tree(format!("index_{}", index))?)
+    }
+}
+
+impl ChainStore for SledStore {
+    fn get_tip_hash(&self) -> Result<Option<String>> {
+        match self.blocks.get(TIP_BLOCK_HASH_KEY)? {
+            Some(value) => Ok(Some(String::from_utf8(value.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_tip_hash(&self, block_hash: &str) -> Result<()> {
+        self.blocks.insert(TIP_BLOCK_HASH_KEY, block_hash)?;
+        Ok(())
+    }
+
+    fn get_block(&self, block_hash: &[u8]) -> Result<Option<Block>> {
+        match self.blocks.get(block_hash)? {
+            Some(bytes) => Ok(Some(Block::deserialize(bytes.as_ref())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_header(&self, block_hash: &[u8]) -> Result<Option<BlockHeader>> {
+        if let Some(bytes) = self.headers.get(block_hash)? {
+            return Ok(Some(BlockHeader::deserialize(bytes.as_ref())?));
+        }
+
+        // 旧数据库没有 headers 树，从区块本身取
+        Ok(self.get_block(block_hash)?.map(|block| block.get_header()))
+    }
+
+    fn contains_block(&self, block_hash: &[u8]) -> Result<bool> {
+        Ok(self.headers.contains_key(block_hash)? || self.blocks.contains_key(block_hash)?)
+    }
+
+    fn put_block(&self, block: &Block, set_tip: bool) -> Result<()> {
+        let block_hash = block.get_hash();
+        let block_bytes = block.serialize()?;
+        let header_bytes = block.get_header().serialize()?;
+
+        (&self.blocks, &self.headers)
+            .transaction(|(blocks, headers)| {
+                blocks.insert(block_hash, block_bytes.as_slice())?;
+                headers.insert(block_hash, header_bytes.as_slice())?;
+                if set_tip {
+                    blocks.insert(TIP_BLOCK_HASH_KEY, block_hash)?;
+                }
+                Ok::<_, ConflictableTransactionError<()>>(())
+            })
+            .map_err(|e| anyhow::anyhow!("Transaction error: {:?}", e))?;
+
+        Ok(())
+    }
+
+    fn prune_block(&self, block_hash: &[u8]) -> Result<()> {
+        if let Some(block) = self.get_block(block_hash)? {
+            self.headers
+                .insert(block_hash, block.get_header().serialize()?)?;
+            self.blocks.remove(block_hash)?;
+        }
+        Ok(())
+    }
+
+    fn put_header(&self, header: &BlockHeader) -> Result<()> {
+        self.headers
+            .insert(header.get_hash(), header.serialize()?)?;
+        Ok(())
+    }
+
+    fn get_utxo(&self, txid: &[u8]) -> Result<Option<UTXOs>> {
+        match self.utxo.get(txid)? {
+            Some(bytes) => Ok(Some(bincode::deserialize(bytes.as_ref())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_utxo(&self, txid: &[u8], outs: &UTXOs) -> Result<()> {
+        let bytes = bincode::serialize(outs)?;
+        self.utxo.insert(txid, bytes)?;
+        Ok(())
+    }
+
+    fn remove_utxo(&self, txid: &[u8]) -> Result<()> {
+        self.utxo.remove(txid)?;
+        Ok(())
+    }
+
+    fn clear_utxo(&self) -> Result<()> {
+        self.utxo.clear()?;
+        Ok(())
+    }
+
+    fn utxo_entries(&self) -> Result<Vec<(Vec<u8>, UTXOs)>> {
+        let mut entries = Vec::new();
+        for item in self.utxo.iter() {
+            let (k, v) = item?;
+            entries.push((k.to_vec(), bincode::deserialize(v.as_ref())?));
+        }
+
+        Ok(entries)
+    }
+
+    fn count_utxo(&self) -> Result<usize> {
+        Ok(self.utxo.len())
+    }
+
+    fn get_index(&self, index: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.index_tree(index)?.get(key)?.map(|v| v.to_vec()))
+    }
+
+    fn put_index(&self, index: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        self.index_tree(index)?.insert(key, value)?;
+        Ok(())
+    }
+
+    fn remove_index(&self, index: &str, key: &[u8]) -> Result<()> {
+        self.index_tree(index)?.remove(key)?;
+        Ok(())
+    }
+
+    fn index_entries(&self, index: &str, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entries = Vec::new();
+        for item in self.index_tree(index)?.scan_prefix(prefix) {
+            let (k, v) = item?;
+            entries.push((k.to_vec(), v.to_vec()));
+        }
+
+        Ok(entries)
+    }
+
+    fn clear_index(&self, index: &str) -> Result<()> {
+        self.index_tree(index)?.clear()?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct MemoryInner {
+    tip_hash: Option<String>,
+    blocks: HashMap<Vec<u8>, Block>,
+    headers: HashMap<Vec<u8>, BlockHeader>,
+    utxo: BTreeMap<Vec<u8>, UTXOs>,
+    indexes: HashMap<String, BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+/// A store that never touches disk, for tests and throwaway nodes.
+#[derive(Default)]
+pub struct MemoryStore {
+    inner: RwLock<MemoryInner>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read(&self) -> Result<std::sync::RwLockReadGuard<'_, MemoryInner>> {
+        self.inner
+            .read()
+            .map_err(|e| anyhow::anyhow!("failed to read from MemoryStore: {:?}", e))
+    }
+
+    fn write(&self) -> Result<std::sync::RwLockWriteGuard<'_, MemoryInner>> {
+        self.inner
+            .write()
+            .map_err(|e| anyhow::anyhow!("failed to write to MemoryStore: {:?}", e))
+    }
+}
+
+impl ChainStore for MemoryStore {
+    fn get_tip_hash(&self) -> Result<Option<String>> {
+        Ok(self.read()?.tip_hash.clone())
+    }
+
+    fn set_tip_hash(&self, block_hash: &str) -> Result<()> {
+        self.write()?.tip_hash = Some(String::from(block_hash));
+        Ok(())
+    }
+
+    fn get_block(&self, block_hash: &[u8]) -> Result<Option<Block>> {
+        Ok(self.read()?.blocks.get(block_hash).cloned())
+    }
+
+    fn get_header(&self, block_hash: &[u8]) -> Result<Option<BlockHeader>> {
+        Ok(self.read()?.headers.get(block_hash).cloned())
+    }
+
+    fn contains_block(&self, block_hash: &[u8]) -> Result<bool> {
+        Ok(self.read()?.headers.contains_key(block_hash))
+    }
+
+    fn put_block(&self, block: &Block, set_tip: bool) -> Result<()> {
+        let mut inner = self.write()?;
+        inner.blocks.insert(block.get_hash_bytes(), block.clone());
+        inner
+            .headers
+            .insert(block.get_hash_bytes(), block.get_header());
+        if set_tip {
+            inner.tip_hash = Some(String::from(block.get_hash()));
+        }
+        Ok(())
+    }
+
+    fn prune_block(&self, block_hash: &[u8]) -> Result<()> {
+        self.write()?.blocks.remove(block_hash);
+        Ok(())
+    }
+
+    fn put_header(&self, header: &BlockHeader) -> Result<()> {
+        self.write()?
+            .headers
+            .insert(header.get_hash().as_bytes().to_vec(), header.clone());
+        Ok(())
+    }
+
+    fn get_utxo(&self, txid: &[u8]) -> Result<Option<UTXOs>> {
+        Ok(self.read()?.utxo.get(txid).cloned())
+    }
+
+    fn put_utxo(&self, txid: &[u8], outs: &UTXOs) -> Result<()> {
+        self.write()?.utxo.insert(txid.to_vec(), outs.clone());
+        Ok(())
+    }
+
+    fn remove_utxo(&self, txid: &[u8]) -> Result<()> {
+        self.write()?.utxo.remove(txid);
+        Ok(())
+    }
+
+    fn clear_utxo(&self) -> Result<()> {
+        self.write()?.utxo.clear();
+        Ok(())
+    }
+
+    fn utxo_entries(&self) -> Result<Vec<(Vec<u8>, UTXOs)>> {
+        Ok(self
+            .read()?
+            .utxo
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+
+    fn count_utxo(&self) -> Result<usize> {
+        Ok(self.read()?.utxo.len())
+    }
+
+    fn get_index(&self, index: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .read()?
+            .indexes
+            .get(index)
+            .and_then(|tree| tree.get(key).cloned()))
+    }
+
+    fn put_index(&self, index: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        self.write()?
+            .indexes
+            .entry(String::from(index))
+            .or_default()
+            .insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove_index(&self, index: &str, key: &[u8]) -> Result<()> {
+        if let Some(tree) = self.write()?.indexes.get_mut(index) {
+            tree.remove(key);
+        }
+        Ok(())
+    }
+
+    fn index_entries(&self, index: &str, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let inner = self.read()?;
+        let entries = match inner.indexes.get(index) {
+            Some(tree) => tree
+                .range(prefix.to_vec()..)
+                .take_while(|(k, _)| k.starts_with(prefix))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            None => Vec::new(),
+        };
+
+        Ok(entries)
+    }
+
+    fn clear_index(&self, index: &str) -> Result<()> {
+        self.write()?.indexes.remove(index);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{signature::SignatureScheme, transaction::Transaction, wallets::Wallet};
+
+    // TXOutput 没有实现 PartialEq，按序列化结果比较
+    fn bytes<T: serde::Serialize>(value: &T) -> Vec<u8> {
+        bincode::serialize(value).unwrap()
+    }
+
+    fn genesis() -> Block {
+        let address = Wallet::try_new(SignatureScheme::P256)
+            .unwrap()
+            .get_address();
+        Block::generate_genesis_block(&Transaction::new_coinbase_tx(&address).unwrap())
+    }
+
+    // 两种实现必须表现一致
+    fn check_store(store: &dyn ChainStore) {
+        let block = genesis();
+        assert_eq!(store.get_tip_hash().unwrap(), None);
+        store.put_block(&block, true).unwrap();
+        assert_eq!(
+            store.get_tip_hash().unwrap().as_deref(),
+            Some(block.get_hash())
+        );
+        assert!(store.contains_block(&block.get_hash_bytes()).unwrap());
+        let stored = store.get_block(&block.get_hash_bytes()).unwrap().unwrap();
+        assert_eq!(stored.get_hash(), block.get_hash());
+
+        let outs: UTXOs = block.get_transactions()[0]
+            .get_vout()
+            .iter()
+            .cloned()
+            .enumerate()
+            .collect();
+        store.put_utxo(b"b", &outs).unwrap();
+        store.put_utxo(b"a", &outs).unwrap();
+        assert_eq!(bytes(&store.get_utxo(b"a").unwrap()), bytes(&Some(&outs)));
+        assert_eq!(store.count_utxo().unwrap(), 2);
+        store.remove_utxo(b"b").unwrap();
+        assert_eq!(
+            bytes(&store.utxo_entries().unwrap()),
+            bytes(&vec![(b"a".to_vec(), outs)])
+        );
+        store.clear_utxo().unwrap();
+        assert_eq!(store.count_utxo().unwrap(), 0);
+
+        store.put_index("test", b"ab2", b"2").unwrap();
+        store.put_index("test", b"ab1", b"1").unwrap();
+        store.put_index("test", b"ac", b"3").unwrap();
+        store.put_index("other", b"ab3", b"4").unwrap();
+        assert_eq!(
+            store.index_entries("test", b"ab").unwrap(),
+            vec![
+                (b"ab1".to_vec(), b"1".to_vec()),
+                (b"ab2".to_vec(), b"2".to_vec())
+            ]
+        );
+        store.remove_index("test", b"ab1").unwrap();
+        assert_eq!(store.get_index("test", b"ab1").unwrap(), None);
+        store.clear_index("test").unwrap();
+        assert!(store.index_entries("test", b"").unwrap().is_empty());
+        assert_eq!(
+            store.get_index("other", b"ab3").unwrap(),
+            Some(b"4".to_vec())
+        );
+        store.flush().unwrap();
+    }
+
+    #[test]
+    fn memory_store() {
+        check_store(&MemoryStore::new());
+    }
+
+    #[test]
+    fn sled_store() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        check_store(&SledStore::from_db(db).unwrap());
+    }
+}
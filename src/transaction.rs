@@ -4,12 +4,16 @@ use uuid::Uuid;
 
 use crate::{
     blockchain::Blockchain,
+    coin_selection::CoinControl,
+    signature,
+    tx_builder::{self, Recipient},
     utils,
     utxo_set::UTXOSet,
     wallets::{self, Wallets},
 };
 
-const SUBSIDY: i32 = 10;
+/// Value of a block's coinbase output.
+pub const SUBSIDY: i32 = 10;
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct TXInput {
@@ -41,32 +45,35 @@ impl TXInput {
         self.pub_key.as_slice()
     }
 
+    pub fn get_signature(&self) -> &[u8] {
+        self.signature.as_slice()
+    }
+
     pub fn use_key(&self, pub_key: &[u8]) -> bool {
         let locking_hash = wallets::hash_pub_key(self.pub_key.as_slice());
         locking_hash == pub_key.to_vec()
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct TXOutput {
     value: i32,
     pub_key_hash: Vec<u8>,
 }
 
 impl TXOutput {
-    pub fn new(value: i32, address: &str) -> Self {
+    pub fn new(value: i32, address: &str) -> Result<Self> {
         let mut output = TXOutput {
             value,
             pub_key_hash: vec![],
         };
-        output.lock(address);
-        output
+        output.lock(address)?;
+        Ok(output)
     }
 
-    fn lock(&mut self, address: &str) {
-        let payload = utils::base58_decode(address);
-        let pub_key_hash = payload[1..payload.len() - wallets::ADDRESS_CHECK_SUM_LEN].to_vec();
-        self.pub_key_hash = pub_key_hash;
+    fn lock(&mut self, address: &str) -> Result<()> {
+        self.pub_key_hash = wallets::get_pub_key_hash(address)?;
+        Ok(())
     }
 
     pub fn get_value(&self) -> i32 {
@@ -91,9 +98,11 @@ pub struct Transaction {
 
 impl Transaction {
     pub fn new_coinbase_tx(to: &str) -> Result<Self> {
-        let txout = TXOutput::new(SUBSIDY, to);
-        let mut tx_input = TXInput::default();
-        tx_input.signature = Uuid::new_v4().as_bytes().to_vec();
+        let txout = TXOutput::new(SUBSIDY, to)?;
+        let tx_input = TXInput {
+            signature: Uuid::new_v4().as_bytes().to_vec(),
+            ..Default::default()
+        };
 
         let mut tx = Transaction {
             id: vec![],
@@ -105,6 +114,18 @@ impl Transaction {
         Ok(tx)
     }
 
+    /// True if `id` is the hash of the transaction's contents. The id is
+    /// taken before signing, so signatures are left out of the check.
+    pub fn has_valid_id(&self) -> Result<bool> {
+        let mut tx_copy = self.clone();
+        if !self.is_coinbase() {
+            for vin in tx_copy.vin.iter_mut() {
+                vin.signature = vec![];
+            }
+        }
+        Ok(tx_copy.hash()? == self.id)
+    }
+
     fn hash(&mut self) -> Result<Vec<u8>> {
         let tx_copy = Transaction {
             id: vec![],
@@ -115,57 +136,115 @@ impl Transaction {
         Ok(utils::sha256_digest(tx_copy.serialize()?.as_slice()))
     }
 
+    /// Builds and signs a transaction paying every recipient from `from`,
+    /// with a single change output to a fresh change address of the wallet.
     pub fn new_utxo_transaction(
         from: &str,
-        to: &str,
-        amount: i32,
+        recipients: &[Recipient],
+        wallets: &mut Wallets,
         utxo_set: &UTXOSet,
+        coin_control: &CoinControl,
     ) -> Result<Self> {
-        let all_wallets = Wallets::try_new()?;
-        let wallet = all_wallets
+        if wallets.is_watch_only(from) {
+            return Err(anyhow::anyhow!(
+                "ERROR: {} is watch-only and cannot be spent from",
+                from
+            ));
+        }
+        let public_key = wallets
             .get_wallet(from)
-            .ok_or(anyhow::anyhow!("ERROR: Wallet not found"))?;
-        let public_key_hash = wallets::hash_pub_key(wallet.get_public_key());
-
-        let (accumulated, valid_outputs) =
-            utxo_set.find_spendable_outputs(public_key_hash.as_slice(), amount)?;
+            .ok_or(anyhow::anyhow!("ERROR: Wallet not found"))?
+            .get_public_key()
+            .to_vec();
+        let pkcs8 = wallets.get_private_key(from)?;
+
+        // 没有种子的旧钱包只能找零回发送方
+        let change_address = wallets
+            .next_change_address()?
+            .unwrap_or_else(|| from.to_string());
+        let (mut tx, prev_outputs) = Transaction::new_unsigned(
+            from,
+            recipients,
+            &change_address,
+            &public_key,
+            utxo_set,
+            coin_control,
+        )?;
+        tx.update_id()?;
+
+        for (idx, prev_out) in prev_outputs.iter().enumerate() {
+            tx.sign_input(idx, prev_out, pkcs8.as_slice(), &public_key)?;
+        }
 
-        if accumulated < amount {
-            return Err(anyhow::anyhow!("ERROR: Not enough funds"));
+        if tx.vout.len() > recipients.len() && change_address != from {
+            wallets.add_change_address()?;
         }
 
-        let mut inputs = vec![];
+        Ok(tx)
+    }
 
-        for (txid_hex, outs) in valid_outputs {
-            let txid = data_encoding::HEXLOWER.decode(txid_hex.as_bytes())?;
-            for out in outs {
-                let input = TXInput {
-                    txid: txid.clone(),
-                    vout: out,
-                    signature: vec![],
-                    pub_key: wallet.get_public_key().to_vec(),
-                };
-                inputs.push(input);
-            }
+    /// Builds a transaction spending coins locked to `from`, chosen by
+    /// `coin_control`, without signing it, along with the output each input
+    /// spends. Change goes to `change_address`. Inputs carry `public_key`, which may be empty if only the
+    /// address is known; call `update_id` once every input has its public key.
+    pub fn new_unsigned(
+        from: &str,
+        recipients: &[Recipient],
+        change_address: &str,
+        public_key: &[u8],
+        utxo_set: &UTXOSet,
+        coin_control: &CoinControl,
+    ) -> Result<(Self, Vec<TXOutput>)> {
+        let public_key_hash = wallets::get_pub_key_hash(from)?;
+        let amount = tx_builder::total_amount(recipients)?;
+
+        let coins = utxo_set.find_spendable_coins(public_key_hash.as_slice())?;
+        let selected = coin_control.select(coins, amount)?;
+
+        let mut accumulated: i64 = 0;
+        let mut inputs = vec![];
+        let mut prev_outputs = vec![];
+
+        for coin in selected {
+            accumulated += coin.output.get_value() as i64;
+            inputs.push(TXInput {
+                txid: coin.txid,
+                vout: coin.vout,
+                signature: vec![],
+                pub_key: public_key.to_vec(),
+            });
+            prev_outputs.push(coin.output);
         }
 
-        let mut outputs = vec![TXOutput::new(amount, to)];
+        let mut outputs: Vec<TXOutput> = recipients
+            .iter()
+            .map(|recipient| TXOutput::new(recipient.amount, &recipient.address))
+            .collect::<Result<_>>()?;
 
-        if accumulated > amount {
-            outputs.push(TXOutput::new(accumulated - amount, from)) // to: 币收入
+        let change = accumulated - amount as i64;
+        if change > 0 {
+            let change = i32::try_from(change)
+                .map_err(|_| anyhow::anyhow!("Change of {} does not fit in one output", change))?;
+            outputs.push(TXOutput::new(change, change_address)?) // to: 币收入
         }
 
-        let mut tx = Transaction {
+        let tx = Transaction {
             id: vec![],
             vin: inputs,
             vout: outputs,
         };
 
-        tx.id = tx.hash()?;
-
-        tx.sign(utxo_set.get_blockchain(), wallet.get_pkcs8())?;
+        Ok((tx, prev_outputs))
+    }
 
-        Ok(tx)
+    /// Sets `id` from the current contents. Signatures are not part of it.
+    pub fn update_id(&mut self) -> Result<()> {
+        let mut tx_copy = self.clone();
+        for vin in tx_copy.vin.iter_mut() {
+            vin.signature = vec![];
+        }
+        self.id = tx_copy.hash()?;
+        Ok(())
     }
 
     fn trimmed_copy(&self) -> Self {
@@ -188,51 +267,82 @@ impl Transaction {
         }
     }
 
-    fn sign(&mut self, blockchain: &Blockchain, pkcs8: &[u8]) -> Result<()> {
+    /// The digest signed for input `idx`. It covers every input outpoint and
+    /// output, but not other inputs' keys or signatures, so inputs can be
+    /// signed separately.
+    fn signature_hash(&self, idx: usize, prev_out: &TXOutput) -> Result<Vec<u8>> {
         let mut tx_copy = self.trimmed_copy();
+        tx_copy.vin[idx].pub_key = prev_out.pub_key_hash.clone();
+        tx_copy.hash()
+    }
 
-        for (idx, vin) in self.vin.iter_mut().enumerate() {
-            let prev_tx_option = blockchain.find_transaction(vin.get_txid());
-            if prev_tx_option.is_none() {
-                return Err(anyhow::anyhow!(
-                    "ERROR: Previous transaction is not correct"
-                ));
-            }
-            let prev_tx = prev_tx_option.unwrap();
-            tx_copy.vin[idx].signature = vec![];
-            tx_copy.vin[idx].pub_key = prev_tx.vout[vin.vout].pub_key_hash.clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[idx].pub_key = vec![];
-
-            let signature = utils::ecdsa_p256_sha256_sign_digest(pkcs8, tx_copy.get_id());
-            vin.signature = signature;
+    /// Signs input `idx`, which spends `prev_out`, with the given key pair.
+    pub fn sign_input(
+        &mut self,
+        idx: usize,
+        prev_out: &TXOutput,
+        pkcs8: &[u8],
+        public_key: &[u8],
+    ) -> Result<()> {
+        if idx >= self.vin.len() {
+            return Err(anyhow::anyhow!("Input {} does not exist", idx));
         }
+        let digest = self.signature_hash(idx, prev_out)?;
+
+        self.vin[idx].pub_key = public_key.to_vec();
+        self.vin[idx].signature = signature::sign(pkcs8, public_key, digest.as_slice())?;
+
+        Ok(())
+    }
+
+    /// Attaches a signature made elsewhere, e.g. by another signer.
+    pub fn set_input_signature(
+        &mut self,
+        idx: usize,
+        public_key: &[u8],
+        signature: &[u8],
+    ) -> Result<()> {
+        let vin = self
+            .vin
+            .get_mut(idx)
+            .ok_or(anyhow::anyhow!("Input {} does not exist", idx))?;
+        vin.pub_key = public_key.to_vec();
+        vin.signature = signature.to_vec();
 
         Ok(())
     }
 
+    /// Checks that input `idx` carries the key `prev_out` is locked to and a
+    /// valid signature made with it.
+    pub fn verify_input(&self, idx: usize, prev_out: &TXOutput) -> Result<bool> {
+        let vin = self
+            .vin
+            .get(idx)
+            .ok_or(anyhow::anyhow!("Input {} does not exist", idx))?;
+        // 公钥必须是被花费输出锁定的那个
+        if !vin.use_key(prev_out.get_pub_key_hash()) {
+            return Ok(false);
+        }
+        let digest = self.signature_hash(idx, prev_out)?;
+
+        Ok(signature::verify(
+            vin.pub_key.as_slice(),
+            vin.signature.as_slice(),
+            digest.as_slice(),
+        ))
+    }
+
     pub fn verify(&self, blockchain: &Blockchain) -> Result<bool> {
         if self.is_coinbase() {
             return Ok(true);
         }
-        let mut tx_copy = self.trimmed_copy();
         for (idx, vin) in self.vin.iter().enumerate() {
-            let prev_tx_option = blockchain.find_transaction(vin.get_txid());
-            if prev_tx_option.is_none() {
-                panic!("ERROR: Previous transaction is not correct")
-            }
-            let prev_tx = prev_tx_option.unwrap();
-            tx_copy.vin[idx].signature = vec![];
-            tx_copy.vin[idx].pub_key = prev_tx.vout[vin.vout].pub_key_hash.clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[idx].pub_key = vec![];
-
-            let verify = utils::ecdsa_p256_sha256_sign_verify(
-                vin.pub_key.as_slice(),
-                vin.signature.as_slice(),
-                tx_copy.get_id(),
-            );
-            if !verify {
+            let prev_out = blockchain
+                .find_output(vin.get_txid(), vin.get_vout())?
+                .ok_or(anyhow::anyhow!(
+                    "ERROR: Previous transaction is not correct"
+                ))?;
+            if !self.verify_input(idx, &prev_out)? {
                 return Ok(false);
             }
         }
@@ -241,7 +351,7 @@ impl Transaction {
     }
 
     pub fn is_coinbase(&self) -> bool {
-        return self.vin.len() == 1 && self.vin[0].pub_key.len() == 0;
+        self.vin.len() == 1 && self.vin[0].pub_key.is_empty()
     }
 
     pub fn get_id(&self) -> &[u8] {
@@ -268,3 +378,70 @@ impl Transaction {
         Ok(bincode::deserialize(data)?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{block::Block, signature::SignatureScheme};
+
+    fn spend(prev_out: &TXOutput) -> Transaction {
+        Transaction {
+            id: vec![],
+            vin: vec![TXInput::new(&[1; 32], 0)],
+            vout: vec![prev_out.clone()],
+        }
+    }
+
+    #[test]
+    fn verify_input_accepts_owner_key() {
+        let (pkcs8, public_key) = SignatureScheme::P256.new_key_pair().unwrap();
+        let prev_out = TXOutput {
+            value: 5,
+            pub_key_hash: wallets::hash_pub_key(&public_key),
+        };
+        let mut tx = spend(&prev_out);
+        tx.sign_input(0, &prev_out, &pkcs8, &public_key).unwrap();
+
+        assert!(tx.verify_input(0, &prev_out).unwrap());
+    }
+
+    #[test]
+    fn verify_input_rejects_other_key() {
+        let (_, owner_key) = SignatureScheme::P256.new_key_pair().unwrap();
+        let (pkcs8, public_key) = SignatureScheme::P256.new_key_pair().unwrap();
+        let prev_out = TXOutput {
+            value: 5,
+            pub_key_hash: wallets::hash_pub_key(&owner_key),
+        };
+        // 签名本身有效，但公钥不是输出锁定的那个
+        let mut tx = spend(&prev_out);
+        tx.sign_input(0, &prev_out, &pkcs8, &public_key).unwrap();
+
+        assert!(!tx.verify_input(0, &prev_out).unwrap());
+    }
+
+    #[test]
+    fn check_coinbase_limits_count_and_value() {
+        let (_, public_key) = SignatureScheme::P256.new_key_pair().unwrap();
+        let address = wallets::convert_address(&wallets::hash_pub_key(&public_key));
+        let coinbase = Transaction::new_coinbase_tx(&address).unwrap();
+        let block = |txs: &[Transaction]| Block::new_block(String::from("parent"), txs, 1);
+
+        assert!(
+            block(std::slice::from_ref(&coinbase))
+                .check_coinbase()
+                .is_ok()
+        );
+
+        let second = Transaction::new_coinbase_tx(&address).unwrap();
+        assert!(block(&[coinbase.clone(), second]).check_coinbase().is_err());
+
+        let mut transfer = spend(&TXOutput::new(5, &address).unwrap());
+        transfer.vin[0].pub_key = public_key;
+        assert!(block(&[transfer]).check_coinbase().is_err());
+
+        let mut inflated = coinbase;
+        inflated.vout[0].value = SUBSIDY + 1;
+        assert!(block(&[inflated]).check_coinbase().is_err());
+    }
+}
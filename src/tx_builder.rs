@@ -0,0 +1,255 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path, str::FromStr};
+
+use crate::{
+    coin_selection::CoinControl,
+    psbt::PartiallySignedTransaction,
+    transaction::Transaction,
+    utxo_set::UTXOSet,
+    wallets::{self, Wallets},
+};
+
+/// One payment of a transaction.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Recipient {
+    pub address: String,
+    pub amount: i32,
+}
+
+impl Recipient {
+    pub fn new(address: &str, amount: i32) -> Self {
+        Recipient {
+            address: address.to_string(),
+            amount,
+        }
+    }
+}
+
+/// Parses `<address>:<amount>`.
+impl FromStr for Recipient {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (address, amount) = s
+            .rsplit_once(':')
+            .ok_or(anyhow::anyhow!("Recipient {} is not <address>:<amount>", s))?;
+        let amount = amount
+            .trim()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Recipient {} has an invalid amount", s))?;
+        Ok(Recipient::new(address.trim(), amount))
+    }
+}
+
+/// Reads recipients from a JSON array of `{"address", "amount"}` objects if
+/// the file ends in `.json`, otherwise from CSV lines of `address,amount`.
+pub fn load_recipients(path: &Path) -> Result<Vec<Recipient>> {
+    let content = fs::read_to_string(path)?;
+    if path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+    {
+        return Ok(serde_json::from_str(&content)?);
+    }
+
+    let mut recipients = Vec::new();
+    let mut first_row = true;
+    for (idx, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (address, amount) = line
+            .split_once(',')
+            .ok_or(anyhow::anyhow!("Line {}: expected address,amount", idx + 1))?;
+        let is_first_row = std::mem::replace(&mut first_row, false);
+        let amount = match amount.trim().parse() {
+            Ok(amount) => amount,
+            // 第一行可能是表头
+            Err(_) if is_first_row => continue,
+            Err(_) => return Err(anyhow::anyhow!("Line {}: invalid amount", idx + 1)),
+        };
+        recipients.push(Recipient::new(address.trim(), amount));
+    }
+
+    Ok(recipients)
+}
+
+/// Checks every recipient and returns the total they are paid.
+pub fn total_amount(recipients: &[Recipient]) -> Result<i32> {
+    if recipients.is_empty() {
+        return Err(anyhow::anyhow!("ERROR: No recipients"));
+    }
+
+    let mut total: i32 = 0;
+    for recipient in recipients {
+        if !wallets::validate_address(&recipient.address) {
+            return Err(anyhow::anyhow!(
+                "ERROR: Invalid recipient address {}",
+                recipient.address
+            ));
+        }
+        if recipient.amount <= 0 {
+            return Err(anyhow::anyhow!(
+                "ERROR: Amount for {} must be positive",
+                recipient.address
+            ));
+        }
+        total = total
+            .checked_add(recipient.amount)
+            .ok_or(anyhow::anyhow!("ERROR: Total amount is too large"))?;
+    }
+
+    Ok(total)
+}
+
+/// Builds one transaction paying any number of recipients from a single
+/// address, with one change output.
+#[derive(Clone, Debug)]
+pub struct TransactionBuilder {
+    from: String,
+    recipients: Vec<Recipient>,
+    coin_control: CoinControl,
+}
+
+impl TransactionBuilder {
+    pub fn new(from: &str) -> Self {
+        TransactionBuilder {
+            from: from.to_string(),
+            recipients: vec![],
+            coin_control: CoinControl::default(),
+        }
+    }
+
+    pub fn add_recipient(mut self, address: &str, amount: i32) -> Self {
+        self.recipients.push(Recipient::new(address, amount));
+        self
+    }
+
+    pub fn add_recipients(mut self, recipients: impl IntoIterator<Item = Recipient>) -> Self {
+        self.recipients.extend(recipients);
+        self
+    }
+
+    pub fn coin_control(mut self, coin_control: CoinControl) -> Self {
+        self.coin_control = coin_control;
+        self
+    }
+
+    pub fn get_recipients(&self) -> &[Recipient] {
+        self.recipients.as_slice()
+    }
+
+    /// Selects coins and signs with the key of the sender.
+    pub fn build(&self, wallets: &mut Wallets, utxo_set: &UTXOSet) -> Result<Transaction> {
+        Transaction::new_utxo_transaction(
+            &self.from,
+            &self.recipients,
+            wallets,
+            utxo_set,
+            &self.coin_control,
+        )
+    }
+
+    /// Selects coins but leaves signing to `PartiallySignedTransaction::sign`.
+    pub fn build_psbt(
+        &self,
+        wallets: &mut Wallets,
+        utxo_set: &UTXOSet,
+    ) -> Result<PartiallySignedTransaction> {
+        PartiallySignedTransaction::create(
+            &self.from,
+            &self.recipients,
+            wallets,
+            utxo_set,
+            &self.coin_control,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{blockchain::Blockchain, signature::SignatureScheme, store::MemoryStore};
+    use std::sync::Arc;
+
+    fn temp_dir() -> std::path::PathBuf {
+        std::env::temp_dir().join(uuid::Uuid::new_v4().to_string())
+    }
+
+    #[test]
+    fn parse_recipients() {
+        let recipient: Recipient = "addr:12".parse().unwrap();
+        assert_eq!(recipient, Recipient::new("addr", 12));
+        assert!("addr".parse::<Recipient>().is_err());
+        assert!("addr:many".parse::<Recipient>().is_err());
+
+        let dir = temp_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let csv = dir.join("payouts.csv");
+        fs::write(&csv, "address,amount\n# 注释\na, 1\n\nb,2\n").unwrap();
+        assert_eq!(
+            load_recipients(&csv).unwrap(),
+            vec![Recipient::new("a", 1), Recipient::new("b", 2)]
+        );
+        // 只有第一行可以是表头
+        fs::write(&csv, "a,1\nb,two\n").unwrap();
+        assert!(load_recipients(&csv).is_err());
+
+        let json = dir.join("payouts.json");
+        fs::write(&json, r#"[{"address": "a", "amount": 1}]"#).unwrap();
+        assert_eq!(
+            load_recipients(&json).unwrap(),
+            vec![Recipient::new("a", 1)]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn total_amount_checks_recipients() {
+        let address = wallets::convert_address(&[7; 20]);
+
+        assert_eq!(
+            total_amount(&[Recipient::new(&address, 2), Recipient::new(&address, 3)]).unwrap(),
+            5
+        );
+        assert!(total_amount(&[]).is_err());
+        assert!(total_amount(&[Recipient::new(&address, 0)]).is_err());
+        let mut mangled = address.clone();
+        let last = mangled.pop().unwrap();
+        mangled.push(if last == '1' { '2' } else { '1' });
+        assert!(total_amount(&[Recipient::new(&mangled, 1)]).is_err());
+        assert!(
+            total_amount(&[
+                Recipient::new(&address, i32::MAX),
+                Recipient::new(&address, 1)
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn build_pays_every_recipient() {
+        let data_dir = temp_dir();
+        let mut wallets = Wallets::try_new(&data_dir).unwrap();
+        let alice = wallets.create_wallet(SignatureScheme::P256).unwrap();
+        let bob = wallets.create_wallet(SignatureScheme::P256).unwrap();
+        let carol = wallets.create_wallet(SignatureScheme::P256).unwrap();
+        let blockchain =
+            Blockchain::create_with_store(Arc::new(MemoryStore::new()), &alice).unwrap();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex().unwrap();
+
+        let tx = TransactionBuilder::new(&alice)
+            .add_recipient(&bob, 3)
+            .add_recipient(&carol, 4)
+            .build(&mut wallets, &utxo_set)
+            .unwrap();
+        assert!(tx.verify(&blockchain).unwrap());
+        let values: Vec<i32> = tx.get_vout().iter().map(|out| out.get_value()).collect();
+        // 最后一个输出是找零
+        assert_eq!(values, vec![3, 4, 3]);
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+}
@@ -1,16 +1,18 @@
 use anyhow::Result;
 use crypto::digest::Digest as _;
 use std::{
+    fs::{self, File},
+    io::Write as _,
     iter,
+    path::Path,
     time::{SystemTime, UNIX_EPOCH},
 };
 
 use ring::{
+    aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey},
     digest::{Context, SHA256},
-    rand,
-    signature::{
-        ECDSA_P256_SHA256_FIXED, ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair as _,
-    },
+    pbkdf2,
+    rand::{self, SecureRandom as _},
 };
 
 pub fn current_timestamp() -> i64 {
@@ -20,6 +22,30 @@ pub fn current_timestamp() -> i64 {
         .as_millis() as i64
 }
 
+/// Replaces the file at `path` with `data`. A temporary file is written and
+/// synced first, so a crash leaves either the old or the new contents.
+pub fn write_file_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let dir = path.parent().ok_or(anyhow::anyhow!(
+        "{} has no parent directory",
+        path.display()
+    ))?;
+    let file_name = path
+        .file_name()
+        .ok_or(anyhow::anyhow!("{} is not a file path", path.display()))?;
+    let mut tmp_name = file_name.to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = dir.join(tmp_name);
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+
+    Ok(())
+}
+
 pub fn sha256_digest(data: &[u8]) -> Vec<u8> {
     let mut context = Context::new(&SHA256);
     context.update(data);
@@ -30,43 +56,75 @@ pub fn sha256_digest(data: &[u8]) -> Vec<u8> {
 pub fn ripemd160_digest(data: &[u8]) -> Vec<u8> {
     let mut ripemd160 = crypto::ripemd160::Ripemd160::new();
     ripemd160.input(data);
-    let mut buf: Vec<u8> = iter::repeat(0).take(ripemd160.output_bytes()).collect();
+    let mut buf: Vec<u8> = iter::repeat_n(0, ripemd160.output_bytes()).collect();
     ripemd160.result(&mut buf);
-    return buf;
+    buf
 }
 
 pub fn base58_encode(data: &[u8]) -> String {
     bs58::encode(data).into_string()
 }
 
-pub fn base58_decode(data: &str) -> Vec<u8> {
-    bs58::decode(data).into_vec().unwrap()
+pub fn base58_decode(data: &str) -> Result<Vec<u8>> {
+    bs58::decode(data)
+        .into_vec()
+        .map_err(|_| anyhow::anyhow!("{} is not valid base58", data))
 }
 
-pub fn ecdsa_p256_sha256_sign_digest(pkcs8: &[u8], message: &[u8]) -> Vec<u8> {
-    let rng = rand::SystemRandom::new();
-    let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8, &rng).unwrap();
-    let rng = ring::rand::SystemRandom::new();
-    key_pair.sign(&rng, message).unwrap().as_ref().to_vec()
+pub fn random_bytes(len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    rand::SystemRandom::new()
+        .fill(&mut buf)
+        .map_err(|_| anyhow::anyhow!("Ring error: failed to generate random bytes"))?;
+    Ok(buf)
 }
 
-pub fn ecdsa_p256_sha256_sign_verify(public_key: &[u8], signature: &[u8], message: &[u8]) -> bool {
-    let peer_public_key =
-        ring::signature::UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, public_key);
-    let result = peer_public_key.verify(message, signature.as_ref());
-    result.is_ok()
+/// Derives a 256-bit key from a passphrase with PBKDF2-HMAC-SHA256.
+pub fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> Result<Vec<u8>> {
+    let iterations = std::num::NonZeroU32::new(iterations)
+        .ok_or(anyhow::anyhow!("PBKDF2 iterations must not be zero"))?;
+    let mut key = vec![0u8; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    Ok(key)
 }
 
-pub fn new_key_pair() -> Result<(Vec<u8>, Vec<u8>), String> {
-    let rng = rand::SystemRandom::new();
+/// AES-256-GCM with a random nonce, returned as nonce + ciphertext + tag.
+pub fn aes_encrypt(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let unbound_key = UnboundKey::new(&AES_256_GCM, key)
+        .map_err(|_| anyhow::anyhow!("Ring error: invalid encryption key"))?;
+    let key = LessSafeKey::new(unbound_key);
 
-    let pkcs8_doc = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
-        .map_err(|_| "Ring error: failed to generate pkcs8".to_string())?;
+    let nonce_bytes = random_bytes(NONCE_LEN)?;
+    let nonce = Nonce::try_assume_unique_for_key(&nonce_bytes)
+        .map_err(|_| anyhow::anyhow!("Ring error: invalid nonce"))?;
+    let mut in_out = plaintext.to_vec();
+    key.seal_in_place_append_tag(nonce, Aad::empty(), &mut in_out)
+        .map_err(|_| anyhow::anyhow!("Ring error: failed to encrypt"))?;
 
-    let pkcs8_bytes = pkcs8_doc.as_ref().to_vec();
+    let mut sealed = nonce_bytes;
+    sealed.extend_from_slice(in_out.as_slice());
+    Ok(sealed)
+}
 
-    let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8_bytes, &rng)
-        .map_err(|_| "Ring error: failed to derive key pair".to_string())?;
+pub fn aes_decrypt(key: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        return Err(anyhow::anyhow!("Ciphertext is too short"));
+    }
+    let unbound_key = UnboundKey::new(&AES_256_GCM, key)
+        .map_err(|_| anyhow::anyhow!("Ring error: invalid encryption key"))?;
+    let key = LessSafeKey::new(unbound_key);
 
-    Ok((pkcs8_bytes, key_pair.public_key().as_ref().to_vec()))
+    let nonce = Nonce::try_assume_unique_for_key(&sealed[..NONCE_LEN])
+        .map_err(|_| anyhow::anyhow!("Ring error: invalid nonce"))?;
+    let mut in_out = sealed[NONCE_LEN..].to_vec();
+    let plaintext = key
+        .open_in_place(nonce, Aad::empty(), &mut in_out)
+        .map_err(|_| anyhow::anyhow!("Decryption failed"))?;
+    Ok(plaintext.to_vec())
 }
@@ -1,9 +1,53 @@
 use anyhow::Result;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 
-use crate::{block::Block, blockchain::Blockchain, transaction::TXOutput};
+use crate::{
+    block::Block,
+    blockchain::Blockchain,
+    coin_selection::Coin,
+    history::SpentOutputs,
+    store::{ChainStore, META_INDEX, TX_INDEX, UNDO_INDEX, UTXOs},
+    transaction::TXOutput,
+};
 
-const UTXO_TREE: &str = "chainstate";
+const BEST_BLOCK_KEY: &str = "utxo_best_block";
+
+/// An output spent by a block, kept so the block can be disconnected later.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct SpentOutput {
+    txid: Vec<u8>,
+    vout: usize,
+    output: TXOutput,
+}
+
+fn spent_outputs(spent: &[SpentOutput]) -> SpentOutputs {
+    spent
+        .iter()
+        .map(|spent| ((spent.txid.clone(), spent.vout), spent.output.clone()))
+        .collect()
+}
+
+fn put_tx_height(store: &dyn ChainStore, txid: &[u8], height: usize) -> Result<()> {
+    store.put_index(TX_INDEX, txid, &bincode::serialize(&(height as u64))?)
+}
+
+/// Rebuilds the transaction height index from the stored blocks. Transactions
+/// of pruned blocks are left out.
+pub fn rebuild_tx_index(blockchain: &Blockchain) -> Result<()> {
+    let store = blockchain.get_store();
+    store.clear_index(TX_INDEX)?;
+
+    for hash in blockchain.get_block_hashes()? {
+        if let Some(block) = store.get_block(&hash)? {
+            for tx in block.get_transactions() {
+                put_tx_height(store, tx.get_id(), block.get_height())?;
+            }
+        }
+    }
+
+    Ok(())
+}
 
 pub struct UTXOSet {
     blockchain: Blockchain,
@@ -18,45 +62,41 @@ impl UTXOSet {
         &self.blockchain
     }
 
-    pub fn find_spendable_outputs(
-        &self,
-        pub_key_hash: &[u8],
-        amount: i32,
-    ) -> Result<(i32, HashMap<String, Vec<usize>>)> {
-        let mut unspent_outputs: HashMap<String, Vec<usize>> = HashMap::new();
-        let mut accumulated = 0;
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE)?;
-
-        for item in utxo_tree.iter() {
-            let (k, v) = item?;
-            let txid_hex = data_encoding::HEXLOWER.encode(k.to_vec().as_slice());
-            let outs: Vec<TXOutput> = bincode::deserialize(v.to_vec().as_slice())?;
-
-            for (vout_idx, vout) in outs.iter().enumerate() {
-                if vout.is_locked_with_key(pub_key_hash) && accumulated < amount {
-                    accumulated += vout.get_value();
-                    unspent_outputs
-                        .entry(txid_hex.clone())
-                        .or_default()
-                        .push(vout_idx);
+    /// Every unspent output locked to `pub_key_hash`.
+    pub fn find_spendable_coins(&self, pub_key_hash: &[u8]) -> Result<Vec<Coin>> {
+        let mut coins = Vec::new();
+        let store = self.blockchain.get_store();
+
+        for (txid, outs) in store.utxo_entries()? {
+            let mut height = None;
+            if outs
+                .iter()
+                .any(|(_, out)| out.is_locked_with_key(pub_key_hash))
+            {
+                height = self.get_tx_height(&txid)?;
+            }
+
+            for (vout_idx, vout) in outs {
+                if vout.is_locked_with_key(pub_key_hash) {
+                    coins.push(Coin {
+                        txid: txid.clone(),
+                        vout: vout_idx,
+                        output: vout,
+                        height,
+                    });
                 }
             }
         }
 
-        Ok((accumulated, unspent_outputs))
+        Ok(coins)
     }
 
     pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Result<Vec<TXOutput>> {
         let mut utxos: Vec<TXOutput> = Vec::new();
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE)?;
+        let store = self.blockchain.get_store();
 
-        for item in utxo_tree.iter() {
-            let (_, v) = item?;
-            let outs: Vec<TXOutput> = bincode::deserialize(v.to_vec().as_slice())?;
-
-            for vout in outs.iter() {
+        for (_, outs) in store.utxo_entries()? {
+            for (_, vout) in outs.iter() {
                 if vout.is_locked_with_key(pub_key_hash) {
                     utxos.push(vout.clone());
                 }
@@ -66,66 +106,516 @@ impl UTXOSet {
         Ok(utxos)
     }
 
+    pub fn is_unspent(&self, txid: &[u8], vout: usize) -> Result<bool> {
+        let outs = self.blockchain.get_store().get_utxo(txid)?;
+        Ok(outs.is_some_and(|outs| outs.iter().any(|(idx, _)| *idx == vout)))
+    }
+
+    /// Height of the block `txid` was mined in, if it is known.
+    pub fn get_tx_height(&self, txid: &[u8]) -> Result<Option<usize>> {
+        match self.blockchain.get_store().get_index(TX_INDEX, txid)? {
+            Some(bytes) => Ok(Some(bincode::deserialize::<u64>(&bytes)? as usize)),
+            None => Ok(None),
+        }
+    }
+
     pub fn count_transactions(&self) -> Result<usize> {
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE)?;
+        self.blockchain.get_store().count_utxo()
+    }
+
+    /// Hash of the block the UTXO set was last brought up to.
+    pub fn get_best_block(&self) -> Result<Option<String>> {
+        let store = self.blockchain.get_store();
+        match store.get_index(META_INDEX, BEST_BLOCK_KEY.as_bytes())? {
+            Some(bytes) => Ok(Some(String::from_utf8(bytes)?)),
+            None => Ok(None),
+        }
+    }
 
-        Ok(utxo_tree.len())
+    fn set_best_block(&self, block_hash: &str) -> Result<()> {
+        let store = self.blockchain.get_store();
+        store.put_index(META_INDEX, BEST_BLOCK_KEY.as_bytes(), block_hash.as_bytes())
     }
 
     pub fn reindex(&self) -> Result<()> {
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE)?;
-        utxo_tree.clear()?;
-        let utxo_map = self.blockchain.find_utxo();
+        if self.blockchain.is_pruned()? {
+            return Err(anyhow::anyhow!(
+                "Cannot rebuild the UTXO set of a pruned node"
+            ));
+        }
+
+        let store = self.blockchain.get_store();
+        store.clear_utxo()?;
+        let utxo_map = self.blockchain.find_utxo()?;
 
         for (txid_hex, outs) in &utxo_map {
             let txid = data_encoding::HEXLOWER.decode(txid_hex.as_bytes())?;
-            let value = bincode::serialize(outs)?;
-            utxo_tree.insert(txid.as_slice(), value.as_slice())?;
+            store.put_utxo(txid.as_slice(), outs)?;
+        }
+        self.set_best_block(self.blockchain.get_tip_hash().as_str())?;
+        rebuild_tx_index(&self.blockchain)?;
+
+        Ok(())
+    }
+
+    /// Replaces the whole set, e.g. with the contents of a snapshot taken at `best_block`.
+    pub fn replace(&self, entries: &[(Vec<u8>, UTXOs)], best_block: &str) -> Result<()> {
+        let store = self.blockchain.get_store();
+        store.clear_utxo()?;
+
+        for (txid, outs) in entries {
+            store.put_utxo(txid.as_slice(), outs)?;
+        }
+        self.set_best_block(best_block)?;
+
+        Ok(())
+    }
+
+    /// Checks the inputs of `block` against the end of this set: every input
+    /// spends an existing unspent output, or one created earlier in the block,
+    /// no output is spent twice, and each transaction's inputs cover its
+    /// outputs. The block must also have a single coinbase paying at most the
+    /// subsidy. Run this before `update`, which assumes all of that.
+    pub fn check_spends(&self, block: &Block) -> Result<()> {
+        block.check_coinbase()?;
+
+        let store = self.blockchain.get_store();
+        let mut created: HashMap<Vec<u8>, UTXOs> = HashMap::new();
+        let mut spent = HashSet::new();
+
+        for tx in block.get_transactions() {
+            let txid_hex = data_encoding::HEXLOWER.encode(tx.get_id());
+            let mut output_total: i64 = 0;
+            for out in tx.get_vout() {
+                if out.get_value() < 0 {
+                    return Err(anyhow::anyhow!(
+                        "Transaction {} has a negative output",
+                        txid_hex
+                    ));
+                }
+                output_total += out.get_value() as i64;
+            }
+
+            if !tx.is_coinbase() {
+                let mut input_total: i64 = 0;
+                for vin in tx.get_vin() {
+                    let outpoint = format!(
+                        "{}:{}",
+                        data_encoding::HEXLOWER.encode(vin.get_txid()),
+                        vin.get_vout()
+                    );
+                    if !spent.insert(outpoint.clone()) {
+                        return Err(anyhow::anyhow!(
+                            "Block {} spends {} twice",
+                            block.get_hash(),
+                            outpoint
+                        ));
+                    }
+                    // 同一区块内前面交易的输出也可以花费
+                    let outs = match created.get(vin.get_txid()) {
+                        Some(outs) => Some(outs.clone()),
+                        None => store.get_utxo(vin.get_txid())?,
+                    };
+                    let value = outs
+                        .and_then(|outs| outs.into_iter().find(|(idx, _)| *idx == vin.get_vout()))
+                        .map(|(_, out)| out.get_value())
+                        .ok_or(anyhow::anyhow!(
+                            "Transaction {} spends {}, which is spent or does not exist",
+                            txid_hex,
+                            outpoint
+                        ))?;
+                    input_total += value as i64;
+                }
+                if input_total < output_total {
+                    return Err(anyhow::anyhow!(
+                        "Transaction {} pays out {} but its inputs hold only {}",
+                        txid_hex,
+                        output_total,
+                        input_total
+                    ));
+                }
+            }
+
+            created.insert(
+                tx.get_id().to_vec(),
+                tx.get_vout().iter().cloned().enumerate().collect(),
+            );
         }
 
         Ok(())
     }
 
     pub fn update(&self, block: &Block) -> Result<()> {
-        let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE)?;
+        let store = self.blockchain.get_store();
+        let mut spent = Vec::new();
 
         for tx in block.get_transactions() {
             if !tx.is_coinbase() {
                 for vin in tx.get_vin() {
-                    let mut updated_outs = Vec::new();
-                    let outs_bytes = utxo_tree
-                        .get(vin.get_txid())?
+                    let outs = store
+                        .get_utxo(vin.get_txid())?
                         .ok_or(anyhow::anyhow!("UTXO not found"))?;
-                    let outs: Vec<TXOutput> = bincode::deserialize(outs_bytes.as_ref())?;
 
-                    for (idx, out) in outs.iter().enumerate() {
-                        if idx != vin.get_vout() {
-                            updated_outs.push(out.clone());
+                    let mut updated_outs: UTXOs = Vec::new();
+                    for (idx, out) in outs {
+                        if idx == vin.get_vout() {
+                            spent.push(SpentOutput {
+                                txid: vin.get_txid().to_vec(),
+                                vout: idx,
+                                output: out,
+                            });
+                        } else {
+                            updated_outs.push((idx, out));
                         }
                     }
 
-                    if updated_outs.len() > 0 {
-                        let outs_bytes = bincode::serialize(&updated_outs)?;
-                        utxo_tree.insert(vin.get_txid(), outs_bytes.as_slice())?;
+                    if !updated_outs.is_empty() {
+                        store.put_utxo(vin.get_txid(), &updated_outs)?;
                     } else {
-                        utxo_tree.remove(vin.get_txid())?;
+                        store.remove_utxo(vin.get_txid())?;
                     }
                 }
             }
 
-            let mut new_outputs = Vec::new();
-            for vout in tx.get_vout() {
-                new_outputs.push(vout.clone());
+            let new_outputs: UTXOs = tx.get_vout().iter().cloned().enumerate().collect();
+            store.put_utxo(tx.get_id(), &new_outputs)?;
+            put_tx_height(store, tx.get_id(), block.get_height())?;
+        }
+
+        store.put_index(
+            UNDO_INDEX,
+            block.get_hash().as_bytes(),
+            &bincode::serialize(&spent)?,
+        )?;
+        self.set_best_block(block.get_hash())?;
+
+        Ok(())
+    }
+
+    /// Reverts `update` for the block the UTXO set currently ends at.
+    pub fn disconnect(&self, block: &Block) -> Result<()> {
+        let store = self.blockchain.get_store();
+        let undo_bytes = store
+            .get_index(UNDO_INDEX, block.get_hash().as_bytes())?
+            .ok_or(anyhow::anyhow!(
+                "No undo data for block {}",
+                block.get_hash()
+            ))?;
+        let spent: Vec<SpentOutput> = bincode::deserialize(&undo_bytes)?;
+
+        let mut created = HashSet::new();
+        for tx in block.get_transactions() {
+            store.remove_utxo(tx.get_id())?;
+            store.remove_index(TX_INDEX, tx.get_id())?;
+            created.insert(tx.get_id_bytes());
+        }
+
+        for spent_output in spent {
+            // 同一个块里产生又花掉的输出不需要恢复
+            if created.contains(&spent_output.txid) {
+                continue;
             }
 
-            let outs_bytes = bincode::serialize(&new_outputs)?;
-            let txid_hex = data_encoding::HEXLOWER.encode(tx.get_id());
-            utxo_tree.insert(txid_hex.as_bytes(), outs_bytes.as_slice())?;
+            let mut outs = store.get_utxo(&spent_output.txid)?.unwrap_or_default();
+            outs.push((spent_output.vout, spent_output.output));
+            outs.sort_by_key(|(idx, _)| *idx);
+            store.put_utxo(&spent_output.txid, &outs)?;
+        }
+
+        store.remove_index(UNDO_INDEX, block.get_hash().as_bytes())?;
+        self.set_best_block(block.get_pre_block_hash().as_str())?;
+
+        Ok(())
+    }
+
+    /// Outputs spent by a block the set has connected, from its undo data.
+    /// `None` for blocks that were pruned or lie below a loaded snapshot.
+    pub fn get_spent_outputs(&self, block_hash: &str) -> Result<Option<SpentOutputs>> {
+        match self
+            .blockchain
+            .get_store()
+            .get_index(UNDO_INDEX, block_hash.as_bytes())?
+        {
+            Some(bytes) => {
+                let spent: Vec<SpentOutput> = bincode::deserialize(&bytes)?;
+                Ok(Some(spent_outputs(&spent)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Brings the UTXO set from its best block to the chain tip, disconnecting
+    /// blocks of an abandoned branch first. Falls back to a full reindex when
+    /// the set has no recorded best block.
+    pub fn catch_up(&self) -> Result<()> {
+        let tip_hash = self.blockchain.get_tip_hash();
+        let best_block = match self.get_best_block()? {
+            Some(hash) => hash,
+            None => return self.reindex(),
+        };
+        if best_block == tip_hash {
+            return Ok(());
+        }
+
+        let header_of = |hash: &str| {
+            self.blockchain
+                .get_header(hash.as_bytes())?
+                .ok_or(anyhow::anyhow!("Unknown block {}", hash))
+        };
+
+        // 找到 UTXO 所在分支与当前主链的分叉点
+        let mut to_connect = Vec::new();
+        let mut to_disconnect = Vec::new();
+        let mut main = header_of(&tip_hash)?;
+        let mut side = header_of(&best_block)?;
+        while main.get_hash() != side.get_hash() {
+            if main.get_height() >= side.get_height() {
+                to_connect.push(main.get_hash().to_string());
+                main = match header_of(&main.get_pre_block_hash()) {
+                    Ok(header) => header,
+                    // 两条链没有共同祖先
+                    Err(_) => return self.reindex(),
+                };
+            } else {
+                to_disconnect.push(side.get_hash().to_string());
+                side = match header_of(&side.get_pre_block_hash()) {
+                    Ok(header) => header,
+                    Err(_) => return self.reindex(),
+                };
+            }
+        }
+
+        for hash in to_disconnect {
+            let block = self.body_of(&hash)?;
+            self.disconnect(&block)?;
+        }
+        for hash in to_connect.iter().rev() {
+            let block = self.body_of(hash)?;
+            self.update(&block)?;
         }
 
         Ok(())
     }
+
+    fn body_of(&self, hash: &str) -> Result<Block> {
+        self.blockchain
+            .get_block(hash.as_bytes())?
+            .ok_or(anyhow::anyhow!("Block {} is not available", hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        coin_selection::CoinControl,
+        signature::SignatureScheme,
+        store::MemoryStore,
+        transaction::Transaction,
+        tx_builder::Recipient,
+        wallets::{self, Wallet, Wallets},
+    };
+    use std::sync::Arc;
+
+    fn new_wallets() -> Wallets {
+        let data_dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        Wallets::try_new(&data_dir).unwrap()
+    }
+
+    fn new_chain(miner: &str) -> UTXOSet {
+        let store = Arc::new(MemoryStore::new());
+        let blockchain = Blockchain::create_with_store(store, miner).unwrap();
+        let utxo_set = UTXOSet::new(blockchain);
+        utxo_set.catch_up().unwrap();
+        utxo_set
+    }
+
+    fn balance(utxo_set: &UTXOSet, wallet: &Wallet) -> i32 {
+        let pub_key_hash = wallets::hash_pub_key(wallet.get_public_key());
+        utxo_set
+            .find_utxo(&pub_key_hash)
+            .unwrap()
+            .iter()
+            .map(|out| out.get_value())
+            .sum()
+    }
+
+    // TXOutput 没有实现 PartialEq，按序列化结果比较
+    fn entries(utxo_set: &UTXOSet) -> Vec<u8> {
+        let mut entries = utxo_set
+            .get_blockchain()
+            .get_store()
+            .utxo_entries()
+            .unwrap();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        bincode::serialize(&entries).unwrap()
+    }
+
+    #[test]
+    fn update_matches_reindex() {
+        let wallet = Wallet::try_new(SignatureScheme::P256).unwrap();
+        let utxo_set = new_chain(&wallet.get_address());
+        let blockchain = utxo_set.get_blockchain();
+
+        let coinbase = Transaction::new_coinbase_tx(&wallet.get_address()).unwrap();
+        let block = blockchain.mine_block(&[coinbase]).unwrap();
+        utxo_set.update(&block).unwrap();
+        let updated = entries(&utxo_set);
+
+        utxo_set.reindex().unwrap();
+        assert_eq!(entries(&utxo_set), updated);
+        assert_eq!(balance(&utxo_set, &wallet), 20);
+    }
+
+    #[test]
+    fn disconnect_undoes_update() {
+        let mut wallets = new_wallets();
+        let alice = wallets.create_wallet(SignatureScheme::P256).unwrap();
+        let bob = wallets.create_wallet(SignatureScheme::P256).unwrap();
+        let utxo_set = new_chain(&alice);
+        let genesis_hash = utxo_set.get_blockchain().get_tip_hash();
+        let before = entries(&utxo_set);
+
+        let tx = Transaction::new_utxo_transaction(
+            &alice,
+            &[Recipient::new(&bob, 3)],
+            &mut wallets,
+            &utxo_set,
+            &CoinControl::default(),
+        )
+        .unwrap();
+        let block = utxo_set.get_blockchain().mine_block(&[tx]).unwrap();
+        utxo_set.update(&block).unwrap();
+        // 找零去了新的找零地址
+        assert_eq!(balance(&utxo_set, wallets.get_wallet(&alice).unwrap()), 0);
+        assert_eq!(balance(&utxo_set, wallets.get_wallet(&bob).unwrap()), 3);
+        let change = wallets
+            .get_addresses()
+            .into_iter()
+            .find(|address| wallets.is_change(address))
+            .unwrap();
+        assert_eq!(balance(&utxo_set, wallets.get_wallet(&change).unwrap()), 7);
+
+        utxo_set.disconnect(&block).unwrap();
+        assert_eq!(entries(&utxo_set), before);
+        assert_eq!(utxo_set.get_best_block().unwrap(), Some(genesis_hash));
+        // 撤销数据用过即删
+        assert!(utxo_set.disconnect(&block).is_err());
+    }
+
+    #[test]
+    fn check_spends_rejects_spent_output() {
+        let mut wallets = new_wallets();
+        let alice = wallets.create_wallet(SignatureScheme::P256).unwrap();
+        let bob = wallets.create_wallet(SignatureScheme::P256).unwrap();
+        let utxo_set = new_chain(&alice);
+
+        let tx = Transaction::new_utxo_transaction(
+            &alice,
+            &[Recipient::new(&bob, 3)],
+            &mut wallets,
+            &utxo_set,
+            &CoinControl::default(),
+        )
+        .unwrap();
+        let coinbase = || Transaction::new_coinbase_tx(&bob).unwrap();
+        let block = Block::new_block(
+            utxo_set.get_blockchain().get_tip_hash(),
+            &[tx.clone(), coinbase()],
+            1,
+        );
+        utxo_set.check_spends(&block).unwrap();
+        utxo_set.get_blockchain().add_block(&block).unwrap();
+        utxo_set.update(&block).unwrap();
+
+        // 同一笔交易再花一次
+        let replay = Block::new_block(block.get_hash().to_string(), &[tx, coinbase()], 2);
+        assert!(utxo_set.check_spends(&replay).is_err());
+    }
+
+    #[test]
+    fn check_spends_requires_one_coinbase() {
+        let mut wallets = new_wallets();
+        let alice = wallets.create_wallet(SignatureScheme::P256).unwrap();
+        let bob = wallets.create_wallet(SignatureScheme::P256).unwrap();
+        let utxo_set = new_chain(&alice);
+        let tip_hash = utxo_set.get_blockchain().get_tip_hash();
+
+        let tx = Transaction::new_utxo_transaction(
+            &alice,
+            &[Recipient::new(&bob, 3)],
+            &mut wallets,
+            &utxo_set,
+            &CoinControl::default(),
+        )
+        .unwrap();
+        let block = Block::new_block(tip_hash.clone(), std::slice::from_ref(&tx), 1);
+        assert!(utxo_set.check_spends(&block).is_err());
+
+        let coinbase = || Transaction::new_coinbase_tx(&bob).unwrap();
+        let block = Block::new_block(tip_hash, &[tx, coinbase(), coinbase()], 1);
+        assert!(utxo_set.check_spends(&block).is_err());
+    }
+
+    #[test]
+    fn validate_block_checks_coinbase_off_the_tip() {
+        let mut wallets = new_wallets();
+        let alice = wallets.create_wallet(SignatureScheme::P256).unwrap();
+        let utxo_set = new_chain(&alice);
+        let blockchain = utxo_set.get_blockchain();
+        let genesis_hash = blockchain.get_tip_hash();
+
+        let coinbase = || Transaction::new_coinbase_tx(&alice).unwrap();
+        let block = blockchain.mine_block(&[coinbase()]).unwrap();
+        utxo_set.update(&block).unwrap();
+
+        // 分叉块不在 UTXO 末端，不会走 check_spends
+        let fork = Block::new_block(genesis_hash, &[coinbase(), coinbase()], 1);
+        assert!(blockchain.validate_block(&fork).is_err());
+    }
+
+    #[test]
+    fn catch_up_follows_reorg() {
+        let mut wallets = new_wallets();
+        let alice = wallets.create_wallet(SignatureScheme::P256).unwrap();
+        let bob = wallets.create_wallet(SignatureScheme::P256).unwrap();
+        let utxo_set = new_chain(&alice);
+        let blockchain = utxo_set.get_blockchain();
+        let genesis_hash = blockchain.get_tip_hash();
+
+        let tx = Transaction::new_utxo_transaction(
+            &alice,
+            &[Recipient::new(&bob, 3)],
+            &mut wallets,
+            &utxo_set,
+            &CoinControl::default(),
+        )
+        .unwrap();
+        blockchain.mine_block(&[tx]).unwrap();
+        utxo_set.catch_up().unwrap();
+        assert_eq!(balance(&utxo_set, wallets.get_wallet(&bob).unwrap()), 3);
+
+        // 从创世块分出一条更长的链，其中没有这笔转账
+        let fork_1 = Block::new_block(
+            genesis_hash,
+            &[Transaction::new_coinbase_tx(&bob).unwrap()],
+            1,
+        );
+        blockchain.add_block(&fork_1).unwrap();
+        let fork_2 = Block::new_block(
+            fork_1.get_hash().to_string(),
+            &[Transaction::new_coinbase_tx(&bob).unwrap()],
+            2,
+        );
+        blockchain.add_block(&fork_2).unwrap();
+        assert_eq!(blockchain.get_tip_hash(), fork_2.get_hash());
+
+        utxo_set.catch_up().unwrap();
+        assert_eq!(
+            utxo_set.get_best_block().unwrap().as_deref(),
+            Some(fork_2.get_hash())
+        );
+        assert_eq!(balance(&utxo_set, wallets.get_wallet(&alice).unwrap()), 10);
+        assert_eq!(balance(&utxo_set, wallets.get_wallet(&bob).unwrap()), 20);
+    }
 }
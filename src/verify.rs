@@ -0,0 +1,126 @@
+use anyhow::Result;
+use std::collections::HashMap;
+
+use crate::{blockchain::Blockchain, snapshot, utxo_set::UTXOSet};
+
+#[derive(Debug)]
+pub struct BadBlock {
+    pub hash: String,
+    pub height: usize,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub blocks_checked: usize,
+    /// The lowest failing block; everything above it builds on bad data.
+    pub first_bad_block: Option<BadBlock>,
+    /// Set when the walk stopped at a pruned block before reaching the requested depth.
+    pub stopped_at_pruned: Option<String>,
+    /// Differences between the stored chainstate and one rebuilt from blocks.
+    pub utxo_mismatches: Vec<String>,
+    pub utxo_checked: bool,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.first_bad_block.is_none() && self.utxo_mismatches.is_empty()
+    }
+}
+
+/// Walks the stored chain from the tip, checking `depth` blocks (all when
+/// `None`), then compares the chainstate against a fresh UTXO computation.
+pub fn verify_chain(blockchain: &Blockchain, depth: Option<usize>) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let mut expected_hash = blockchain.get_tip_hash();
+    let mut expected_height = None;
+
+    while depth.is_none_or(|depth| report.blocks_checked < depth) {
+        let block = match blockchain.get_block(expected_hash.as_bytes())? {
+            Some(block) => block,
+            None => {
+                if blockchain.get_header(expected_hash.as_bytes())?.is_some() {
+                    report.stopped_at_pruned = Some(expected_hash);
+                }
+                break;
+            }
+        };
+        report.blocks_checked += 1;
+
+        let mut reason = None;
+        if block.get_hash() != expected_hash {
+            reason = Some(format!("stored under hash {}", expected_hash));
+        } else if let Some(height) = expected_height
+            && block.get_height() != height
+        {
+            reason = Some(format!(
+                "height {} does not follow its child, expected {}",
+                block.get_height(),
+                height
+            ));
+        } else if let Err(e) = blockchain.validate_block(&block) {
+            reason = Some(e.to_string());
+        }
+
+        if let Some(reason) = reason {
+            report.first_bad_block = Some(BadBlock {
+                hash: expected_hash.clone(),
+                height: block.get_height(),
+                reason,
+            });
+        }
+
+        if block.get_height() == 0 {
+            break;
+        }
+        expected_height = Some(block.get_height() - 1);
+        expected_hash = block.get_pre_block_hash();
+    }
+
+    // 没有完整历史时无法从区块重建 UTXO
+    if blockchain.is_pruned()? || snapshot::pending_snapshot(blockchain)?.is_some() {
+        return Ok(report);
+    }
+
+    report.utxo_checked = true;
+    report.utxo_mismatches = compare_chainstate(blockchain)?;
+
+    Ok(report)
+}
+
+fn compare_chainstate(blockchain: &Blockchain) -> Result<Vec<String>> {
+    let mut mismatches = Vec::new();
+    let utxo_set = UTXOSet::new(blockchain.clone());
+
+    let tip_hash = blockchain.get_tip_hash();
+    if let Some(best_block) = utxo_set.get_best_block()?
+        && best_block != tip_hash
+    {
+        mismatches.push(format!(
+            "chainstate is at block {}, tip is {}",
+            best_block, tip_hash
+        ));
+    }
+
+    let mut expected: HashMap<Vec<u8>, _> = HashMap::new();
+    for (txid_hex, outs) in blockchain.find_utxo()? {
+        expected.insert(data_encoding::HEXLOWER.decode(txid_hex.as_bytes())?, outs);
+    }
+
+    for (txid, outs) in blockchain.get_store().utxo_entries()? {
+        let txid_hex = data_encoding::HEXLOWER.encode(&txid);
+        match expected.remove(&txid) {
+            Some(expected_outs) if expected_outs == outs => {}
+            Some(_) => mismatches.push(format!("outputs of {} differ", txid_hex)),
+            None => mismatches.push(format!("{} should not be in the chainstate", txid_hex)),
+        }
+    }
+    for txid in expected.keys() {
+        mismatches.push(format!(
+            "{} is missing from the chainstate",
+            data_encoding::HEXLOWER.encode(txid)
+        ));
+    }
+
+    Ok(mismatches)
+}
@@ -0,0 +1,242 @@
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+use crate::config::GLOBAL_CONFIG;
+
+/// Socket in the network directory where the running node holds the keys of
+/// unlocked wallets. The keys are never written to disk.
+const AGENT_SOCKET: &str = "wallet.sock";
+
+/// Requests are tiny, anything larger is not a wallet command.
+const MAX_MESSAGE_LEN: usize = 4096;
+
+#[derive(Deserialize, Serialize)]
+enum AgentRequest {
+    Unlock {
+        wallet: PathBuf,
+        key: Vec<u8>,
+        timeout_secs: u64,
+    },
+    Lock {
+        wallet: PathBuf,
+    },
+    GetKey {
+        wallet: PathBuf,
+    },
+}
+
+struct UnlockedKey {
+    key: Vec<u8>,
+    expires_at: Instant,
+}
+
+// 以钱包目录为键
+static UNLOCKED: Lazy<Mutex<HashMap<PathBuf, UnlockedKey>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn handle_request(request: AgentRequest) -> Option<Vec<u8>> {
+    let mut unlocked = UNLOCKED.lock().expect("Wallet agent lock poisoned");
+    match request {
+        AgentRequest::Unlock {
+            wallet,
+            key,
+            timeout_secs,
+        } => {
+            let expires_at = Instant::now() + Duration::from_secs(timeout_secs);
+            unlocked.insert(wallet, UnlockedKey { key, expires_at });
+            None
+        }
+        AgentRequest::Lock { wallet } => {
+            unlocked.remove(&wallet);
+            None
+        }
+        AgentRequest::GetKey { wallet } => match unlocked.get(&wallet) {
+            Some(unlocked_key) if unlocked_key.expires_at > Instant::now() => {
+                Some(unlocked_key.key.clone())
+            }
+            Some(_) => {
+                unlocked.remove(&wallet);
+                None
+            }
+            None => None,
+        },
+    }
+}
+
+/// Serves wallet commands of the same user until `shutdown` is set.
+#[cfg(unix)]
+pub async fn serve(network_dir: PathBuf, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    use std::{
+        fs,
+        os::unix::fs::{MetadataExt as _, PermissionsExt as _},
+    };
+    use tokio::{
+        io::{AsyncReadExt as _, AsyncWriteExt as _},
+        net::{UnixListener, UnixStream},
+    };
+
+    async fn handle(mut stream: UnixStream, owner: u32) -> Result<()> {
+        // 只接受和节点同一用户的连接
+        if stream.peer_cred()?.uid() != owner {
+            return Err(anyhow::anyhow!("Refusing a wallet command of another user"));
+        }
+        let len = stream.read_u32_le().await? as usize;
+        if len > MAX_MESSAGE_LEN {
+            return Err(anyhow::anyhow!("Wallet command too large"));
+        }
+        let mut buf = vec![0u8; len];
+        stream.read_exact(&mut buf).await?;
+        let response = handle_request(bincode::deserialize(&buf)?);
+
+        let bytes = bincode::serialize(&response)?;
+        stream.write_u32_le(bytes.len() as u32).await?;
+        stream.write_all(&bytes).await?;
+        Ok(())
+    }
+
+    let path = network_dir.join(AGENT_SOCKET);
+    // 上次异常退出时可能留下套接字文件
+    if path.exists() {
+        fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    let owner = fs::metadata(&path)?.uid();
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        eprintln!("Wallet agent error: {}", e);
+                        continue;
+                    }
+                };
+                tokio::spawn(async move {
+                    if let Err(e) = handle(stream, owner).await {
+                        eprintln!("Wallet agent error: {}", e);
+                    }
+                });
+            }
+            _ = shutdown.changed() => break,
+        }
+    }
+
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn serve(_network_dir: PathBuf, _shutdown: watch::Receiver<bool>) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn request(request: &AgentRequest) -> Result<Option<Vec<u8>>> {
+    use std::{
+        io::{Read as _, Write as _},
+        os::unix::net::UnixStream,
+    };
+
+    let path = GLOBAL_CONFIG.get_network_dir()?.join(AGENT_SOCKET);
+    let mut stream = UnixStream::connect(path).map_err(|_| {
+        anyhow::anyhow!("The node is not running, unlocked wallets are kept by start-node")
+    })?;
+    let bytes = bincode::serialize(request)?;
+    stream.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    stream.write_all(&bytes)?;
+
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(anyhow::anyhow!("Wallet agent response too large"));
+    }
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf)?;
+    Ok(bincode::deserialize(&buf)?)
+}
+
+#[cfg(not(unix))]
+fn request(_request: &AgentRequest) -> Result<Option<Vec<u8>>> {
+    Err(anyhow::anyhow!(
+        "Unlocking a wallet needs Unix domain sockets"
+    ))
+}
+
+// 同一个钱包无论用什么路径打开都对应同一个条目
+fn wallet_id(wallet_dir: &Path) -> Result<PathBuf> {
+    Ok(std::fs::canonicalize(wallet_dir)?)
+}
+
+/// Hands the key of the wallet in `wallet_dir` to the running node for
+/// `timeout_secs` seconds.
+pub fn unlock(wallet_dir: &Path, key: &[u8], timeout_secs: u64) -> Result<()> {
+    request(&AgentRequest::Unlock {
+        wallet: wallet_id(wallet_dir)?,
+        key: key.to_vec(),
+        timeout_secs,
+    })?;
+    Ok(())
+}
+
+/// Makes the running node forget the key. Nothing to do if it is not running.
+pub fn lock(wallet_dir: &Path) -> Result<()> {
+    let wallet = wallet_id(wallet_dir)?;
+    let _ = request(&AgentRequest::Lock { wallet });
+    Ok(())
+}
+
+/// The key of the wallet in `wallet_dir`, if the running node holds it.
+pub fn get_key(wallet_dir: &Path) -> Option<Vec<u8>> {
+    let wallet = wallet_id(wallet_dir).ok()?;
+    request(&AgentRequest::GetKey { wallet }).ok().flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_key(wallet: &Path) -> Option<Vec<u8>> {
+        handle_request(AgentRequest::GetKey {
+            wallet: wallet.to_path_buf(),
+        })
+    }
+
+    #[test]
+    fn keys_expire_and_lock() {
+        // 每个测试用不同的路径，避免共用全局状态
+        let wallet = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let other = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        assert_eq!(get_key(&wallet), None);
+
+        handle_request(AgentRequest::Unlock {
+            wallet: wallet.clone(),
+            key: vec![1; 32],
+            timeout_secs: 60,
+        });
+        assert_eq!(get_key(&wallet), Some(vec![1; 32]));
+        assert_eq!(get_key(&other), None);
+
+        handle_request(AgentRequest::Lock {
+            wallet: wallet.clone(),
+        });
+        assert_eq!(get_key(&wallet), None);
+
+        handle_request(AgentRequest::Unlock {
+            wallet: wallet.clone(),
+            key: vec![2; 32],
+            timeout_secs: 0,
+        });
+        assert_eq!(get_key(&wallet), None);
+    }
+}
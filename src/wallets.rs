@@ -1,55 +1,77 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    env,
-    fs::{File, OpenOptions},
-    io::{BufWriter, Read, Write},
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+    str::FromStr,
 };
 
-use crate::utils;
+use bech32::{Bech32m, Hrp, primitives::decode::CheckedHrpstring};
+use p256::pkcs8::LineEnding;
 
+use crate::{
+    config::{self, NETWORKS, Network},
+    hd::ExtendedKey,
+    migration,
+    signature::{SCHEMES, SignatureScheme},
+    utils, wallet_agent,
+};
+
+/// Version byte of legacy base58 addresses.
 const VERSION: u8 = 0x00;
 pub const ADDRESS_CHECK_SUM_LEN: usize = 4;
+/// Length of a P-256 public key hash. Hashes of other schemes have the
+/// scheme id in front.
+pub const PUB_KEY_HASH_LEN: usize = 20;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Wallet {
+    // 钱包加密后这里保存的是密文
     pkcs8: Vec<u8>,
     public_key: Vec<u8>,
 }
 
 impl Wallet {
-    pub fn try_new() -> Result<Self> {
-        let (pkcs8, public_key) = utils::new_key_pair().map_err(|e| anyhow::anyhow!(e))?;
+    pub fn try_new(scheme: SignatureScheme) -> Result<Self> {
+        let (pkcs8, public_key) = scheme.new_key_pair()?;
 
         Ok(Wallet { pkcs8, public_key })
     }
 
-    pub fn get_address(&self) -> String {
-        let mut address = vec![VERSION];
+    fn from_extended_key(key: &ExtendedKey) -> Result<Self> {
+        let (pkcs8, public_key) = key.to_key_pair()?;
 
-        let pub_key_hash = hash_pub_key(self.public_key.as_slice());
-        address.extend_from_slice(pub_key_hash.as_slice());
-
-        let checksum = checksum(address.as_slice());
-        address.extend_from_slice(checksum.as_slice());
+        Ok(Wallet { pkcs8, public_key })
+    }
 
-        // version + pub_key_hash + checksum
-        utils::base58_encode(address.as_slice())
+    pub fn get_address(&self) -> String {
+        address_of_pub_key(self.public_key.as_slice())
     }
 
     pub fn get_public_key(&self) -> &[u8] {
         self.public_key.as_slice()
     }
 
-    pub fn get_pkcs8(&self) -> &[u8] {
-        &self.pkcs8.as_slice()
+    pub fn get_scheme(&self) -> Result<SignatureScheme> {
+        SignatureScheme::of_public_key(&self.public_key)
     }
 }
 
+pub fn address_of_pub_key(pub_key: &[u8]) -> String {
+    convert_address(hash_pub_key(pub_key).as_slice())
+}
+
 pub fn hash_pub_key(pub_key: &[u8]) -> Vec<u8> {
     let pub_key_sha256: Vec<u8> = utils::sha256_digest(pub_key);
-    utils::ripemd160_digest(pub_key_sha256.as_slice())
+    let hash = utils::ripemd160_digest(pub_key_sha256.as_slice());
+    // P-256 的哈希不带方案标识，和旧地址保持一致
+    match SignatureScheme::of_public_key(pub_key) {
+        Ok(SignatureScheme::P256) | Err(_) => hash,
+        Ok(scheme) => [&[scheme.id()][..], &hash].concat(),
+    }
 }
 
 fn checksum(payload: &[u8]) -> Vec<u8> {
@@ -58,106 +80,1322 @@ fn checksum(payload: &[u8]) -> Vec<u8> {
     second_sha[0..ADDRESS_CHECK_SUM_LEN].to_vec()
 }
 
-pub fn validate_address(address: &str) -> bool {
-    let decoded = utils::base58_decode(address);
-    if decoded.is_empty() {
-        return false;
-    }
+fn current_network() -> Network {
+    config::GLOBAL_CONFIG.get_network().unwrap_or_default()
+}
 
-    let address_len = decoded.len();
+// 旧格式：base58(version(1) | pub_key_hash | checksum(4))
+fn decode_legacy_address(address: &str) -> Result<Vec<u8>> {
+    let decoded = utils::base58_decode(address)?;
+    if decoded.len() <= 1 + ADDRESS_CHECK_SUM_LEN || decoded[0] != VERSION {
+        return Err(anyhow::anyhow!("Invalid address {}", address));
+    }
 
-    let version = decoded[0];
-    if version != VERSION {
-        return false;
+    let (payload, actual_checksum) = decoded.split_at(decoded.len() - ADDRESS_CHECK_SUM_LEN);
+    if checksum(payload) != actual_checksum {
+        return Err(anyhow::anyhow!("Invalid address {}: bad checksum", address));
     }
 
-    let pub_key_hash = &decoded[1..address_len - ADDRESS_CHECK_SUM_LEN];
-    let actual_checksum = &decoded[address_len - ADDRESS_CHECK_SUM_LEN..];
+    Ok(payload[1..].to_vec())
+}
 
-    let mut target = vec![version];
-    target.extend_from_slice(pub_key_hash);
+fn has_network_prefix(address: &str) -> bool {
+    let address = address.to_lowercase();
+    NETWORKS
+        .iter()
+        .any(|network| address.starts_with(&format!("{}1", network.address_prefix())))
+}
+
+/// The public key hash an address locks outputs to. Takes bech32m addresses
+/// of the selected network as well as legacy base58 addresses, which carry
+/// no network.
+pub fn get_pub_key_hash(address: &str) -> Result<Vec<u8>> {
+    // 新格式：bech32m(network_prefix, pub_key_hash)
+    let pub_key_hash = match CheckedHrpstring::new::<Bech32m>(address) {
+        Ok(checked) => {
+            let prefix = checked.hrp().to_lowercase();
+            let network = current_network();
+            if prefix != network.address_prefix() {
+                return Err(
+                    match NETWORKS.iter().find(|n| n.address_prefix() == prefix) {
+                        Some(other) => anyhow::anyhow!(
+                            "{} is a {} address, but the node runs on {}",
+                            address,
+                            other,
+                            network
+                        ),
+                        None => anyhow::anyhow!("Invalid address {}: unknown prefix", address),
+                    },
+                );
+            }
+            checked.byte_iter().collect()
+        }
+        Err(_) if has_network_prefix(address) => {
+            return Err(anyhow::anyhow!("Invalid address {}: bad checksum", address));
+        }
+        Err(_) => decode_legacy_address(address)?,
+    };
+    SignatureScheme::of_pub_key_hash(&pub_key_hash)
+        .map_err(|_| anyhow::anyhow!("Invalid address {}", address))?;
 
-    let expected_checksum = checksum(target.as_slice());
+    Ok(pub_key_hash)
+}
 
-    expected_checksum == actual_checksum
+pub fn validate_address(address: &str) -> bool {
+    // 标签等任意字符串也会传进来，不能 panic
+    get_pub_key_hash(address).is_ok()
 }
 
+/// The address of `pub_key_hash` on the selected network.
 pub fn convert_address(pub_key_hash: &[u8]) -> String {
-    let mut address = vec![VERSION];
-    address.extend_from_slice(pub_key_hash);
+    let hrp = Hrp::parse_unchecked(current_network().address_prefix());
+    bech32::encode::<Bech32m>(hrp, pub_key_hash).expect("public key hash fits in an address")
+}
+
+/// `address` in the current format, so legacy and bech32m forms of the same
+/// address are treated alike.
+pub fn normalize_address(address: &str) -> Result<String> {
+    Ok(convert_address(&get_pub_key_hash(address)?))
+}
+
+// 钱包里的地址都以新格式保存，查找前先转换
+fn canonical(address: &str) -> String {
+    normalize_address(address).unwrap_or_else(|_| address.to_string())
+}
+
+// base58 私钥格式：version(1) | secret(32) | checksum(4)，version 为 0x80 加方案 id
+const PRIVATE_KEY_VERSION: u8 = 0x80;
+const PRIVATE_KEY_SECRET_LEN: usize = 32;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyFormat {
+    /// PKCS#8 in a `PRIVATE KEY` PEM block.
+    #[default]
+    Pem,
+    /// The raw secret with a version byte and checksum, like an address.
+    Base58,
+}
+
+impl fmt::Display for KeyFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyFormat::Pem => f.write_str("pem"),
+            KeyFormat::Base58 => f.write_str("base58"),
+        }
+    }
+}
+
+impl FromStr for KeyFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pem" | "pkcs8" => Ok(KeyFormat::Pem),
+            "base58" => Ok(KeyFormat::Base58),
+            _ => Err(anyhow::anyhow!("Unknown key format: {}", s)),
+        }
+    }
+}
 
-    let checksum = checksum(pub_key_hash);
-    address.extend_from_slice(checksum.as_slice());
+pub fn encode_private_key(pkcs8: &[u8], format: KeyFormat) -> Result<String> {
+    let (scheme, pkcs8, _) = SignatureScheme::from_pkcs8(pkcs8)?;
+    match format {
+        KeyFormat::Pem => {
+            let document = p256::pkcs8::SecretDocument::try_from(pkcs8.as_slice())
+                .map_err(|e| anyhow::anyhow!("Invalid PKCS#8 private key: {}", e))?;
+            let pem = document
+                .to_pem("PRIVATE KEY", LineEnding::LF)
+                .map_err(|e| anyhow::anyhow!("Failed to encode private key: {}", e))?;
+            Ok(pem.to_string())
+        }
+        KeyFormat::Base58 => {
+            let mut payload = vec![PRIVATE_KEY_VERSION + scheme.id()];
+            payload.extend_from_slice(scheme.secret_from_pkcs8(&pkcs8)?.as_slice());
+            let checksum = checksum(payload.as_slice());
+            payload.extend_from_slice(checksum.as_slice());
+            Ok(utils::base58_encode(payload.as_slice()))
+        }
+    }
+}
+
+/// Parses a key in either `KeyFormat` and returns its PKCS#8 document and
+/// public key.
+pub fn decode_private_key(text: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+    let text = text.trim();
+    if text.starts_with("-----BEGIN") {
+        let (_, document) = p256::pkcs8::SecretDocument::from_pem(text)
+            .map_err(|e| anyhow::anyhow!("Invalid PEM private key: {}", e))?;
+        let (_, pkcs8, public_key) = SignatureScheme::from_pkcs8(document.as_bytes())?;
+        return Ok((pkcs8, public_key));
+    }
 
-    utils::base58_encode(address.as_slice())
+    let payload = utils::base58_decode(text)
+        .map_err(|_| anyhow::anyhow!("Private key is neither PEM nor base58"))?;
+    let scheme = SCHEMES
+        .iter()
+        .find(|scheme| payload.first() == Some(&(PRIVATE_KEY_VERSION + scheme.id())));
+    let scheme = match scheme {
+        Some(scheme) if payload.len() == 1 + PRIVATE_KEY_SECRET_LEN + ADDRESS_CHECK_SUM_LEN => {
+            scheme
+        }
+        _ => return Err(anyhow::anyhow!("Not a base58 private key")),
+    };
+    let (body, actual_checksum) = payload.split_at(payload.len() - ADDRESS_CHECK_SUM_LEN);
+    if checksum(body) != actual_checksum {
+        return Err(anyhow::anyhow!("Private key checksum mismatch"));
+    }
+
+    scheme.key_pair_from_secret(&body[1..])
 }
 
 pub const WALLET_FILE: &str = "wallet.dat";
 
+// 钱包文件格式：magic(4) | version(u32 LE) | bincode(wallets)
+const WALLET_MAGIC: &[u8; 4] = b"RBWL";
+const WALLET_HEADER_LEN: usize = 8;
+
+/// Named wallets each get a directory under `<data dir>/wallets/`, laid out
+/// like the data directory of the default wallet.
+pub const WALLETS_DIR: &str = "wallets";
+/// Names of the loaded wallets, one per line.
+const LOADED_FILE: &str = "loaded";
+
+const KDF_ITERATIONS: u32 = 100_000;
+const KDF_SALT_LEN: usize = 16;
+const PASSPHRASE_CHECK: &[u8] = b"wallet passphrase check";
+
+/// How private keys are encrypted. Addresses and public keys stay readable
+/// so balances can be shown while the wallet is locked.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WalletEncryption {
+    salt: Vec<u8>,
+    iterations: u32,
+    // 用来校验口令是否正确
+    check: Vec<u8>,
+}
+
+/// Receiving addresses are derived at m/0'/i'.
+const RECEIVE_BRANCH: u32 = 0;
+
+/// Change addresses are derived at m/1'/i'.
+const CHANGE_BRANCH: u32 = 1;
+
+/// Change always uses the default scheme, whatever the key of the sender.
+const CHANGE_SCHEME: SignatureScheme = SignatureScheme::P256;
+
+/// Restoring stops after this many consecutive addresses without any coins.
+const GAP_LIMIT: u32 = 20;
+
+const MNEMONIC_ENTROPY_LEN: usize = 16;
+
+/// The seed all derived addresses come from.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HdChain {
+    // 钱包加密后这里保存的是密文
+    mnemonic: Vec<u8>,
+    next_index: u32,
+    next_change_index: u32,
+}
+
+/// An address tracked without its private key. The public key is known
+/// when the entry was added from one.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WatchOnly {
+    public_key: Option<Vec<u8>>,
+}
+
+impl WatchOnly {
+    pub fn get_public_key(&self) -> Option<&[u8]> {
+        self.public_key.as_deref()
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Wallets {
     wallets: HashMap<String, Wallet>,
+    encryption: Option<WalletEncryption>,
+    hd: Option<HdChain>,
+    /// Addresses whose keys are not derived from the seed, so the mnemonic
+    /// does not back them up.
+    imported: HashSet<String>,
+    watch_only: HashMap<String, WatchOnly>,
+    /// Addresses the wallet created to receive its own change.
+    change: HashSet<String>,
+    /// Names the user gave to addresses of this wallet.
+    labels: HashMap<String, String>,
+    /// External addresses the user pays, with their names.
+    contacts: HashMap<String, String>,
+    #[serde(skip)]
+    data_dir: PathBuf,
+    #[serde(skip)]
+    key: Option<Vec<u8>>,
 }
 
 impl Wallets {
-    pub fn try_new() -> Result<Self> {
+    /// Opens the wallet called `name`, or the default wallet of `data_dir`.
+    /// A named wallet must exist and be loaded.
+    pub fn load(data_dir: &Path, name: Option<&str>) -> Result<Self> {
+        let name = match name {
+            Some(name) => name,
+            None => return Self::try_new(data_dir),
+        };
+        let wallet_dir = named_wallet_dir(data_dir, name)?;
+        if !wallet_dir.join(WALLET_FILE).exists() {
+            return Err(anyhow::anyhow!(
+                "Wallet {} does not exist, create it with create-named-wallet",
+                name
+            ));
+        }
+        if !get_loaded_wallets(data_dir)?
+            .iter()
+            .any(|loaded| loaded == name)
+        {
+            return Err(anyhow::anyhow!(
+                "Wallet {} is not loaded, load it with load-wallet",
+                name
+            ));
+        }
+
+        Self::try_new(&wallet_dir)
+    }
+
+    pub fn try_new(data_dir: &Path) -> Result<Self> {
         let mut wallets = Wallets {
             wallets: HashMap::new(),
+            encryption: None,
+            hd: None,
+            imported: HashSet::new(),
+            watch_only: HashMap::new(),
+            change: HashSet::new(),
+            labels: HashMap::new(),
+            contacts: HashMap::new(),
+            data_dir: data_dir.to_path_buf(),
+            key: None,
         };
         wallets.load_from_file()?;
+        // 解锁的密钥只保存在运行中的节点里
+        if wallets.encryption.is_some() {
+            wallets.key = wallet_agent::get_key(&wallets.data_dir);
+        }
 
         Ok(wallets)
     }
 
     fn load_from_file(&mut self) -> Result<()> {
-        let path = env::current_dir()?.join("data").join(WALLET_FILE);
+        let path = self.data_dir.join(WALLET_FILE);
         if !path.exists() {
             return Ok(());
         }
 
         let mut file = File::open(path)?;
-        let metadata = file.metadata()?;
-        let mut buf = vec![0u8; metadata.len() as usize];
-        file.read(&mut buf)?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
 
-        let wallets = bincode::deserialize(&buf)?;
-        self.wallets = wallets;
+        // 没有文件头的是版本化之前写入的钱包
+        let (version, payload) = if buf.starts_with(WALLET_MAGIC) && buf.len() >= WALLET_HEADER_LEN
+        {
+            let mut version_bytes = [0u8; 4];
+            version_bytes.copy_from_slice(&buf[4..WALLET_HEADER_LEN]);
+            (
+                Some(u32::from_le_bytes(version_bytes)),
+                buf[WALLET_HEADER_LEN..].to_vec(),
+            )
+        } else {
+            (None, buf)
+        };
+
+        let payload = migration::migrate_wallet(version, payload)?;
+        let wallets: Wallets = bincode::deserialize(&payload)?;
+        self.wallets = wallets.wallets;
+        self.encryption = wallets.encryption;
+        self.hd = wallets.hd;
+        self.imported = wallets.imported;
+        self.watch_only = wallets.watch_only;
+        self.change = wallets.change;
+        self.labels = wallets.labels;
+        self.contacts = wallets.contacts;
+
+        if version != Some(migration::WALLET_VERSION) {
+            self.save_to_file()?;
+        }
 
         Ok(())
     }
 
-    pub fn create_wallet(&mut self) -> Result<String> {
-        let wallet = Wallet::try_new()?;
+    /// Derives the next receiving address, generating a new mnemonic first
+    /// if the wallet has no seed yet.
+    pub fn create_wallet(&mut self, scheme: SignatureScheme) -> Result<String> {
+        if self.hd.is_none() {
+            let entropy = utils::random_bytes(MNEMONIC_ENTROPY_LEN)?;
+            let mnemonic = bip39::Mnemonic::from_entropy(&entropy)?;
+            self.hd = Some(HdChain {
+                mnemonic: self.seal(mnemonic.to_string().as_bytes())?,
+                next_index: 0,
+                next_change_index: 0,
+            });
+        }
+
+        let index = self.hd.as_ref().map_or(0, |hd| hd.next_index);
+        let address = self.add_derived_wallet(&self.master_key(scheme)?, RECEIVE_BRANCH, index)?;
+        if let Some(hd) = self.hd.as_mut() {
+            hd.next_index = index + 1;
+        }
+
+        self.save_to_file()?;
+
+        Ok(address)
+    }
+
+    /// Adds a key from outside the wallet. Returns its address.
+    pub fn import_key(&mut self, pkcs8: Vec<u8>, public_key: Vec<u8>) -> Result<String> {
+        let mut wallet = Wallet { pkcs8, public_key };
         let address = wallet.get_address();
+        if self.wallets.contains_key(&address) {
+            return Err(anyhow::anyhow!("{} is already in the wallet", address));
+        }
+
+        wallet.pkcs8 = self.seal(wallet.pkcs8.as_slice())?;
         self.wallets.insert(address.clone(), wallet);
+        self.imported.insert(address.clone());
+        // 有了私钥之后不再是只读地址
+        self.watch_only.remove(&address);
+        self.save_to_file()?;
+
+        Ok(address)
+    }
+
+    pub fn export_key(&self, address: &str, format: KeyFormat) -> Result<String> {
+        encode_private_key(self.get_private_key(address)?.as_slice(), format)
+    }
+
+    /// Tracks `address` without a private key. With `public_key` the address
+    /// is derived from it instead.
+    pub fn add_watch_only(
+        &mut self,
+        address: Option<&str>,
+        public_key: Option<Vec<u8>>,
+    ) -> Result<String> {
+        let address = match (&public_key, address) {
+            (Some(public_key), address) => {
+                SignatureScheme::of_public_key(public_key)?.validate_public_key(public_key)?;
+                let derived = address_of_pub_key(public_key);
+                if address.is_some_and(|address| canonical(address) != derived) {
+                    return Err(anyhow::anyhow!(
+                        "The public key belongs to {}, not the given address",
+                        derived
+                    ));
+                }
+                derived
+            }
+            (None, Some(address)) => normalize_address(address)?,
+            (None, None) => return Err(anyhow::anyhow!("Need an address or a public key")),
+        };
+        if self.wallets.contains_key(&address) {
+            return Err(anyhow::anyhow!(
+                "{} is already in the wallet with its private key",
+                address
+            ));
+        }
 
+        // 已有的条目只在补充了公钥时更新
+        let entry = self
+            .watch_only
+            .entry(address.clone())
+            .or_insert(WatchOnly { public_key: None });
+        if public_key.is_some() {
+            entry.public_key = public_key;
+        }
         self.save_to_file()?;
 
         Ok(address)
     }
 
-    fn save_to_file(&self) -> Result<()> {
-        let path = env::current_dir()?.join("data");
-        if !path.exists() {
-            std::fs::create_dir_all(&path)?;
+    /// The public key of a wallet or watch-only address, if known.
+    pub fn get_public_key(&self, address: &str) -> Option<&[u8]> {
+        match self.get_wallet(address) {
+            Some(wallet) => Some(wallet.get_public_key()),
+            None => self.get_watch_only(address)?.get_public_key(),
         }
-        let path = path.join(WALLET_FILE);
+    }
+
+    pub fn is_watch_only(&self, address: &str) -> bool {
+        self.watch_only.contains_key(&canonical(address))
+    }
+
+    pub fn get_watch_only(&self, address: &str) -> Option<&WatchOnly> {
+        self.watch_only.get(&canonical(address))
+    }
+
+    pub fn get_watch_only_addresses(&self) -> Vec<String> {
+        self.watch_only.keys().cloned().collect()
+    }
 
-        let file = OpenOptions::new().create(true).write(true).open(&path)?;
-        let mut writer = BufWriter::new(file);
+    /// The change address the next transaction would use, without adding it
+    /// to the wallet. `None` if the wallet has no seed to derive it from.
+    pub fn next_change_address(&self) -> Result<Option<String>> {
+        let hd = match &self.hd {
+            Some(hd) => hd,
+            None => return Ok(None),
+        };
+        let wallet = Self::derive_wallet(
+            &self.master_key(CHANGE_SCHEME)?,
+            CHANGE_BRANCH,
+            hd.next_change_index,
+        )?;
+        Ok(Some(wallet.get_address()))
+    }
+
+    /// Adds the address `next_change_address` returned, once a transaction
+    /// pays change to it.
+    pub fn add_change_address(&mut self) -> Result<String> {
+        let index = self
+            .hd
+            .as_ref()
+            .map(|hd| hd.next_change_index)
+            .ok_or(anyhow::anyhow!("Wallet has no seed"))?;
+        let address =
+            self.add_derived_wallet(&self.master_key(CHANGE_SCHEME)?, CHANGE_BRANCH, index)?;
+        self.change.insert(address.clone());
+        if let Some(hd) = self.hd.as_mut() {
+            hd.next_change_index = index + 1;
+        }
+
+        self.save_to_file()?;
+
+        Ok(address)
+    }
 
-        let buf = bincode::serialize(&self.wallets)?;
-        writer.write(buf.as_slice())?;
-        writer.flush()?;
+    pub fn is_change(&self, address: &str) -> bool {
+        self.change.contains(&canonical(address))
+    }
 
+    /// Checks that `name` can be used as a label or contact name for
+    /// `address` without making `resolve_address` ambiguous.
+    fn check_name(&self, address: &str, name: &str) -> Result<()> {
+        if name.trim().is_empty() {
+            return Err(anyhow::anyhow!("Label cannot be empty"));
+        }
+        if validate_address(name) {
+            return Err(anyhow::anyhow!("Label cannot be an address"));
+        }
+        let taken = self
+            .labels
+            .iter()
+            .chain(self.contacts.iter())
+            .find(|(other, label)| label.as_str() == name && other.as_str() != address);
+        if let Some((other, _)) = taken {
+            return Err(anyhow::anyhow!(
+                "{} is already the label of {}",
+                name,
+                other
+            ));
+        }
         Ok(())
     }
 
+    /// Labels an address of this wallet, or removes its label when `label`
+    /// is `None`.
+    pub fn set_label(&mut self, address: &str, label: Option<&str>) -> Result<()> {
+        let address = canonical(address);
+        let address = address.as_str();
+        if !self.wallets.contains_key(address) && !self.watch_only.contains_key(address) {
+            return Err(anyhow::anyhow!(
+                "{} is not in the wallet, add it as a contact instead",
+                address
+            ));
+        }
+        match label {
+            Some(label) => {
+                self.check_name(address, label)?;
+                self.labels.insert(address.to_string(), label.to_string());
+            }
+            None => {
+                self.labels.remove(address);
+            }
+        }
+        self.save_to_file()
+    }
+
+    /// Adds or renames an external address in the address book.
+    pub fn add_contact(&mut self, address: &str, name: &str) -> Result<()> {
+        let address = normalize_address(address)?;
+        let address = address.as_str();
+        if self.wallets.contains_key(address) || self.watch_only.contains_key(address) {
+            return Err(anyhow::anyhow!(
+                "{} is in the wallet, label it instead",
+                address
+            ));
+        }
+        self.check_name(address, name)?;
+        self.contacts.insert(address.to_string(), name.to_string());
+        self.save_to_file()
+    }
+
+    /// Removes a contact given by name or address. Returns its address.
+    pub fn remove_contact(&mut self, name_or_address: &str) -> Result<String> {
+        let as_address = canonical(name_or_address);
+        let address = self
+            .contacts
+            .iter()
+            .find(|(address, name)| **address == as_address || *name == name_or_address)
+            .map(|(address, _)| address.clone())
+            .ok_or(anyhow::anyhow!("No contact {}", name_or_address))?;
+        self.contacts.remove(&address);
+        self.save_to_file()?;
+
+        Ok(address)
+    }
+
+    /// Contacts as (address, name), sorted by name.
+    pub fn get_contacts(&self) -> Vec<(String, String)> {
+        let mut contacts: Vec<(String, String)> = self
+            .contacts
+            .iter()
+            .map(|(address, name)| (address.clone(), name.clone()))
+            .collect();
+        contacts.sort_by(|a, b| a.1.cmp(&b.1));
+        contacts
+    }
+
+    /// The label of an own address or the name of a contact.
+    pub fn get_label(&self, address: &str) -> Option<&str> {
+        let address = canonical(address);
+        self.labels
+            .get(&address)
+            .or_else(|| self.contacts.get(&address))
+            .map(String::as_str)
+    }
+
+    /// `name_or_address` itself if it is an address, otherwise the address
+    /// carrying that label or contact name.
+    pub fn resolve_address(&self, name_or_address: &str) -> Result<String> {
+        if let Ok(address) = normalize_address(name_or_address) {
+            return Ok(address);
+        }
+        self.labels
+            .iter()
+            .chain(self.contacts.iter())
+            .find(|(_, label)| label.as_str() == name_or_address)
+            .map(|(address, _)| address.clone())
+            .ok_or(anyhow::anyhow!(
+                "{} is neither a valid address nor a known label",
+                name_or_address
+            ))
+    }
+
+    pub fn is_imported(&self, address: &str) -> bool {
+        self.imported.contains(&canonical(address))
+    }
+
+    pub fn has_seed(&self) -> bool {
+        self.hd.is_some()
+    }
+
+    /// The backup phrase of the wallet seed.
+    pub fn get_mnemonic(&self) -> Result<String> {
+        let hd = self
+            .hd
+            .as_ref()
+            .ok_or(anyhow::anyhow!("Wallet has no seed"))?;
+        Ok(String::from_utf8(self.open(hd.mnemonic.as_slice())?)?)
+    }
+
+    fn master_key(&self, scheme: SignatureScheme) -> Result<ExtendedKey> {
+        let mnemonic = bip39::Mnemonic::parse(self.get_mnemonic()?)?;
+        ExtendedKey::from_seed(&mnemonic.to_seed(""), scheme)
+    }
+
+    fn derive_wallet(master: &ExtendedKey, branch: u32, index: u32) -> Result<Wallet> {
+        Wallet::from_extended_key(&master.derive_path(&[branch, index])?)
+    }
+
+    fn add_derived_wallet(
+        &mut self,
+        master: &ExtendedKey,
+        branch: u32,
+        index: u32,
+    ) -> Result<String> {
+        let mut wallet = Self::derive_wallet(master, branch, index)?;
+        wallet.pkcs8 = self.seal(wallet.pkcs8.as_slice())?;
+        let address = wallet.get_address();
+        self.wallets.insert(address.clone(), wallet);
+
+        Ok(address)
+    }
+
+    /// For each index of `branch` up to the last used one, the positions in
+    /// `masters` whose key at that index `is_used` returns true for.
+    /// Scanning stops after `GAP_LIMIT` unused indexes in a row.
+    fn scan_branch(
+        masters: &[ExtendedKey],
+        branch: u32,
+        is_used: &impl Fn(&[u8]) -> bool,
+    ) -> Result<Vec<Vec<usize>>> {
+        // 扫描链上用过的地址，连续 GAP_LIMIT 个没用过就停止
+        let mut used = Vec::new();
+        let mut used_up_to = None;
+        let mut index = 0;
+        while index < used_up_to.map_or(0, |used| used + 1) + GAP_LIMIT {
+            let mut used_at = Vec::new();
+            for (pos, master) in masters.iter().enumerate() {
+                let wallet = Self::derive_wallet(master, branch, index)?;
+                if is_used(hash_pub_key(wallet.get_public_key()).as_slice()) {
+                    used_at.push(pos);
+                }
+            }
+            if !used_at.is_empty() {
+                used_up_to = Some(index);
+            }
+            used.push(used_at);
+            index += 1;
+        }
+        used.truncate(used_up_to.map_or(0, |used| used as usize + 1));
+
+        Ok(used)
+    }
+
+    /// Sets the wallet seed from `mnemonic` and adds every derived receiving
+    /// and change address for which `is_used` returns true, in any signature
+    /// scheme, stopping after `GAP_LIMIT` unused ones in a row. Unused
+    /// indexes below the last used one, and the first receiving address,
+    /// are added as default scheme keys.
+    pub fn restore(
+        &mut self,
+        mnemonic: &str,
+        is_used: impl Fn(&[u8]) -> bool,
+    ) -> Result<Vec<String>> {
+        if self.hd.is_some() {
+            return Err(anyhow::anyhow!(
+                "Wallet already has a seed, restore into an empty data directory"
+            ));
+        }
+        let mnemonic = bip39::Mnemonic::parse(mnemonic)
+            .map_err(|e| anyhow::anyhow!("Invalid mnemonic: {}", e))?;
+        self.hd = Some(HdChain {
+            mnemonic: self.seal(mnemonic.to_string().as_bytes())?,
+            next_index: 0,
+            next_change_index: 0,
+        });
+        let masters = SCHEMES
+            .iter()
+            .map(|scheme| self.master_key(*scheme))
+            .collect::<Result<Vec<_>>>()?;
+        let default_pos = SCHEMES
+            .iter()
+            .position(|scheme| *scheme == SignatureScheme::default())
+            .unwrap_or(0);
+
+        let receive = Self::scan_branch(&masters, RECEIVE_BRANCH, &is_used)?;
+        let change_master = self.master_key(CHANGE_SCHEME)?;
+        let change = Self::scan_branch(
+            std::slice::from_ref(&change_master),
+            CHANGE_BRANCH,
+            &is_used,
+        )?;
+
+        let next_index = receive.len().max(1);
+        let mut addresses = Vec::new();
+        for index in 0..next_index {
+            let positions = match receive.get(index) {
+                Some(used_at) if !used_at.is_empty() => used_at.clone(),
+                _ => vec![default_pos],
+            };
+            for pos in positions {
+                addresses.push(self.add_derived_wallet(
+                    &masters[pos],
+                    RECEIVE_BRANCH,
+                    index as u32,
+                )?);
+            }
+        }
+        for index in 0..change.len() {
+            let address = self.add_derived_wallet(&change_master, CHANGE_BRANCH, index as u32)?;
+            self.change.insert(address.clone());
+            addresses.push(address);
+        }
+        if let Some(hd) = self.hd.as_mut() {
+            hd.next_index = next_index as u32;
+            hd.next_change_index = change.len() as u32;
+        }
+
+        self.save_to_file()?;
+
+        Ok(addresses)
+    }
+
+    fn save_to_file(&self) -> Result<()> {
+        if !self.data_dir.exists() {
+            std::fs::create_dir_all(&self.data_dir)?;
+        }
+        let mut data = WALLET_MAGIC.to_vec();
+        data.extend(migration::WALLET_VERSION.to_le_bytes());
+        data.extend(bincode::serialize(self)?);
+
+        // 中途崩溃也不会留下半个钱包文件
+        utils::write_file_atomically(&self.data_dir.join(WALLET_FILE), &data)
+    }
+
     pub fn get_wallet(&self, address: &str) -> Option<&Wallet> {
-        self.wallets.get(address)
+        self.wallets.get(&canonical(address))
+    }
+
+    /// Directory holding the wallet file.
+    pub fn get_data_dir(&self) -> &Path {
+        &self.data_dir
     }
 
     pub fn get_addresses(&self) -> Vec<String> {
         self.wallets.keys().cloned().collect()
     }
+
+    /// Pub key hashes of the addresses the wallet holds keys for, change
+    /// addresses included.
+    pub fn get_own_pub_key_hashes(&self) -> Result<HashSet<Vec<u8>>> {
+        self.wallets
+            .keys()
+            .map(|address| get_pub_key_hash(address))
+            .collect()
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption.is_some()
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.encryption.is_some() && self.key.is_none()
+    }
+
+    /// The PKCS#8 private key of `address`, decrypted if the wallet is encrypted.
+    pub fn get_private_key(&self, address: &str) -> Result<Vec<u8>> {
+        if self.is_watch_only(address) {
+            return Err(anyhow::anyhow!(
+                "{} is watch-only, its private key is not in the wallet",
+                address
+            ));
+        }
+        let wallet = self
+            .get_wallet(address)
+            .ok_or(anyhow::anyhow!("ERROR: Wallet not found"))?;
+        self.open(wallet.pkcs8.as_slice())
+    }
+
+    /// Encrypts `secret` if the wallet is encrypted, otherwise returns it as is.
+    fn seal(&self, secret: &[u8]) -> Result<Vec<u8>> {
+        match self.encryption {
+            Some(_) => utils::aes_encrypt(self.unlocked_key()?, secret),
+            None => Ok(secret.to_vec()),
+        }
+    }
+
+    fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        match self.encryption {
+            Some(_) => utils::aes_decrypt(self.unlocked_key()?, sealed),
+            None => Ok(sealed.to_vec()),
+        }
+    }
+
+    fn unlocked_key(&self) -> Result<&[u8]> {
+        self.key.as_deref().ok_or(anyhow::anyhow!(
+            "Wallet is locked, run unlock with the passphrase while the node is running"
+        ))
+    }
+
+    /// Derives the key for `passphrase` and checks it against the wallet.
+    fn check_passphrase(&self, passphrase: &str) -> Result<Vec<u8>> {
+        let encryption = self
+            .encryption
+            .as_ref()
+            .ok_or(anyhow::anyhow!("Wallet is not encrypted"))?;
+        let key = utils::derive_key(passphrase, &encryption.salt, encryption.iterations)?;
+        match utils::aes_decrypt(&key, &encryption.check) {
+            Ok(check) if check == PASSPHRASE_CHECK => Ok(key),
+            _ => Err(anyhow::anyhow!("Incorrect wallet passphrase")),
+        }
+    }
+
+    /// Re-encrypts every private key and the seed under a key derived from
+    /// `passphrase`. `self.key` must hold the current key if the wallet is
+    /// already encrypted.
+    fn seal_with(&mut self, passphrase: &str) -> Result<()> {
+        let salt = utils::random_bytes(KDF_SALT_LEN)?;
+        let key = utils::derive_key(passphrase, &salt, KDF_ITERATIONS)?;
+
+        let mut resealed = HashMap::new();
+        for (address, wallet) in &self.wallets {
+            let pkcs8 = self.open(wallet.pkcs8.as_slice())?;
+            resealed.insert(address.clone(), utils::aes_encrypt(&key, pkcs8.as_slice())?);
+        }
+        for (address, pkcs8) in resealed {
+            if let Some(wallet) = self.wallets.get_mut(&address) {
+                wallet.pkcs8 = pkcs8;
+            }
+        }
+        if let Some(hd) = &self.hd {
+            let mnemonic = utils::aes_encrypt(&key, self.open(hd.mnemonic.as_slice())?.as_slice())?;
+            if let Some(hd) = self.hd.as_mut() {
+                hd.mnemonic = mnemonic;
+            }
+        }
+        self.encryption = Some(WalletEncryption {
+            salt,
+            iterations: KDF_ITERATIONS,
+            check: utils::aes_encrypt(&key, PASSPHRASE_CHECK)?,
+        });
+        self.key = None;
+
+        self.save_to_file()?;
+        self.lock()
+    }
+
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<()> {
+        if self.encryption.is_some() {
+            return Err(anyhow::anyhow!(
+                "Wallet is already encrypted, use change-passphrase instead"
+            ));
+        }
+        if passphrase.is_empty() {
+            return Err(anyhow::anyhow!("Passphrase must not be empty"));
+        }
+
+        self.seal_with(passphrase)
+    }
+
+    pub fn change_passphrase(&mut self, old_passphrase: &str, new_passphrase: &str) -> Result<()> {
+        if new_passphrase.is_empty() {
+            return Err(anyhow::anyhow!("Passphrase must not be empty"));
+        }
+        self.key = Some(self.check_passphrase(old_passphrase)?);
+
+        self.seal_with(new_passphrase)
+    }
+
+    /// Keeps the wallet unlocked for `timeout_secs` seconds. The running node
+    /// holds the key in memory, so later commands can use it until the timeout
+    /// or until the node stops.
+    pub fn unlock(&mut self, passphrase: &str, timeout_secs: u64) -> Result<()> {
+        let key = self.check_passphrase(passphrase)?;
+        wallet_agent::unlock(&self.data_dir, &key, timeout_secs)?;
+
+        self.key = Some(key);
+        Ok(())
+    }
+
+    pub fn lock(&mut self) -> Result<()> {
+        self.key = None;
+        wallet_agent::lock(&self.data_dir)
+    }
+}
+
+fn named_wallet_dir(data_dir: &Path, name: &str) -> Result<PathBuf> {
+    // 名字直接用作目录名，只允许安全的字符
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(anyhow::anyhow!(
+            "Invalid wallet name {}, use letters, digits, - and _",
+            name
+        ));
+    }
+    Ok(data_dir.join(WALLETS_DIR).join(name))
+}
+
+pub fn get_loaded_wallets(data_dir: &Path) -> Result<Vec<String>> {
+    let path = data_dir.join(WALLETS_DIR).join(LOADED_FILE);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+fn set_loaded_wallets(data_dir: &Path, names: &[String]) -> Result<()> {
+    let dir = data_dir.join(WALLETS_DIR);
+    fs::create_dir_all(&dir)?;
+    let mut content = names.join("\n");
+    content.push('\n');
+    fs::write(dir.join(LOADED_FILE), content)?;
+    Ok(())
+}
+
+/// Creates an empty named wallet and loads it.
+pub fn create_named_wallet(data_dir: &Path, name: &str) -> Result<()> {
+    let wallet_dir = named_wallet_dir(data_dir, name)?;
+    if wallet_dir.join(WALLET_FILE).exists() {
+        return Err(anyhow::anyhow!("Wallet {} already exists", name));
+    }
+
+    Wallets::try_new(&wallet_dir)?.save_to_file()?;
+    load_wallet(data_dir, name)
+}
+
+/// Makes a named wallet usable with `--wallet`.
+pub fn load_wallet(data_dir: &Path, name: &str) -> Result<()> {
+    if !named_wallet_dir(data_dir, name)?.join(WALLET_FILE).exists() {
+        return Err(anyhow::anyhow!("Wallet {} does not exist", name));
+    }
+    let mut loaded = get_loaded_wallets(data_dir)?;
+    if loaded.iter().any(|loaded| loaded == name) {
+        return Err(anyhow::anyhow!("Wallet {} is already loaded", name));
+    }
+    loaded.push(name.to_string());
+    set_loaded_wallets(data_dir, &loaded)
+}
+
+/// Makes a named wallet unusable until it is loaded again, locking it first.
+pub fn unload_wallet(data_dir: &Path, name: &str) -> Result<()> {
+    let mut loaded = get_loaded_wallets(data_dir)?;
+    if !loaded.iter().any(|loaded| loaded == name) {
+        return Err(anyhow::anyhow!("Wallet {} is not loaded", name));
+    }
+
+    Wallets::load(data_dir, Some(name))?.lock()?;
+    loaded.retain(|loaded| loaded != name);
+    set_loaded_wallets(data_dir, &loaded)
+}
+
+/// Every named wallet in `data_dir`, with whether it is loaded.
+pub fn list_wallets(data_dir: &Path) -> Result<Vec<(String, bool)>> {
+    let dir = data_dir.join(WALLETS_DIR);
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let loaded = get_loaded_wallets(data_dir)?;
+
+    let mut wallets = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.path().join(WALLET_FILE).exists() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        let is_loaded = loaded.contains(&name);
+        wallets.push((name, is_loaded));
+    }
+    wallets.sort();
+
+    Ok(wallets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: [u8; PUB_KEY_HASH_LEN] = [0x11; PUB_KEY_HASH_LEN];
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(uuid::Uuid::new_v4().to_string())
+    }
+
+    #[test]
+    fn save_replaces_the_wallet_file() {
+        let data_dir = temp_dir();
+        let mut wallets = Wallets::try_new(&data_dir).unwrap();
+        // 上次保存中途中断留下的临时文件
+        let tmp_path = data_dir.join(format!("{}.tmp", WALLET_FILE));
+        fs::create_dir_all(&data_dir).unwrap();
+        fs::write(&tmp_path, b"partial").unwrap();
+
+        let address = wallets.create_wallet(SignatureScheme::P256).unwrap();
+        assert!(!tmp_path.exists());
+        let wallets = Wallets::try_new(&data_dir).unwrap();
+        assert!(wallets.get_wallet(&address).is_some());
+    }
+
+    #[test]
+    fn encrypt_and_unlock_round_trip() {
+        let data_dir = temp_dir();
+        let mut wallets = Wallets::try_new(&data_dir).unwrap();
+        let address = wallets.create_wallet(SignatureScheme::P256).unwrap();
+        let pkcs8 = wallets.get_private_key(&address).unwrap();
+
+        wallets.encrypt("correct horse").unwrap();
+        assert!(wallets.is_locked());
+        assert!(wallets.get_private_key(&address).is_err());
+        assert_ne!(wallets.get_wallet(&address).unwrap().pkcs8, pkcs8);
+        assert!(wallets.unlock("wrong horse", 60).is_err());
+
+        // 解锁的密钥由运行中的节点保存，这里直接设置
+        wallets.key = Some(wallets.check_passphrase("correct horse").unwrap());
+        assert_eq!(wallets.get_private_key(&address).unwrap(), pkcs8);
+        // 没有节点时其他进程看到的钱包仍是锁定的
+        assert!(Wallets::try_new(&data_dir).unwrap().is_locked());
+
+        wallets.lock().unwrap();
+        assert!(wallets.is_locked());
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn change_passphrase_keeps_keys() {
+        let data_dir = temp_dir();
+        let mut wallets = Wallets::try_new(&data_dir).unwrap();
+        let address = wallets.create_wallet(SignatureScheme::P256).unwrap();
+        let pkcs8 = wallets.get_private_key(&address).unwrap();
+        wallets.encrypt("old").unwrap();
+
+        assert!(wallets.change_passphrase("wrong", "new").is_err());
+        wallets.change_passphrase("old", "new").unwrap();
+        assert!(wallets.check_passphrase("old").is_err());
+        wallets.key = Some(wallets.check_passphrase("new").unwrap());
+        assert_eq!(wallets.get_private_key(&address).unwrap(), pkcs8);
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn restore_finds_used_addresses() {
+        let data_dir = temp_dir();
+        let mut wallets = Wallets::try_new(&data_dir).unwrap();
+        let addresses: Vec<String> = (0..3)
+            .map(|_| wallets.create_wallet(SignatureScheme::P256).unwrap())
+            .collect();
+        let mnemonic = wallets.get_mnemonic().unwrap();
+        let used = wallets
+            .get_wallet(&addresses[1])
+            .map(|wallet| hash_pub_key(wallet.get_public_key()))
+            .unwrap();
+
+        // 第三个地址没用过，恢复时不应加回来
+        let restore_dir = temp_dir();
+        let mut restored = Wallets::try_new(&restore_dir).unwrap();
+        let found = restored
+            .restore(&mnemonic, |pub_key_hash| pub_key_hash == used.as_slice())
+            .unwrap();
+        assert_eq!(found, addresses[..2]);
+        assert_eq!(
+            restored.get_private_key(&addresses[1]).unwrap(),
+            wallets.get_private_key(&addresses[1]).unwrap()
+        );
+        assert!(restored.restore(&mnemonic, |_| false).is_err());
+
+        fs::remove_dir_all(&data_dir).unwrap();
+        fs::remove_dir_all(&restore_dir).unwrap();
+    }
+
+    #[test]
+    fn private_key_encodings_round_trip() {
+        for scheme in crate::signature::SCHEMES {
+            let (pkcs8, public_key) = scheme.new_key_pair().unwrap();
+            for format in [KeyFormat::Pem, KeyFormat::Base58] {
+                let text = encode_private_key(&pkcs8, format).unwrap();
+                let (decoded, decoded_public_key) = decode_private_key(&text).unwrap();
+                assert_eq!(decoded_public_key, public_key);
+                // 重新编码后得到同一个私钥
+                assert_eq!(encode_private_key(&decoded, format).unwrap(), text);
+            }
+        }
+
+        let (pkcs8, _) = SignatureScheme::P256.new_key_pair().unwrap();
+        let mut base58 = encode_private_key(&pkcs8, KeyFormat::Base58).unwrap();
+        let last = base58.pop().unwrap();
+        base58.push(if last == '1' { '2' } else { '1' });
+        assert!(decode_private_key(&base58).is_err());
+    }
+
+    #[test]
+    fn export_then_import_key() {
+        let data_dir = temp_dir();
+        let mut wallets = Wallets::try_new(&data_dir).unwrap();
+        let address = wallets.create_wallet(SignatureScheme::P256).unwrap();
+        let exported = wallets.export_key(&address, KeyFormat::Base58).unwrap();
+
+        let import_dir = temp_dir();
+        let mut other = Wallets::try_new(&import_dir).unwrap();
+        let (pkcs8, public_key) = decode_private_key(&exported).unwrap();
+        assert_eq!(
+            other.import_key(pkcs8.clone(), public_key.clone()).unwrap(),
+            address
+        );
+        assert!(other.is_imported(&address));
+        assert!(other.import_key(pkcs8, public_key).is_err());
+
+        fs::remove_dir_all(&data_dir).unwrap();
+        fs::remove_dir_all(&import_dir).unwrap();
+    }
+
+    #[test]
+    fn watch_only_addresses() {
+        let data_dir = temp_dir();
+        let mut wallets = Wallets::try_new(&data_dir).unwrap();
+        let (pkcs8, public_key) = SignatureScheme::P256.new_key_pair().unwrap();
+        let address = address_of_pub_key(&public_key);
+
+        assert_eq!(
+            wallets.add_watch_only(Some(&address), None).unwrap(),
+            address
+        );
+        assert!(
+            wallets
+                .get_watch_only(&address)
+                .unwrap()
+                .get_public_key()
+                .is_none()
+        );
+        // 之后补充公钥
+        wallets
+            .add_watch_only(None, Some(public_key.clone()))
+            .unwrap();
+        assert_eq!(
+            wallets.get_watch_only(&address).unwrap().get_public_key(),
+            Some(public_key.as_slice())
+        );
+        assert!(wallets.get_private_key(&address).is_err());
+
+        let other = Wallet::try_new(SignatureScheme::P256)
+            .unwrap()
+            .get_address();
+        assert!(
+            wallets
+                .add_watch_only(Some(&other), Some(public_key.clone()))
+                .is_err()
+        );
+        let mut mangled = other.clone();
+        let last = mangled.pop().unwrap();
+        mangled.push(if last == '1' { '2' } else { '1' });
+        assert!(wallets.add_watch_only(Some(&mangled), None).is_err());
+
+        wallets.import_key(pkcs8, public_key).unwrap();
+        assert!(!wallets.is_watch_only(&address));
+        assert!(wallets.add_watch_only(Some(&address), None).is_err());
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn change_addresses_are_derived_in_order() {
+        let data_dir = temp_dir();
+        let mut wallets = Wallets::try_new(&data_dir).unwrap();
+        assert_eq!(wallets.next_change_address().unwrap(), None);
+        let address = wallets.create_wallet(SignatureScheme::P256).unwrap();
+
+        // 没用掉之前一直是同一个找零地址
+        let first = wallets.next_change_address().unwrap().unwrap();
+        assert_eq!(wallets.next_change_address().unwrap().unwrap(), first);
+        assert_ne!(first, address);
+        assert!(!wallets.is_change(&first));
+
+        assert_eq!(wallets.add_change_address().unwrap(), first);
+        assert!(wallets.is_change(&first));
+        assert!(wallets.get_private_key(&first).is_ok());
+        let second = wallets.next_change_address().unwrap().unwrap();
+        assert_ne!(second, first);
+
+        // 找零地址和收款地址不冲突
+        assert_ne!(
+            wallets.create_wallet(SignatureScheme::P256).unwrap(),
+            second
+        );
+        let reopened = Wallets::try_new(&data_dir).unwrap();
+        assert_eq!(reopened.next_change_address().unwrap().unwrap(), second);
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn named_wallets_are_separate() {
+        let data_dir = temp_dir();
+        let mut default = Wallets::load(&data_dir, None).unwrap();
+        let default_address = default.create_wallet(SignatureScheme::P256).unwrap();
+
+        create_named_wallet(&data_dir, "savings").unwrap();
+        assert!(create_named_wallet(&data_dir, "savings").is_err());
+        assert!(create_named_wallet(&data_dir, "../escape").is_err());
+        let mut savings = Wallets::load(&data_dir, Some("savings")).unwrap();
+        let savings_address = savings.create_wallet(SignatureScheme::P256).unwrap();
+        assert!(savings.get_wallet(&default_address).is_none());
+        assert!(
+            Wallets::load(&data_dir, None)
+                .unwrap()
+                .get_wallet(&savings_address)
+                .is_none()
+        );
+
+        unload_wallet(&data_dir, "savings").unwrap();
+        assert!(Wallets::load(&data_dir, Some("savings")).is_err());
+        assert_eq!(
+            list_wallets(&data_dir).unwrap(),
+            vec![("savings".to_string(), false)]
+        );
+        load_wallet(&data_dir, "savings").unwrap();
+        assert!(load_wallet(&data_dir, "savings").is_err());
+        let reloaded = Wallets::load(&data_dir, Some("savings")).unwrap();
+        assert!(reloaded.get_wallet(&savings_address).is_some());
+        assert!(Wallets::load(&data_dir, Some("missing")).is_err());
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn labels_and_contacts_resolve() {
+        let data_dir = temp_dir();
+        let mut wallets = Wallets::try_new(&data_dir).unwrap();
+        let own = wallets.create_wallet(SignatureScheme::P256).unwrap();
+        let friend = Wallet::try_new(SignatureScheme::P256)
+            .unwrap()
+            .get_address();
+
+        wallets.set_label(&own, Some("savings")).unwrap();
+        assert!(wallets.set_label(&friend, Some("friend")).is_err());
+        assert!(wallets.add_contact(&own, "me").is_err());
+        wallets.add_contact(&friend, "alice").unwrap();
+        // 名字不能重复，也不能是地址
+        assert!(wallets.add_contact(&friend, "savings").is_err());
+        assert!(wallets.set_label(&own, Some(&friend)).is_err());
+
+        assert_eq!(wallets.resolve_address("savings").unwrap(), own);
+        assert_eq!(wallets.resolve_address("alice").unwrap(), friend);
+        assert_eq!(wallets.resolve_address(&friend).unwrap(), friend);
+        assert!(wallets.resolve_address("bob").is_err());
+        assert_eq!(wallets.get_label(&friend), Some("alice"));
+
+        let reopened = Wallets::try_new(&data_dir).unwrap();
+        assert_eq!(reopened.get_label(&own), Some("savings"));
+        assert_eq!(wallets.remove_contact("alice").unwrap(), friend);
+        assert!(wallets.resolve_address("alice").is_err());
+        wallets.set_label(&own, None).unwrap();
+        assert_eq!(wallets.get_label(&own), None);
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn bech32m_round_trip() {
+        let address = convert_address(&HASH);
+
+        assert!(address.starts_with("brs1"));
+        assert_eq!(get_pub_key_hash(&address).unwrap(), HASH.to_vec());
+        // bech32m 允许全大写
+        assert_eq!(
+            get_pub_key_hash(&address.to_uppercase()).unwrap(),
+            HASH.to_vec()
+        );
+    }
+
+    #[test]
+    fn bech32m_rejects_bad_checksum() {
+        let mut address = convert_address(&HASH);
+        let last = address.pop().unwrap();
+        address.push(if last == 'q' { 'p' } else { 'q' });
+
+        let err = get_pub_key_hash(&address).unwrap_err().to_string();
+        assert!(err.ends_with("bad checksum"));
+    }
+
+    #[test]
+    fn bech32m_rejects_other_network() {
+        let hrp = Hrp::parse_unchecked(Network::Testnet.address_prefix());
+        let address = bech32::encode::<Bech32m>(hrp, &HASH).unwrap();
+
+        let err = get_pub_key_hash(&address).unwrap_err().to_string();
+        assert!(err.contains("is a testnet address"));
+    }
+
+    #[test]
+    fn legacy_address_is_accepted() {
+        let payload = [&[VERSION][..], &HASH].concat();
+        let legacy = utils::base58_encode(&[payload.clone(), checksum(&payload)].concat());
+
+        assert_eq!(get_pub_key_hash(&legacy).unwrap(), HASH.to_vec());
+        assert_eq!(normalize_address(&legacy).unwrap(), convert_address(&HASH));
+    }
 }